@@ -0,0 +1,95 @@
+use std::fmt::{Display, Formatter};
+use std::io::{Read, Write};
+
+use crate::error::Error;
+
+const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+const SSH_AGENT_IDENTITIES_ANSWER: u8 = 12;
+
+/// Key held by the ssh-agent (or Pageant, through its OpenSSH-compatible pipe).
+pub(crate) struct AgentIdentity {
+    pub comment: String,
+    pub fingerprint: String,
+}
+
+/// Asks the agent at `SSH_AUTH_SOCK` for the identities it holds.
+pub(crate) fn identities() -> Result<Vec<AgentIdentity>, Error> {
+    let mut stream = connect()?;
+    stream.write_all(&[0, 0, 0, 1, SSH_AGENTC_REQUEST_IDENTITIES])?;
+    let mut len_buf = [0u8; 4];
+    stream.read_exact(&mut len_buf)?;
+    let mut msg = vec![0u8; u32::from_be_bytes(len_buf) as usize];
+    stream.read_exact(&mut msg)?;
+    let mut reader = AgentReader { data: &msg, pos: 0 };
+    if reader.byte()? != SSH_AGENT_IDENTITIES_ANSWER {
+        return Err(Error::new("Unexpected reply from ssh-agent"));
+    }
+    let count = reader.u32()?;
+    let mut identities = Vec::<AgentIdentity>::new();
+    for _ in 0..count {
+        let blob = reader.string()?;
+        let comment = String::from_utf8_lossy(reader.string()?).to_string();
+        let hash = openssl::base64::encode_block(&openssl::sha::sha256(blob));
+        identities.push(AgentIdentity {
+            comment,
+            fingerprint: format!("SHA256:{}", hash.trim_end_matches('=')),
+        });
+    }
+    return Ok(identities);
+}
+
+#[cfg(unix)]
+fn connect() -> Result<std::os::unix::net::UnixStream, Error> {
+    let path = std::env::var("SSH_AUTH_SOCK")
+        .map_err(|_| Error::new("SSH_AUTH_SOCK is not set, is ssh-agent running?"))?;
+    return Ok(std::os::unix::net::UnixStream::connect(path)?);
+}
+
+#[cfg(windows)]
+fn connect() -> Result<std::fs::File, Error> {
+    let path = std::env::var("SSH_AUTH_SOCK")
+        .unwrap_or_else(|_| String::from(r"\\.\pipe\openssh-ssh-agent"));
+    return Ok(std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)?);
+}
+
+struct AgentReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> AgentReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.pos + len > self.data.len() {
+            return Err(Error::new("Truncated reply from ssh-agent"));
+        }
+        let slice = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        return Ok(slice);
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        return Ok(self.take(1)?[0]);
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        let bytes = self.take(4)?;
+        return Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+    }
+
+    fn string(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.u32()? as usize;
+        return self.take(len);
+    }
+}
+
+impl Display for AgentIdentity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.comment.is_empty() {
+            return f.write_str(&self.fingerprint);
+        }
+        return f.write_fmt(format_args!("{} ({})", self.comment, self.fingerprint));
+    }
+}
@@ -0,0 +1,107 @@
+use std::time::Duration;
+
+use crate::conn_pool::Algorithms;
+use crate::device_manager::Device;
+
+/// Seconds to wait for the connection, when the device doesn't set `connectTimeout`.
+const DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+
+const DEFAULT_KEX: [&str; 11] = [
+    "curve25519-sha256",
+    "curve25519-sha256@libssh.org",
+    "ecdh-sha2-nistp256",
+    "ecdh-sha2-nistp384",
+    "ecdh-sha2-nistp521",
+    "diffie-hellman-group18-sha512",
+    "diffie-hellman-group16-sha512",
+    "diffie-hellman-group-exchange-sha256",
+    "diffie-hellman-group14-sha256",
+    "diffie-hellman-group1-sha1",
+    "diffie-hellman-group14-sha1",
+];
+const DEFAULT_MACS: [&str; 7] = [
+    "hmac-sha2-256-etm@openssh.com",
+    "hmac-sha2-512-etm@openssh.com",
+    "hmac-sha2-256",
+    "hmac-sha2-512",
+    "hmac-sha1-96",
+    "hmac-sha1",
+    "hmac-md5",
+];
+const DEFAULT_HOST_KEYS: [&str; 7] = [
+    "ssh-ed25519",
+    "ecdsa-sha2-nistp521",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp256",
+    "rsa-sha2-512",
+    "rsa-sha2-256",
+    "ssh-rsa",
+];
+
+/// Default of libssh, only used as the base when a device adds or removes ciphers.
+const DEFAULT_CIPHERS: [&str; 6] = [
+    "chacha20-poly1305@openssh.com",
+    "aes256-gcm@openssh.com",
+    "aes128-gcm@openssh.com",
+    "aes256-ctr",
+    "aes192-ctr",
+    "aes128-ctr",
+];
+
+impl Algorithms {
+    /// Algorithm lists for the device, with its `sshOptions` merged over the defaults.
+    pub(crate) fn for_device(device: &Device) -> Algorithms {
+        let options = device.ssh_options.clone().unwrap_or_default();
+        return Algorithms {
+            kex: merge(&DEFAULT_KEX, options.kex.as_deref()),
+            macs: merge(&DEFAULT_MACS, options.macs.as_deref()),
+            host_keys: merge(&DEFAULT_HOST_KEYS, options.host_keys.as_deref()),
+            ciphers: options
+                .ciphers
+                .as_deref()
+                .map(|ciphers| merge(&DEFAULT_CIPHERS, Some(ciphers))),
+        };
+    }
+}
+
+/// Connection timeout of the device, the default one if it isn't set or is 0.
+pub(crate) fn connect_timeout(device: &Device) -> Duration {
+    let secs = device
+        .ssh_options
+        .as_ref()
+        .and_then(|options| options.connect_timeout)
+        .filter(|secs| *secs > 0)
+        .unwrap_or(DEFAULT_CONNECT_TIMEOUT);
+    return Duration::from_secs(secs);
+}
+
+/// Merges overrides the same way as OpenSSH does for its algorithm options.
+///
+/// Entries prefixed with `+` are appended to the defaults, `^` are prepended, and `-` are
+/// removed from them. Entries without a prefix replace the defaults altogether.
+fn merge(defaults: &[&str], overrides: Option<&[String]>) -> Vec<String> {
+    let Some(overrides) = overrides else {
+        return defaults.iter().map(|s| String::from(*s)).collect();
+    };
+    let mut result: Vec<String> = overrides
+        .iter()
+        .filter(|s| !s.starts_with(['+', '^', '-']))
+        .cloned()
+        .collect();
+    if result.is_empty() {
+        result = defaults.iter().map(|s| String::from(*s)).collect();
+    }
+    for entry in overrides {
+        if let Some(name) = entry.strip_prefix('+') {
+            if !result.iter().any(|s| s == name) {
+                result.push(String::from(name));
+            }
+        } else if let Some(name) = entry.strip_prefix('^') {
+            result.retain(|s| s != name);
+            result.insert(0, String::from(name));
+        } else if let Some(name) = entry.strip_prefix('-') {
+            result.retain(|s| s != name);
+        }
+    }
+    return result;
+}
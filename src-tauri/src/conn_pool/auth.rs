@@ -0,0 +1,198 @@
+use std::path::Path;
+
+use libssh_rs::{AuthMethods, AuthStatus, Session, SshKey};
+
+use crate::conn_pool::{agent, InteractiveAuth};
+use crate::device_manager::{AuthMethod, Device, PrivateKey};
+use crate::error::{AuthAttempt, Error};
+
+/// Tries the auth methods of the device in order, until one of them succeeds.
+///
+/// `device` needs to have its secrets resolved from the vault already. Returns the method that
+/// succeeded.
+pub(crate) fn authenticate(
+    session: &Session,
+    device: &Device,
+    ssh_dir: Option<&Path>,
+    interactive: &InteractiveAuth,
+) -> Result<AuthMethod, Error> {
+    let methods = device.auth_methods();
+    let mut attempts = Vec::<AuthAttempt>::new();
+    // The host only tells which methods it accepts after `none` has been tried
+    if session.userauth_none(None)? == AuthStatus::Success {
+        if methods.contains(&AuthMethod::None) {
+            log::info!("Authenticated to {} with none", device.name);
+            return Ok(AuthMethod::None);
+        }
+        // The session is logged in already, no other method can be tried on it
+        log::warn!(
+            "{} accepted none auth, which isn't enabled for it",
+            device.name
+        );
+        let message = String::from("Accepted, but not enabled for this device");
+        return Err(Error::Authorization {
+            message: format!("{}: {}", AuthMethod::None.name(), message),
+            identities: Vec::new(),
+            offered: Vec::new(),
+            attempts: vec![AuthAttempt {
+                method: String::from(AuthMethod::None.name()),
+                message,
+            }],
+        });
+    }
+    let offered = session.userauth_list(None)?;
+    let mut identities = Vec::<String>::new();
+    for method in methods {
+        let result = if !offered.is_empty() && !offered.contains(method.flag()) {
+            Err(Error::new("Not offered by host"))
+        } else {
+            match method {
+                AuthMethod::PublicKey => publickey(session, device, ssh_dir, &mut identities),
+                AuthMethod::Password => password(session, device),
+                AuthMethod::KeyboardInteractive => {
+                    keyboard_interactive(session, device, interactive)
+                }
+                AuthMethod::None => Ok(AuthStatus::Denied),
+            }
+        };
+        let message = match result {
+            Ok(AuthStatus::Success) => {
+                log::info!("Authenticated to {} with {}", device.name, method.name());
+                return Ok(method);
+            }
+            Ok(AuthStatus::Partial) => String::from("Accepted, but host requires more methods"),
+            Ok(_) => String::from(method.denied_message(device)),
+            Err(e @ (Error::Disconnected | Error::Timeout)) => return Err(e),
+            Err(e) => failure_message(&e),
+        };
+        log::info!(
+            "{} auth to {} failed: {}",
+            method.name(),
+            device.name,
+            message
+        );
+        attempts.push(AuthAttempt {
+            method: String::from(method.name()),
+            message,
+        });
+    }
+    let message = if attempts.is_empty() {
+        String::from("Host needs authorization")
+    } else {
+        attempts
+            .iter()
+            .map(|a| format!("{}: {}", a.method, a.message))
+            .collect::<Vec<String>>()
+            .join("; ")
+    };
+    return Err(Error::Authorization {
+        message,
+        identities,
+        offered: offered_names(offered),
+        attempts,
+    });
+}
+
+fn publickey(
+    session: &Session,
+    device: &Device,
+    ssh_dir: Option<&Path>,
+    identities: &mut Vec<String>,
+) -> Result<AuthStatus, Error> {
+    let Some(private_key) = &device.private_key else {
+        return Err(Error::new("No private key configured"));
+    };
+    if let PrivateKey::Agent { .. } = private_key {
+        let status = session.userauth_agent(None)?;
+        if status != AuthStatus::Success {
+            match agent::identities() {
+                Ok(list) => identities.extend(list.iter().map(|i| i.to_string())),
+                Err(e) => log::warn!("Failed to list ssh-agent identities: {e:?}"),
+            }
+        }
+        return Ok(status);
+    }
+    let passphrase = device.valid_passphrase();
+    let priv_key_content = private_key.content(ssh_dir)?;
+    let priv_key = SshKey::from_privkey_base64(&priv_key_content, passphrase.as_deref())?;
+    return Ok(session.userauth_publickey(None, &priv_key)?);
+}
+
+fn password(session: &Session, device: &Device) -> Result<AuthStatus, Error> {
+    let Some(password) = &device.password else {
+        return Err(Error::new("No password configured"));
+    };
+    return Ok(session.userauth_password(None, Some(password))?);
+}
+
+fn keyboard_interactive(
+    session: &Session,
+    device: &Device,
+    interactive: &InteractiveAuth,
+) -> Result<AuthStatus, Error> {
+    let mut status = session.userauth_keyboard_interactive(None, None)?;
+    let mut used_cache = false;
+    while status == AuthStatus::Info {
+        let info = session.userauth_keyboard_interactive_info()?;
+        let (answers, from_cache) = interactive.answer(device, &info)?;
+        used_cache |= from_cache;
+        session.userauth_keyboard_interactive_set_answers(&answers)?;
+        status = session.userauth_keyboard_interactive(None, None)?;
+    }
+    if status == AuthStatus::Denied && used_cache {
+        interactive.forget(&device.name);
+    }
+    return Ok(status);
+}
+
+fn failure_message(e: &Error) -> String {
+    return match e {
+        Error::Message { message } | Error::IO { message, .. } => message.clone(),
+        Error::BadPassphrase => String::from("Bad passphrase"),
+        Error::PassphraseRequired => String::from("Passphrase required"),
+        Error::BadPrivateKey => String::from("Bad private key"),
+        Error::NotFound => String::from("Private key not found"),
+        e => format!("{e:?}"),
+    };
+}
+
+pub(crate) fn offered_methods(session: &Session) -> Result<Vec<String>, Error> {
+    return Ok(offered_names(session.userauth_list(None)?));
+}
+
+fn offered_names(offered: AuthMethods) -> Vec<String> {
+    return [
+        (AuthMethods::PUBLIC_KEY, "publickey"),
+        (AuthMethods::PASSWORD, "password"),
+        (AuthMethods::INTERACTIVE, "keyboard-interactive"),
+        (AuthMethods::HOST_BASED, "hostbased"),
+        (AuthMethods::GSSAPI_MIC, "gssapi-with-mic"),
+    ]
+    .into_iter()
+    .filter(|(flag, _)| offered.contains(*flag))
+    .map(|(_, name)| String::from(name))
+    .collect();
+}
+
+impl AuthMethod {
+    fn flag(&self) -> AuthMethods {
+        return match self {
+            AuthMethod::PublicKey => AuthMethods::PUBLIC_KEY,
+            AuthMethod::Password => AuthMethods::PASSWORD,
+            AuthMethod::KeyboardInteractive => AuthMethods::INTERACTIVE,
+            AuthMethod::None => AuthMethods::NONE,
+        };
+    }
+
+    fn denied_message(&self, device: &Device) -> &'static str {
+        return match self {
+            AuthMethod::PublicKey => match device.private_key {
+                Some(PrivateKey::Agent { .. }) => "No identity from ssh-agent was accepted",
+                _ => "Key authorization failed",
+            },
+            AuthMethod::Password => "Bad SSH password",
+            AuthMethod::KeyboardInteractive => "Answers were rejected",
+            AuthMethod::None => "Host needs authorization",
+        };
+    }
+}
@@ -2,60 +2,103 @@ use std::fmt::{Debug, Formatter};
 use std::io::Read;
 use std::ops::{Deref, DerefMut};
 use std::path::Path;
-use std::sync::Mutex;
-use std::time::Duration;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
 
-use libssh_rs::{AuthStatus, Session, SshKey, SshOption};
+use libssh_rs::{Session, SshOption};
 use regex::Regex;
 use uuid::Uuid;
 
-use crate::conn_pool::{DeviceConnection, DeviceConnectionUserInfo, Id};
+use crate::conn_pool::algorithms::{connect_timeout, Algorithms};
+use crate::conn_pool::auth::authenticate;
+use crate::conn_pool::{
+    ConnectionStats, DeviceConnection, DeviceConnectionUserInfo, Id, InteractiveAuth, JumpTunnel,
+};
 use crate::device_manager::Device;
 use crate::error::Error;
+use crate::known_hosts::KnownHosts;
+use crate::vault::SecretVault;
 
 impl DeviceConnection {
-    pub(crate) fn new(device: Device, ssh_dir: Option<&Path>) -> Result<DeviceConnection, Error> {
-        let kex = vec![
-            "curve25519-sha256",
-            "curve25519-sha256@libssh.org",
-            "ecdh-sha2-nistp256",
-            "ecdh-sha2-nistp384",
-            "ecdh-sha2-nistp521",
-            "diffie-hellman-group18-sha512",
-            "diffie-hellman-group16-sha512",
-            "diffie-hellman-group-exchange-sha256",
-            "diffie-hellman-group14-sha256",
-            "diffie-hellman-group1-sha1",
-            "diffie-hellman-group14-sha1",
-        ];
-        let hmac = vec![
-            "hmac-sha2-256-etm@openssh.com",
-            "hmac-sha2-512-etm@openssh.com",
-            "hmac-sha2-256",
-            "hmac-sha2-512",
-            "hmac-sha1-96",
-            "hmac-sha1",
-            "hmac-md5",
-        ];
-        let key_types = vec![
-            "ssh-ed25519",
-            "ecdsa-sha2-nistp521",
-            "ecdsa-sha2-nistp384",
-            "ecdsa-sha2-nistp256",
-            "rsa-sha2-512",
-            "rsa-sha2-256",
-            "ssh-rsa",
-        ];
+    /// Connects to the device through the jump hosts in `chain`, see [Device::jump_chain].
+    pub(crate) fn new(
+        device: Device,
+        chain: &[Device],
+        ssh_dir: Option<&Path>,
+        conf_dir: Option<&Path>,
+        vault: &SecretVault,
+        interactive: &InteractiveAuth,
+    ) -> Result<DeviceConnection, Error> {
+        let tunnel = JumpTunnel::open(&device, chain, ssh_dir, conf_dir, vault, interactive)?;
+        return DeviceConnection::open(device, ssh_dir, conf_dir, vault, interactive, tunnel);
+    }
+
+    /// Connects to the device directly, or through `tunnel` if it's behind a jump host.
+    pub(super) fn open(
+        device: Device,
+        ssh_dir: Option<&Path>,
+        conf_dir: Option<&Path>,
+        vault: &SecretVault,
+        interactive: &InteractiveAuth,
+        mut tunnel: Option<JumpTunnel>,
+    ) -> Result<DeviceConnection, Error> {
+        let resolved = vault.resolve(&device)?;
+        let session = DeviceConnection::connect_session(&device, tunnel.as_mut())?;
+        if let Some(conf_dir) = conf_dir {
+            KnownHosts::new(conf_dir).verify(&device, &session)?;
+        } else {
+            log::warn!("Skipping host key verification for {}", device.name);
+        }
+
+        authenticate(&session, &resolved, ssh_dir, interactive)?;
+        let id = Uuid::new_v4();
+        let user = DeviceConnectionUserInfo::new(&session)?;
+        let connection = DeviceConnection {
+            id,
+            device: device.clone(),
+            user: user.clone(),
+            sftp: Mutex::default(),
+            sftp_missing: AtomicBool::new(false),
+            session,
+            jump: tunnel,
+            stats: Arc::new(ConnectionStats::new(id, user)),
+        };
+        log::info!("{:?} created", connection);
+        return Ok(connection);
+    }
+
+    /// Creates a connected, but not yet authenticated, session to the device.
+    pub(crate) fn connect_session(
+        device: &Device,
+        tunnel: Option<&mut JumpTunnel>,
+    ) -> Result<Session, Error> {
+        let algorithms = Algorithms::for_device(device);
+        let options = device.ssh_options.clone().unwrap_or_default();
         let session = Session::new()?;
-        session.set_option(SshOption::Timeout(Duration::from_secs(10)))?;
+        session.set_option(SshOption::Timeout(connect_timeout(device)))?;
         session.set_option(SshOption::Hostname(device.host.clone()))?;
         session.set_option(SshOption::Port(device.port.clone()))?;
         session.set_option(SshOption::User(Some(device.username.clone())))?;
-        session.set_option(SshOption::KeyExchange(kex.join(",")))?;
-        session.set_option(SshOption::HmacCS(hmac.join(",")))?;
-        session.set_option(SshOption::HmacSC(hmac.join(",")))?;
-        session.set_option(SshOption::HostKeys(key_types.join(",")))?;
-        session.set_option(SshOption::PublicKeyAcceptedTypes(key_types.join(",")))?;
+        session.set_option(SshOption::KeyExchange(algorithms.kex.join(",")))?;
+        session.set_option(SshOption::HmacCS(algorithms.macs.join(",")))?;
+        session.set_option(SshOption::HmacSC(algorithms.macs.join(",")))?;
+        session.set_option(SshOption::HostKeys(algorithms.host_keys.join(",")))?;
+        session.set_option(SshOption::PublicKeyAcceptedTypes(
+            algorithms.host_keys.join(","),
+        ))?;
+        if let Some(ciphers) = &algorithms.ciphers {
+            session.set_option(SshOption::CiphersCS(ciphers.join(",")))?;
+            session.set_option(SshOption::CiphersSC(ciphers.join(",")))?;
+        }
+        if let Some(compression) = options.compression {
+            let methods = if compression {
+                "zlib@openssh.com,zlib,none"
+            } else {
+                "none"
+            };
+            session.set_option(SshOption::CompressionCS(String::from(methods)))?;
+            session.set_option(SshOption::CompressionSC(String::from(methods)))?;
+        }
         session.set_option(SshOption::ProcessConfig(false))?;
         #[cfg(windows)]
         {
@@ -69,42 +112,16 @@ impl DeviceConnection {
             session.set_option(SshOption::GlobalKnownHosts(Some(format!("/dev/null"))))?;
         }
 
-        session.connect()?;
-
-        if let Some(private_key) = &device.private_key {
-            let passphrase = device.valid_passphrase();
-            let priv_key_content = private_key.content(ssh_dir)?;
-            let priv_key = SshKey::from_privkey_base64(&priv_key_content, passphrase.as_deref())?;
-
-            if session.userauth_publickey(None, &priv_key)? != AuthStatus::Success {
-                return Err(Error::Authorization {
-                    message: format!("Key authorization failed"),
-                });
-            }
-        } else if let Some(password) = &device.password {
-            if session.userauth_password(None, Some(password))? != AuthStatus::Success {
-                return Err(Error::Authorization {
-                    message: format!("Bad SSH password"),
-                });
-            }
-        } else if session.userauth_none(None)? != AuthStatus::Success {
-            return Err(Error::Authorization {
-                message: format!("Host needs authorization"),
-            });
+        if let Some(tunnel) = tunnel {
+            tunnel.attach(&session)?;
         }
-        let connection = DeviceConnection {
-            id: Uuid::new_v4(),
-            device: device.clone(),
-            user: DeviceConnectionUserInfo::new(&session)?,
-            session,
-            last_ok: Mutex::new(true),
-        };
-        log::info!("{:?} created", connection);
-        return Ok(connection);
+        session.connect()?;
+        return Ok(session);
     }
 
     pub(super) fn reset_last_ok(&self) {
         *self
+            .stats
             .last_ok
             .lock()
             .expect("Failed to lock DeviceConnection::last_ok") = false;
@@ -112,9 +129,11 @@ impl DeviceConnection {
 
     pub fn mark_last_ok(&self) {
         *self
+            .stats
             .last_ok
             .lock()
             .expect("Failed to lock DeviceConnection::last_ok") = true;
+        self.stats.touch();
     }
 }
 
@@ -137,7 +156,8 @@ impl Drop for DeviceConnection {
         log::info!(
             "Dropping {:?}, last_ok={}",
             self,
-            self.last_ok
+            self.stats
+                .last_ok
                 .lock()
                 .expect("Failed to lock DeviceConnection::last_ok")
         );
@@ -154,7 +174,7 @@ impl Debug for DeviceConnection {
 }
 
 impl DeviceConnectionUserInfo {
-    fn new(session: &Session) -> Result<Option<DeviceConnectionUserInfo>, Error> {
+    pub(super) fn new(session: &Session) -> Result<Option<DeviceConnectionUserInfo>, Error> {
         let ch = session.new_channel()?;
         ch.open_session()?;
         ch.request_exec("id")?;
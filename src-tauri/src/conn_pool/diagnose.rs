@@ -0,0 +1,278 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use crate::conn_pool::auth::{authenticate, offered_methods};
+use crate::conn_pool::{
+    Algorithms, DeviceConnection, DeviceConnectionUserInfo, DiagnosticReport, DiagnosticStage,
+    DiagnosticStatus, InteractiveAuth, JumpTunnel,
+};
+use crate::device_manager::Device;
+use crate::error::Error;
+use crate::known_hosts::{fingerprint, KnownHosts};
+use crate::vault::SecretVault;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+const CLIENT_BANNER: &[u8] = b"SSH-2.0-devman_diagnose\r\n";
+const SSH_MSG_KEXINIT: u8 = 20;
+/// How many lines are read looking for the identification string of the server
+const MAX_BANNER_LINES: usize = 20;
+
+/// Algorithms listed in the `SSH_MSG_KEXINIT` of the server.
+struct KexInit {
+    kex: Vec<String>,
+    host_key: Vec<String>,
+    ciphers: Vec<String>,
+    macs: Vec<String>,
+}
+
+/// Connects to the device one stage at a time, stopping at the first stage that fails.
+pub(crate) fn run(
+    device: &Device,
+    ssh_dir: Option<&Path>,
+    conf_dir: Option<&Path>,
+    vault: &SecretVault,
+    interactive: &InteractiveAuth,
+) -> DiagnosticReport {
+    let mut report = DiagnosticReport {
+        device: device.name.clone(),
+        ok: false,
+        stages: Vec::new(),
+    };
+    let mut tunnel: Option<JumpTunnel> = None;
+    let mut server_algorithms: Option<KexInit> = None;
+    if device.jump_host.is_some() {
+        for name in ["dns", "tcp", "banner"] {
+            report.skip(name, json!({ "reason": "Behind a jump host" }));
+        }
+        let Some(opened) = report.stage("jump", || {
+            let chain = device.jump_chain(conf_dir)?;
+            let names: Vec<&str> = chain.iter().map(|d| d.name.as_str()).collect();
+            let opened = JumpTunnel::open(device, &chain, ssh_dir, conf_dir, vault, interactive)?;
+            return Ok((opened, json!({ "chain": names })));
+        }) else {
+            return report;
+        };
+        tunnel = opened;
+    } else {
+        let Some(addresses) = report.stage("dns", || resolve(device)) else {
+            return report;
+        };
+        let Some(stream) = report.stage("tcp", || connect(&addresses)) else {
+            return report;
+        };
+        let Some(kex_init) = report.stage("banner", || banner(stream)) else {
+            return report;
+        };
+        server_algorithms = Some(kex_init);
+    }
+    let Some(session) = report.stage("kex", || {
+        let session = DeviceConnection::connect_session(device, tunnel.as_mut())?;
+        let fingerprint = fingerprint(&session)?;
+        let mut detail = json!({ "fingerprint": fingerprint });
+        if let Some(server) = &server_algorithms {
+            let client = Algorithms::for_device(device);
+            detail["kex"] = json!(negotiate(&client.kex, &server.kex));
+            detail["hostKey"] = json!(negotiate(&client.host_keys, &server.host_key));
+            detail["mac"] = json!(negotiate(&client.macs, &server.macs));
+            if let Some(ciphers) = &client.ciphers {
+                detail["cipher"] = json!(negotiate(ciphers, &server.ciphers));
+            }
+            detail["serverCiphers"] = json!(server.ciphers);
+        }
+        // Only compared, diagnosing must not pin keys of devices never connected to
+        if let Some(conf_dir) = conf_dir {
+            detail["hostKeyStatus"] = match KnownHosts::new(conf_dir).get(&device.name)? {
+                None => json!("unpinned"),
+                Some(pinned) if pinned.fingerprint == fingerprint => json!("verified"),
+                Some(pinned) => {
+                    return Err(Error::HostKeyChanged {
+                        name: device.name.clone(),
+                        old_fingerprint: pinned.fingerprint,
+                        new_fingerprint: fingerprint,
+                    });
+                }
+            };
+        }
+        return Ok((session, detail));
+    }) else {
+        return report;
+    };
+    let authenticated = report.stage("auth", || {
+        let resolved = vault.resolve(device)?;
+        let method = authenticate(&session, &resolved, ssh_dir, interactive)?;
+        let offered = offered_methods(&session).unwrap_or_default();
+        return Ok(((), json!({ "method": method, "offered": offered })));
+    });
+    if authenticated.is_none() {
+        return report;
+    }
+    let checked = report.stage("user", || {
+        let detail = match DeviceConnectionUserInfo::new(&session)? {
+            Some(user) => json!({
+                "uid": format!("{:?}", user.uid),
+                "gid": format!("{:?}", user.gid),
+                "groups": user.groups.iter().map(|g| format!("{g:?}")).collect::<Vec<String>>(),
+            }),
+            None => Value::Null,
+        };
+        return Ok(((), detail));
+    });
+    report.ok = checked.is_some();
+    return report;
+}
+
+impl DiagnosticReport {
+    fn stage<T, F>(&mut self, name: &'static str, action: F) -> Option<T>
+    where
+        F: FnOnce() -> Result<(T, Value), Error>,
+    {
+        let started = Instant::now();
+        let result = action();
+        let elapsed_ms = started.elapsed().as_millis() as u64;
+        log::info!("Diagnose {}: {} took {}ms", self.device, name, elapsed_ms);
+        return match result {
+            Ok((value, detail)) => {
+                self.stages.push(DiagnosticStage {
+                    name,
+                    status: DiagnosticStatus::Ok,
+                    elapsed_ms,
+                    detail,
+                    error: None,
+                });
+                Some(value)
+            }
+            Err(e) => {
+                self.stages.push(DiagnosticStage {
+                    name,
+                    status: DiagnosticStatus::Failed,
+                    elapsed_ms,
+                    detail: Value::Null,
+                    error: Some(e),
+                });
+                None
+            }
+        };
+    }
+
+    fn skip(&mut self, name: &'static str, detail: Value) {
+        self.stages.push(DiagnosticStage {
+            name,
+            status: DiagnosticStatus::Skipped,
+            elapsed_ms: 0,
+            detail,
+            error: None,
+        });
+    }
+}
+
+fn resolve(device: &Device) -> Result<(Vec<SocketAddr>, Value), Error> {
+    let addresses: Vec<SocketAddr> = (device.host.as_str(), device.port)
+        .to_socket_addrs()?
+        .collect();
+    let detail = json!({
+        "addresses": addresses.iter().map(|a| a.ip().to_string()).collect::<Vec<String>>(),
+    });
+    return Ok((addresses, detail));
+}
+
+fn connect(addresses: &[SocketAddr]) -> Result<(TcpStream, Value), Error> {
+    let mut last_error = Error::io(std::io::ErrorKind::AddrNotAvailable);
+    for address in addresses {
+        match TcpStream::connect_timeout(address, PROBE_TIMEOUT) {
+            Ok(stream) => return Ok((stream, json!({ "address": address.to_string() }))),
+            Err(e) => last_error = e.into(),
+        }
+    }
+    return Err(last_error);
+}
+
+/// Reads the identification string of the server, then its `SSH_MSG_KEXINIT`.
+fn banner(mut stream: TcpStream) -> Result<(KexInit, Value), Error> {
+    stream.set_read_timeout(Some(PROBE_TIMEOUT))?;
+    stream.write_all(CLIENT_BANNER)?;
+    let mut banner = String::new();
+    // Servers may send other lines before the identification string
+    for _ in 0..MAX_BANNER_LINES {
+        banner = read_line(&mut stream)?;
+        if banner.starts_with("SSH-") {
+            break;
+        }
+    }
+    if !banner.starts_with("SSH-") {
+        return Err(Error::new("No SSH identification from server"));
+    }
+    let (version, software) = banner
+        .strip_prefix("SSH-")
+        .and_then(|s| s.split_once('-'))
+        .unwrap_or(("", ""));
+    let detail = json!({
+        "banner": banner,
+        "protoVersion": version,
+        "software": software.split(' ').next().unwrap_or(software),
+    });
+    return Ok((read_kex_init(&mut stream)?, detail));
+}
+
+fn read_line(stream: &mut TcpStream) -> Result<String, Error> {
+    let mut line = Vec::<u8>::new();
+    let mut byte = [0u8; 1];
+    while line.len() < 255 {
+        stream.read_exact(&mut byte)?;
+        if byte[0] == b'\n' {
+            break;
+        }
+        line.push(byte[0]);
+    }
+    return Ok(String::from_utf8_lossy(&line).trim_end().to_string());
+}
+
+fn read_kex_init(stream: &mut TcpStream) -> Result<KexInit, Error> {
+    let mut header = [0u8; 5];
+    stream.read_exact(&mut header)?;
+    let packet_len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    if packet_len < 1 || packet_len > 35000 {
+        return Err(Error::new("Malformed packet from server"));
+    }
+    let mut packet = vec![0u8; packet_len - 1];
+    stream.read_exact(&mut packet)?;
+    let payload_len = packet.len().saturating_sub(header[4] as usize);
+    let payload = &packet[..payload_len];
+    if payload.first() != Some(&SSH_MSG_KEXINIT) || payload.len() < 17 {
+        return Err(Error::new("Server didn't start key exchange"));
+    }
+    // Message type and cookie
+    let mut pos = 17;
+    let mut lists = Vec::<Vec<String>>::new();
+    for _ in 0..6 {
+        let len_bytes = payload
+            .get(pos..pos + 4)
+            .ok_or_else(|| Error::new("Malformed key exchange from server"))?;
+        let len = u32::from_be_bytes(len_bytes.try_into().unwrap()) as usize;
+        let list = payload
+            .get(pos + 4..pos + 4 + len)
+            .ok_or_else(|| Error::new("Malformed key exchange from server"))?;
+        lists.push(
+            String::from_utf8_lossy(list)
+                .split(',')
+                .filter(|s| !s.is_empty())
+                .map(String::from)
+                .collect(),
+        );
+        pos += 4 + len;
+    }
+    return Ok(KexInit {
+        kex: lists[0].clone(),
+        host_key: lists[1].clone(),
+        ciphers: lists[2].clone(),
+        macs: lists[4].clone(),
+    });
+}
+
+/// First algorithm of the client that the server also supports, as the SSH spec picks.
+fn negotiate(client: &[String], server: &[String]) -> Option<String> {
+    return client.iter().find(|c| server.contains(c)).cloned();
+}
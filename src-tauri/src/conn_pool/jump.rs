@@ -0,0 +1,415 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use libssh_rs::{Channel, Session, SshOption};
+use polling::{Event, Poller};
+
+use crate::conn_pool::algorithms::connect_timeout;
+use crate::conn_pool::{DeviceConnection, InteractiveAuth, JumpTunnel};
+use crate::device_manager::Device;
+use crate::error::Error;
+use crate::vault::SecretVault;
+
+#[cfg(unix)]
+pub(crate) type LocalStream = std::os::unix::net::UnixStream;
+#[cfg(not(unix))]
+pub(crate) type LocalStream = std::net::TcpStream;
+
+/// Connected socket given to libssh, instead of letting it connect by itself.
+#[cfg(unix)]
+pub(crate) type SessionSocket = std::os::fd::OwnedFd;
+#[cfg(not(unix))]
+pub(crate) type SessionSocket = std::os::windows::io::OwnedSocket;
+
+/// Key of the socket of the jump session in the poller, tunnels get the ones after it.
+const SESSION_KEY: usize = 0;
+
+/// How long the pump sleeps at most while several tunnels are open, see [pump].
+const PUMP_RECHECK: Duration = Duration::from_millis(500);
+
+/// Sessions to jump hosts, keyed by the connection fingerprint of the host and the hosts before
+/// it. Each slot is locked while its session connects, so tunnels don't race to open their own.
+static JUMP_SESSIONS: Mutex<Vec<(String, Arc<Mutex<Weak<JumpSession>>>)>> = Mutex::new(Vec::new());
+
+/// Session to a jump host, serving the forwarded channels of every tunnel through it.
+pub(crate) struct JumpSession {
+    shared: Arc<JumpShared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+struct JumpShared {
+    connection: DeviceConnection,
+    /// Duplicate of the socket of the session, only waited on
+    socket: SessionSocket,
+    poller: Poller,
+    forwards: Mutex<Vec<Forward>>,
+    next_key: AtomicUsize,
+    closed: AtomicBool,
+}
+
+struct Forward {
+    key: usize,
+    channel: Channel,
+    local: LocalStream,
+    /// Read from the channel, but not yet taken by the local socket
+    pending: Vec<u8>,
+    /// Set once the tunnel is dropped, or either end is closed
+    done: bool,
+}
+
+impl JumpTunnel {
+    /// Connects through the jump hosts in `chain`, returning the tunnel of the last hop.
+    ///
+    /// Sessions to the jump hosts are shared with the other tunnels through them.
+    pub(crate) fn open(
+        device: &Device,
+        chain: &[Device],
+        ssh_dir: Option<&Path>,
+        conf_dir: Option<&Path>,
+        vault: &SecretVault,
+        interactive: &InteractiveAuth,
+    ) -> Result<Option<JumpTunnel>, Error> {
+        if chain.is_empty() {
+            return Ok(None);
+        }
+        let session = JumpSession::shared(chain, ssh_dir, conf_dir, vault, interactive)?;
+        return Ok(Some(session.forward(device)?));
+    }
+
+    /// Lets the session connect through this tunnel instead of opening a TCP connection.
+    pub(crate) fn attach(&mut self, session: &Session) -> Result<(), Error> {
+        let socket = self.socket.take().ok_or(Error::Disconnected)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::io::IntoRawFd;
+            session.set_option(SshOption::Socket(socket.into_raw_fd()))?;
+        }
+        #[cfg(not(unix))]
+        {
+            use std::os::windows::io::IntoRawSocket;
+            session.set_option(SshOption::Socket(socket.into_raw_socket()))?;
+        }
+        return Ok(());
+    }
+
+    /// Plain TCP connection to the first jump host, so its session has a socket to wait on.
+    fn direct(device: &Device) -> Result<JumpTunnel, Error> {
+        let mut last_error = Error::io(ErrorKind::AddrNotAvailable);
+        for address in (device.host.as_str(), device.port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, connect_timeout(device)) {
+                Ok(stream) => {
+                    return Ok(JumpTunnel {
+                        session: None,
+                        key: 0,
+                        socket: Some(stream.into()),
+                    });
+                }
+                Err(e) if e.kind() == ErrorKind::TimedOut => last_error = Error::Timeout,
+                Err(e) => last_error = e.into(),
+            }
+        }
+        return Err(last_error);
+    }
+}
+
+impl Drop for JumpTunnel {
+    fn drop(&mut self) {
+        if let Some(session) = &self.session {
+            session.release(self.key);
+        }
+    }
+}
+
+impl JumpSession {
+    /// Session to the last host of `chain`, reused if one is open already.
+    fn shared(
+        chain: &[Device],
+        ssh_dir: Option<&Path>,
+        conf_dir: Option<&Path>,
+        vault: &SecretVault,
+        interactive: &InteractiveAuth,
+    ) -> Result<Arc<JumpSession>, Error> {
+        let (hop, before) = chain.split_last().ok_or_else(Error::bad_config)?;
+        let key = hop.connection_fingerprint(before);
+        let slot = {
+            let mut sessions = JUMP_SESSIONS.lock().unwrap();
+            // Slots being connected are locked, and kept
+            sessions.retain(|(_, slot)| {
+                slot.try_lock()
+                    .map_or(true, |session| session.strong_count() > 0)
+            });
+            match sessions.iter().find(|(k, _)| *k == key) {
+                Some((_, slot)) => slot.clone(),
+                None => {
+                    let slot = Arc::<Mutex<Weak<JumpSession>>>::default();
+                    sessions.push((key, slot.clone()));
+                    slot
+                }
+            }
+        };
+        let mut slot = slot.lock().unwrap();
+        if let Some(session) = slot.upgrade().filter(|s| s.is_alive()) {
+            return Ok(session);
+        }
+        let upstream = if before.is_empty() {
+            JumpTunnel::direct(hop)?
+        } else {
+            JumpSession::shared(before, ssh_dir, conf_dir, vault, interactive)?.forward(hop)?
+        };
+        let session = Arc::new(JumpSession::connect(
+            hop,
+            upstream,
+            ssh_dir,
+            conf_dir,
+            vault,
+            interactive,
+        )?);
+        *slot = Arc::downgrade(&session);
+        return Ok(session);
+    }
+
+    fn connect(
+        hop: &Device,
+        upstream: JumpTunnel,
+        ssh_dir: Option<&Path>,
+        conf_dir: Option<&Path>,
+        vault: &SecretVault,
+        interactive: &InteractiveAuth,
+    ) -> Result<JumpSession, Error> {
+        let socket = upstream
+            .socket
+            .as_ref()
+            .ok_or(Error::Disconnected)?
+            .try_clone()?;
+        let connection = DeviceConnection::open(
+            hop.clone(),
+            ssh_dir,
+            conf_dir,
+            vault,
+            interactive,
+            Some(upstream),
+        )?;
+        let poller = Poller::new()?;
+        poller.add(&socket, Event::none(SESSION_KEY))?;
+        let shared = Arc::new(JumpShared {
+            connection,
+            socket,
+            poller,
+            forwards: Mutex::default(),
+            next_key: AtomicUsize::new(SESSION_KEY + 1),
+            closed: AtomicBool::new(false),
+        });
+        let thread = {
+            let shared = shared.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = pump(&shared) {
+                    log::warn!(
+                        "Jump session to {} failed: {e:?}",
+                        shared.connection.device.name
+                    );
+                }
+                shared.closed.store(true, Ordering::SeqCst);
+                for forward in shared.forwards.lock().unwrap().drain(..) {
+                    forward.channel.close().unwrap_or(());
+                }
+            })
+        };
+        return Ok(JumpSession {
+            shared,
+            thread: Some(thread),
+        });
+    }
+
+    /// Opens a forwarded channel to `next`, bridged to a new local socket pair.
+    fn forward(self: &Arc<Self>, next: &Device) -> Result<JumpTunnel, Error> {
+        let shared = &self.shared;
+        log::info!(
+            "Jumping through {} to {}",
+            shared.connection.device.name,
+            next.name
+        );
+        let channel = shared.connection.new_channel()?;
+        channel.open_forward(&next.host, next.port, "127.0.0.1", 0)?;
+        let (local, remote) = stream_pair()?;
+        local.set_nonblocking(true)?;
+        let key = shared.next_key.fetch_add(1, Ordering::SeqCst);
+        shared.poller.add(&local, Event::none(key))?;
+        let mut forwards = shared.forwards.lock().unwrap();
+        if shared.closed.load(Ordering::SeqCst) {
+            shared.poller.delete(&local).unwrap_or(());
+            return Err(Error::Disconnected);
+        }
+        forwards.push(Forward {
+            key,
+            channel,
+            local,
+            pending: Vec::new(),
+            done: false,
+        });
+        drop(forwards);
+        // Opening the channel may have read data of the others off the socket
+        shared.poller.notify()?;
+        return Ok(JumpTunnel {
+            session: Some(self.clone()),
+            key,
+            socket: Some(remote.into()),
+        });
+    }
+
+    fn release(&self, key: usize) {
+        let shared = &self.shared;
+        let mut forwards = shared.forwards.lock().unwrap();
+        if let Some(forward) = forwards.iter_mut().find(|f| f.key == key) {
+            forward.done = true;
+        }
+        drop(forwards);
+        shared.poller.notify().unwrap_or(());
+    }
+
+    fn is_alive(&self) -> bool {
+        return !self.shared.closed.load(Ordering::SeqCst) && self.shared.connection.is_connected();
+    }
+}
+
+impl Drop for JumpSession {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::SeqCst);
+        self.shared.poller.notify().unwrap_or(());
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap_or(());
+        }
+        log::debug!(
+            "Jump session to {} closed",
+            self.shared.connection.device.name
+        );
+    }
+}
+
+impl Forward {
+    /// Moves what both ends have for each other, without waiting on either of them. Returns
+    /// whether anything moved.
+    fn serve(&mut self, buf: &mut [u8]) -> Result<bool, Error> {
+        let mut moved = false;
+        if !self.pending.is_empty() {
+            match self.local.write(&self.pending) {
+                Ok(n) => {
+                    self.pending.drain(..n);
+                    moved = true;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        // Nothing more is taken from the channel until the local socket catches up
+        if self.pending.is_empty() {
+            let n = match self.channel.read_timeout(buf, false, Some(Duration::ZERO)) {
+                Ok(n) => n,
+                Err(libssh_rs::Error::TryAgain) => 0,
+                Err(e) => return Err(e.into()),
+            };
+            if n > 0 {
+                let written = match self.local.write(&buf[..n]) {
+                    Ok(written) => written,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => 0,
+                    Err(e) => return Err(e.into()),
+                };
+                self.pending.extend_from_slice(&buf[written..n]);
+                moved = true;
+            } else if self.channel.is_eof() || self.channel.is_closed() {
+                self.done = true;
+            }
+        }
+        match self.local.read(buf) {
+            Ok(0) => self.done = true,
+            Ok(n) => {
+                self.channel.stdin().write_all(&buf[..n])?;
+                moved = true;
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e.into()),
+        }
+        return Ok(moved);
+    }
+}
+
+/// Copies data between the forwarded channels of the jump session and their local sockets.
+///
+/// Channels can't be waited on by themselves, so this waits for the socket of the session and the
+/// local sockets instead. Reading one channel may take data of another off the socket though,
+/// which nothing would wake up for, so with several tunnels open it checks again after a while.
+fn pump(shared: &JumpShared) -> Result<(), Error> {
+    let mut buf = [0u8; 16384];
+    let mut events = Vec::<Event>::new();
+    while !shared.closed.load(Ordering::SeqCst) {
+        if !shared.connection.is_connected() {
+            return Err(Error::Disconnected);
+        }
+        let mut forwards = shared.forwards.lock().unwrap();
+        let mut moved = true;
+        while moved {
+            moved = false;
+            for forward in forwards.iter_mut().filter(|f| !f.done) {
+                match forward.serve(&mut buf) {
+                    Ok(m) => moved |= m,
+                    Err(e) => {
+                        log::warn!("Tunnel {} closed: {e:?}", forward.key);
+                        forward.done = true;
+                    }
+                }
+            }
+        }
+        forwards.retain(|forward| {
+            if !forward.done {
+                return true;
+            }
+            shared.poller.delete(&forward.local).unwrap_or(());
+            forward.channel.close().unwrap_or(());
+            return false;
+        });
+        // The session is only read while some channel can take data
+        let reading = forwards.iter().any(|f| f.pending.is_empty());
+        let interest = if reading {
+            Event::readable(SESSION_KEY)
+        } else {
+            Event::none(SESSION_KEY)
+        };
+        shared.poller.modify(&shared.socket, interest)?;
+        for forward in forwards.iter() {
+            shared.poller.modify(
+                &forward.local,
+                Event {
+                    key: forward.key,
+                    readable: true,
+                    writable: !forward.pending.is_empty(),
+                },
+            )?;
+        }
+        let timeout = if forwards.len() > 1 {
+            Some(PUMP_RECHECK)
+        } else {
+            None
+        };
+        drop(forwards);
+        events.clear();
+        shared.poller.wait(&mut events, timeout)?;
+    }
+    return Ok(());
+}
+
+#[cfg(unix)]
+fn stream_pair() -> Result<(LocalStream, LocalStream), Error> {
+    return Ok(LocalStream::pair()?);
+}
+
+#[cfg(not(unix))]
+fn stream_pair() -> Result<(LocalStream, LocalStream), Error> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let remote = LocalStream::connect(listener.local_addr()?)?;
+    let (local, _) = listener.accept()?;
+    return Ok((local, remote));
+}
@@ -0,0 +1,55 @@
+use std::time::Duration;
+
+use libssh_rs::SshOption;
+
+use crate::conn_pool::algorithms::connect_timeout;
+use crate::conn_pool::{
+    ConnectionState, ConnectionStateListener, ConnectionStatus, DeviceConnection,
+};
+use crate::error::Error;
+
+/// Connections used more recently than this are assumed to be alive.
+const PROBE_AFTER_IDLE: Duration = Duration::from_secs(10);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+impl DeviceConnection {
+    /// Opens and closes a channel, failing fast if the host doesn't answer. The traffic also keeps
+    /// the session from being dropped by the host for inactivity.
+    pub(super) fn probe(&self) -> Result<(), Error> {
+        if self.stats.idle() < PROBE_AFTER_IDLE {
+            return Ok(());
+        }
+        self.session.set_option(SshOption::Timeout(PROBE_TIMEOUT))?;
+        let result = self.session.new_channel().and_then(|ch| {
+            ch.open_session()?;
+            return ch.close();
+        });
+        self.session
+            .set_option(SshOption::Timeout(connect_timeout(&self.device)))?;
+        result?;
+        return Ok(());
+    }
+}
+
+impl ConnectionStatus {
+    pub(super) fn new(device: &str, listener: ConnectionStateListener) -> ConnectionStatus {
+        return ConnectionStatus {
+            device: String::from(device),
+            state: Default::default(),
+            listener,
+        };
+    }
+
+    /// Notifies the listener if the state changed. A device that never connected can't be lost.
+    pub(super) fn set(&self, state: ConnectionState) {
+        let mut current = self.state.lock().unwrap();
+        if *current == Some(state) || (current.is_none() && state == ConnectionState::Lost) {
+            return;
+        }
+        *current = Some(state);
+        log::info!("Connection to {} is {:?}", self.device, state);
+        if let Some(listener) = self.listener.lock().unwrap().as_ref() {
+            listener.changed(&self.device, state);
+        }
+    }
+}
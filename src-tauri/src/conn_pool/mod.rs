@@ -1,29 +1,84 @@
 use crate::device_manager::Device;
 use crate::error::Error;
-use libssh_rs::Session;
+use crate::vault::SecretVault;
+use libssh_rs::{Session, Sftp};
 use r2d2::{Pool, PooledConnection};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Instant;
 use uuid::Uuid;
 
+mod agent;
+mod algorithms;
+mod auth;
 pub mod connection;
+pub mod diagnose;
+mod jump;
+mod liveness;
 pub mod pool;
+mod prompt;
+mod sftp;
+mod stats;
 
 pub struct DeviceConnection {
     id: Uuid,
     pub device: Device,
     pub user: Option<DeviceConnectionUserInfo>,
+    /// Opened on first use, see [DeviceConnection::with_sftp]
+    sftp: Mutex<Option<Arc<Sftp>>>,
+    /// Set once opening SFTP failed on a live session, see [DeviceConnection::files]
+    sftp_missing: AtomicBool,
     session: Session,
+    /// Dropped after the session, which is tunneled through it
+    jump: Option<JumpTunnel>,
+    stats: Arc<ConnectionStats>,
+}
+
+/// Bookkeeping of a connection, also held by its manager so it can be listed while in use.
+pub(crate) struct ConnectionStats {
+    id: Uuid,
+    user: Option<DeviceConnectionUserInfo>,
+    created_at: Instant,
     last_ok: Mutex<bool>,
+    last_active: Mutex<Instant>,
+    in_use: AtomicBool,
+    /// Set to have the pool drop the connection next time it's checked in or out
+    closing: AtomicBool,
+    sftp_opened: AtomicU64,
+    sftp_reused: AtomicU64,
+    sftp_invalidated: AtomicU64,
+}
+
+/// Algorithms offered when connecting to a device.
+pub(crate) struct Algorithms {
+    pub kex: Vec<String>,
+    pub macs: Vec<String>,
+    pub host_keys: Vec<String>,
+    /// Left to libssh if not overridden
+    pub ciphers: Option<Vec<String>>,
 }
 
-#[derive(Debug)]
+/// Forwarded channel on the session to a jump host, bridged to a local socket.
+pub(crate) struct JumpTunnel {
+    /// Shared with other tunnels through the same host, none for a plain TCP connection
+    session: Option<Arc<jump::JumpSession>>,
+    key: usize,
+    /// Handed over to the session connecting through the tunnel
+    socket: Option<jump::SessionSocket>,
+}
+
+#[derive(Serialize, Clone, Debug)]
 pub struct DeviceConnectionUserInfo {
     pub uid: Id,
     pub gid: Id,
     pub groups: Vec<Id>,
 }
 
+#[derive(Serialize, Clone)]
 pub struct Id {
     pub id: u32,
     pub name: Option<String>,
@@ -32,11 +87,143 @@ pub struct Id {
 pub type ManagedDeviceConnection = PooledConnection<DeviceConnectionManager>;
 
 pub struct DeviceConnectionPool {
+    pub device: Device,
+    /// Names of the hosts it jumps through
+    pub jumps: Vec<String>,
     inner: Pool<DeviceConnectionManager>,
     last_error: Arc<Mutex<Option<Error>>>,
+    status: Arc<ConnectionStatus>,
+    /// Shared with the manager, which adds the connections it opens
+    connections: Arc<Mutex<Vec<Weak<ConnectionStats>>>>,
 }
 
 pub struct DeviceConnectionManager {
     device: Device,
+    /// Jump hosts to connect through, see [Device::jump_chain]
+    chain: Vec<Device>,
     ssh_dir: Option<PathBuf>,
+    conf_dir: Option<PathBuf>,
+    vault: Arc<SecretVault>,
+    interactive: Arc<InteractiveAuth>,
+    status: Arc<ConnectionStatus>,
+    connections: Arc<Mutex<Vec<Weak<ConnectionStats>>>>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct PoolInfo {
+    pub device: String,
+    #[serde(rename = "maxSize")]
+    pub max_size: u32,
+    /// In seconds
+    #[serde(rename = "idleTimeout")]
+    pub idle_timeout: Option<u64>,
+    pub connections: Vec<ConnectionInfo>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ConnectionInfo {
+    pub id: String,
+    /// Seconds since the connection was opened
+    pub age: u64,
+    /// Seconds since the connection was last used
+    pub idle: u64,
+    #[serde(rename = "inUse")]
+    pub in_use: bool,
+    #[serde(rename = "lastOk")]
+    pub last_ok: bool,
+    pub user: Option<DeviceConnectionUserInfo>,
+    pub sftp: SftpStats,
+}
+
+/// How often the cached SFTP session of a connection was opened and reused.
+#[derive(Serialize, Clone, Debug)]
+pub struct SftpStats {
+    pub opened: u64,
+    pub reused: u64,
+    pub invalidated: u64,
+}
+
+pub type ConnectionStateListener =
+    Arc<Mutex<Option<Box<dyn ConnectionStateCallback + Send + Sync>>>>;
+
+pub trait ConnectionStateCallback {
+    fn changed(&self, device: &str, state: ConnectionState);
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// A connection is in use
+    #[serde(rename = "connected")]
+    Connected,
+    /// No connection is in use
+    #[serde(rename = "idle")]
+    Idle,
+    /// A connection was found dead
+    #[serde(rename = "lost")]
+    Lost,
+}
+
+/// Last connection state reported for a device, shared by its pool and connection manager.
+pub(crate) struct ConnectionStatus {
+    device: String,
+    state: Mutex<Option<ConnectionState>>,
+    listener: ConnectionStateListener,
+}
+
+/// Keyboard-interactive answers given by the user, kept in memory until the app exits.
+#[derive(Default)]
+pub struct InteractiveAuth {
+    prompter: Mutex<Option<Arc<dyn AuthPrompter + Send + Sync>>>,
+    /// Answers keyed by device name and prompt text, only for prompts that can be asked again
+    answers: Mutex<HashMap<(String, String), String>>,
+    declined: Mutex<HashMap<String, Instant>>,
+}
+
+pub trait AuthPrompter {
+    /// Asks the user to answer the prompts, returns `None` if cancelled.
+    fn prompt(&self, request: &AuthPromptRequest) -> Result<Option<Vec<String>>, Error>;
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct AuthPromptRequest {
+    pub device: String,
+    pub name: String,
+    pub instruction: String,
+    pub prompts: Vec<AuthPrompt>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct AuthPrompt {
+    pub prompt: String,
+    pub echo: bool,
+}
+
+/// Outcome of each stage of a connection attempt, see [diagnose::run].
+#[derive(Serialize, Clone, Debug)]
+pub struct DiagnosticReport {
+    pub device: String,
+    pub ok: bool,
+    pub stages: Vec<DiagnosticStage>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct DiagnosticStage {
+    pub name: &'static str,
+    pub status: DiagnosticStatus,
+    #[serde(rename = "elapsedMs")]
+    pub elapsed_ms: u64,
+    #[serde(skip_serializing_if = "Value::is_null")]
+    pub detail: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<Error>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiagnosticStatus {
+    #[serde(rename = "ok")]
+    Ok,
+    #[serde(rename = "failed")]
+    Failed,
+    #[serde(rename = "skipped")]
+    Skipped,
 }
@@ -1,34 +1,84 @@
 use std::fmt::Debug;
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, Weak};
 use std::time::Duration;
 
 use r2d2::{HandleError, ManageConnection, Pool};
 
 use crate::conn_pool::{
-    DeviceConnection, DeviceConnectionManager, DeviceConnectionPool, ManagedDeviceConnection,
+    ConnectionInfo, ConnectionState, ConnectionStateListener, ConnectionStats, ConnectionStatus,
+    DeviceConnection, DeviceConnectionManager, DeviceConnectionPool, InteractiveAuth,
+    ManagedDeviceConnection, PoolInfo,
 };
 use crate::device_manager::Device;
 use crate::error::Error;
+use crate::vault::SecretVault;
 
 impl DeviceConnectionPool {
-    pub fn new(device: Device, ssh_dir: Option<PathBuf>) -> DeviceConnectionPool {
+    /// `chain` is resolved once here, the pool is dropped if the jump hosts change.
+    pub fn new(
+        device: Device,
+        chain: Vec<Device>,
+        ssh_dir: Option<PathBuf>,
+        conf_dir: Option<PathBuf>,
+        vault: Arc<SecretVault>,
+        interactive: Arc<InteractiveAuth>,
+        listener: ConnectionStateListener,
+    ) -> DeviceConnectionPool {
         let last_error = Arc::<Mutex<Option<Error>>>::default();
+        let status = Arc::new(ConnectionStatus::new(&device.name, listener));
+        let connections = Arc::<Mutex<Vec<Weak<ConnectionStats>>>>::default();
+        let jumps = chain.iter().map(|jump| jump.name.clone()).collect();
+        let options = device.pool.clone().unwrap_or_default();
+        let idle_timeout = match options.idle_timeout.unwrap_or(900) {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
         let inner = Pool::<DeviceConnectionManager>::builder()
             .min_idle(Some(0))
-            .max_size(3)
-            .idle_timeout(Some(Duration::from_secs(900)))
+            .max_size(options.max_size.unwrap_or(3).max(1))
+            .idle_timeout(idle_timeout)
             .error_handler(Box::new(DeviceConnectionErrorHandler {
                 last_error: last_error.clone(),
             }))
-            .build_unchecked(DeviceConnectionManager { device, ssh_dir });
-        return DeviceConnectionPool { inner, last_error };
+            .build_unchecked(DeviceConnectionManager {
+                device: device.clone(),
+                chain,
+                ssh_dir,
+                conf_dir,
+                vault,
+                interactive,
+                status: status.clone(),
+                connections: connections.clone(),
+            });
+        return DeviceConnectionPool {
+            device,
+            jumps,
+            inner,
+            last_error,
+            status,
+            connections,
+        };
     }
 
     pub fn get(&self) -> Result<ManagedDeviceConnection, Error> {
-        return match self.inner.get() {
+        return self.checked_out(self.inner.get());
+    }
+
+    /// Like [DeviceConnectionPool::get], but waits for a connection at most `timeout`.
+    pub fn get_timeout(&self, timeout: Duration) -> Result<ManagedDeviceConnection, Error> {
+        return self.checked_out(self.inner.get_timeout(timeout));
+    }
+
+    fn checked_out(
+        &self,
+        result: Result<ManagedDeviceConnection, r2d2::Error>,
+    ) -> Result<ManagedDeviceConnection, Error> {
+        return match result {
             Ok(c) => {
                 c.reset_last_ok();
+                c.stats.set_in_use(true);
+                self.status.set(ConnectionState::Connected);
                 Ok(c)
             }
             Err(_) => Err(self
@@ -39,6 +89,80 @@ impl DeviceConnectionPool {
                 .unwrap_or(Error::Timeout)),
         };
     }
+
+    /// Probes the idle connections, so dead ones are dropped before anyone checks them out.
+    pub fn keepalive(&self) {
+        self.check_idle();
+        let state = self.inner.state();
+        if state.connections > state.idle_connections {
+            self.status.set(ConnectionState::Connected);
+        } else if state.connections > 0 {
+            self.status.set(ConnectionState::Idle);
+        }
+    }
+
+    /// Connections the pool keeps open at most.
+    pub fn max_size(&self) -> u32 {
+        return self.inner.max_size();
+    }
+
+    pub fn info(&self) -> PoolInfo {
+        let mut connections: Vec<ConnectionInfo> =
+            self.connections().iter().map(|c| c.info()).collect();
+        connections.sort_by_key(|c| std::cmp::Reverse(c.age));
+        return PoolInfo {
+            device: self.device.name.clone(),
+            max_size: self.inner.max_size(),
+            idle_timeout: self.inner.idle_timeout().map(|d| d.as_secs()),
+            connections,
+        };
+    }
+
+    /// Closes the connection right away if it's idle, otherwise once it's no longer in use.
+    /// Returns false if the pool doesn't have this connection.
+    pub fn close(&self, id: &str) -> bool {
+        let connections = self.connections();
+        let Some(stats) = connections.iter().find(|c| c.id() == id) else {
+            return false;
+        };
+        log::info!("Closing connection {} to {}", id, self.device.name);
+        stats.close();
+        self.check_idle();
+        return true;
+    }
+
+    /// Checks out every idle connection once, so the pool validates them, and closes the ones
+    /// unused for longer than the idle timeout.
+    ///
+    /// Checking out restarts the idle timer of r2d2, so idleness is tracked by [ConnectionStats],
+    /// which only counts checkouts through [DeviceConnectionPool::get].
+    fn check_idle(&self) {
+        let idle = self.inner.state().idle_connections;
+        let idle_timeout = self.inner.idle_timeout();
+        let mut checked = Vec::<ManagedDeviceConnection>::new();
+        for _ in 0..idle {
+            // Connections failing validation are dropped, and the next idle one is tried
+            let Some(c) = self.inner.try_get() else {
+                break;
+            };
+            if idle_timeout.map_or(false, |timeout| c.stats.idle() >= timeout) {
+                log::info!(
+                    "Closing idle connection {} to {}",
+                    c.stats.id(),
+                    self.device.name
+                );
+                c.stats.close();
+            }
+            checked.push(c);
+        }
+    }
+
+    /// Connections of this pool that are still open.
+    fn connections(&self) -> Vec<Arc<ConnectionStats>> {
+        let mut connections = self.connections.lock().unwrap();
+        connections.retain(|c| c.strong_count() > 0);
+        return connections.iter().filter_map(|c| c.upgrade()).collect();
+    }
 }
 
 impl ManageConnection for DeviceConnectionManager {
@@ -46,18 +170,49 @@ impl ManageConnection for DeviceConnectionManager {
     type Error = Error;
 
     fn connect(&self) -> Result<Self::Connection, Self::Error> {
-        return DeviceConnection::new(self.device.clone(), self.ssh_dir.as_deref());
+        let result = DeviceConnection::new(
+            self.device.clone(),
+            &self.chain,
+            self.ssh_dir.as_deref(),
+            self.conf_dir.as_deref(),
+            &self.vault,
+            &self.interactive,
+        );
+        match &result {
+            Ok(conn) => self
+                .connections
+                .lock()
+                .unwrap()
+                .push(Arc::downgrade(&conn.stats)),
+            Err(_) => self.status.set(ConnectionState::Lost),
+        }
+        return result;
     }
 
-    fn is_valid(&self, _: &mut Self::Connection) -> Result<(), Self::Error> {
+    fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), Self::Error> {
+        if conn.stats.is_closing() {
+            return Err(Error::Disconnected);
+        }
+        if let Err(e) = conn.probe() {
+            log::warn!("{:?} is dead: {:?}", conn, e);
+            self.status.set(ConnectionState::Lost);
+            return Err(e);
+        }
         return Ok(());
     }
 
     fn has_broken(&self, conn: &mut Self::Connection) -> bool {
+        if conn.stats.take_in_use() {
+            conn.stats.touch();
+        }
+        if conn.stats.is_closing() {
+            return true;
+        }
         if !conn.is_connected() {
+            self.status.set(ConnectionState::Lost);
             return true;
         }
-        return conn.last_ok.lock().unwrap().eq(&false);
+        return conn.stats.last_ok.lock().unwrap().eq(&false);
     }
 }
 
@@ -78,8 +233,12 @@ struct DeviceConnectionCustomizer {}
 impl Clone for DeviceConnectionPool {
     fn clone(&self) -> Self {
         return DeviceConnectionPool {
+            device: self.device.clone(),
+            jumps: self.jumps.clone(),
             inner: self.inner.clone(),
             last_error: self.last_error.clone(),
+            status: self.status.clone(),
+            connections: self.connections.clone(),
         };
     }
 }
@@ -0,0 +1,122 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use libssh_rs::InteractiveAuthInfo;
+use regex::Regex;
+
+use crate::conn_pool::{AuthPrompt, AuthPromptRequest, AuthPrompter, InteractiveAuth};
+use crate::device_manager::Device;
+use crate::error::Error;
+
+/// Connection attempts right after the user cancelled a prompt fail without asking again, as the
+/// pool retries failed connections for a while.
+const DECLINE_PERIOD: Duration = Duration::from_secs(30);
+
+impl InteractiveAuth {
+    pub fn set_prompter(&self, prompter: Box<dyn AuthPrompter + Send + Sync>) {
+        *self.prompter.lock().unwrap() = Some(Arc::from(prompter));
+    }
+
+    /// Answers for one round of keyboard-interactive prompts.
+    ///
+    /// Cached answers are used first, then hidden prompts are answered with the password of the
+    /// device, and the rest are asked to the user. Only answers to hidden prompts that don't look
+    /// like one-time codes are cached. Returns the answers, and whether any of them came from the
+    /// cache.
+    pub(crate) fn answer(
+        &self,
+        device: &Device,
+        info: &InteractiveAuthInfo,
+    ) -> Result<(Vec<String>, bool), Error> {
+        let cached = self.answers.lock().unwrap().clone();
+        let mut answers: Vec<Option<String>> = info
+            .prompts
+            .iter()
+            .map(|p| {
+                cached
+                    .get(&(device.name.clone(), p.prompt.clone()))
+                    .cloned()
+                    .or_else(|| device.password.clone().filter(|_| !p.echo))
+            })
+            .collect();
+        let from_cache = info
+            .prompts
+            .iter()
+            .any(|p| cached.contains_key(&(device.name.clone(), p.prompt.clone())));
+        let missing: Vec<usize> = (0..answers.len())
+            .filter(|i| answers[*i].is_none())
+            .collect();
+        if !missing.is_empty() {
+            let request = AuthPromptRequest {
+                device: device.name.clone(),
+                name: info.name.clone(),
+                instruction: info.instruction.clone(),
+                prompts: missing
+                    .iter()
+                    .map(|i| AuthPrompt {
+                        prompt: info.prompts[*i].prompt.clone(),
+                        echo: info.prompts[*i].echo,
+                    })
+                    .collect(),
+            };
+            let replies = self.ask(&request)?;
+            if replies.len() != missing.len() {
+                return Err(Error::new("Prompts were not fully answered"));
+            }
+            let mut cache = self.answers.lock().unwrap();
+            for (i, reply) in missing.into_iter().zip(replies) {
+                if reusable(&info.prompts[i].prompt, info.prompts[i].echo) {
+                    cache.insert(
+                        (device.name.clone(), info.prompts[i].prompt.clone()),
+                        reply.clone(),
+                    );
+                }
+                answers[i] = Some(reply);
+            }
+        }
+        return Ok((answers.into_iter().flatten().collect(), from_cache));
+    }
+
+    /// Drops cached answers of the device, so the user will be asked again next time.
+    pub fn forget(&self, name: &str) {
+        self.answers
+            .lock()
+            .unwrap()
+            .retain(|(device, _), _| device != name);
+    }
+
+    fn ask(&self, request: &AuthPromptRequest) -> Result<Vec<String>, Error> {
+        if let Some(declined) = self.declined.lock().unwrap().get(&request.device) {
+            if declined.elapsed() < DECLINE_PERIOD {
+                return Err(Error::new("Cancelled by user"));
+            }
+        }
+        // Not held while the user answers, which can take a while
+        let prompter = self.prompter.lock().unwrap().clone();
+        let Some(prompter) = prompter else {
+            return Err(Error::new("No answer for host prompts"));
+        };
+        log::info!("Asking for {} keyboard-interactive answers", request.device);
+        return match prompter.prompt(request)? {
+            Some(answers) => {
+                self.declined.lock().unwrap().remove(&request.device);
+                Ok(answers)
+            }
+            None => {
+                self.declined
+                    .lock()
+                    .unwrap()
+                    .insert(request.device.clone(), Instant::now());
+                Err(Error::new("Cancelled by user"))
+            }
+        };
+    }
+}
+
+/// Whether the answer to the prompt can be given again, unlike visible answers or one-time codes.
+fn reusable(prompt: &str, echo: bool) -> bool {
+    let one_time = Regex::new(r"(?i)code|token|otp|one[- ]time|verification|authenticator")
+        .unwrap()
+        .is_match(prompt);
+    return !echo && !one_time;
+}
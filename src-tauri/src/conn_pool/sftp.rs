@@ -0,0 +1,80 @@
+use std::io::ErrorKind;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use libssh_rs::Sftp;
+
+use crate::conn_pool::DeviceConnection;
+use crate::device_manager::DeviceFileTransfer;
+use crate::error::Error;
+
+impl DeviceConnection {
+    /// Runs `action` with the SFTP session of this connection, opening it on first use.
+    ///
+    /// The SFTP session is dropped if `action` fails for any reason other than the file itself or
+    /// a cancellation, so the next call starts with a fresh one.
+    pub fn with_sftp<T, F>(&self, action: F) -> Result<T, Error>
+    where
+        F: FnOnce(&Sftp) -> Result<T, Error>,
+    {
+        let sftp = self.sftp()?;
+        let result = action(&sftp);
+        if let Err(e) = &result {
+            if !is_file_error(e) && !matches!(e, Error::Cancelled) {
+                log::debug!("{:?} dropping SFTP session after {:?}", self, e);
+                self.sftp.lock().unwrap().take();
+                self.stats.sftp_invalidated.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        return result;
+    }
+
+    /// How files are transferred over this connection: as set for the device, otherwise over SFTP
+    /// unless the device turns out not to have it.
+    pub fn files(&self) -> DeviceFileTransfer {
+        if let Some(files) = &self.device.files {
+            return files.clone();
+        }
+        if self.sftp_missing.load(Ordering::SeqCst) {
+            return DeviceFileTransfer::Stream;
+        }
+        if let Err(e) = self.sftp() {
+            // A dropped connection is retried instead
+            if self.session.is_connected() {
+                log::info!("{:?} has no SFTP ({:?}), using exec channels", self, e);
+                self.sftp_missing.store(true, Ordering::SeqCst);
+                return DeviceFileTransfer::Stream;
+            }
+        }
+        return DeviceFileTransfer::Sftp;
+    }
+
+    fn sftp(&self) -> Result<Arc<Sftp>, Error> {
+        let mut cached = self.sftp.lock().unwrap();
+        if let Some(sftp) = cached.as_ref() {
+            self.stats.sftp_reused.fetch_add(1, Ordering::SeqCst);
+            return Ok(sftp.clone());
+        }
+        let sftp = Arc::new(self.session.sftp()?);
+        self.stats.sftp_opened.fetch_add(1, Ordering::SeqCst);
+        log::debug!("{:?} opened SFTP session", self);
+        *cached = Some(sftp.clone());
+        return Ok(sftp);
+    }
+}
+
+/// Errors about the file being worked on, which leave the SFTP session usable.
+fn is_file_error(e: &Error) -> bool {
+    return match e {
+        Error::IO { code, .. } => matches!(
+            code,
+            ErrorKind::NotFound
+                | ErrorKind::PermissionDenied
+                | ErrorKind::AlreadyExists
+                | ErrorKind::UnexpectedEof
+                | ErrorKind::InvalidData
+        ),
+        Error::NotFound | Error::Unsupported => true,
+        _ => false,
+    };
+}
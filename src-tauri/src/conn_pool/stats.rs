@@ -0,0 +1,69 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+use crate::conn_pool::{ConnectionInfo, ConnectionStats, DeviceConnectionUserInfo, SftpStats};
+
+impl ConnectionStats {
+    pub(super) fn new(id: Uuid, user: Option<DeviceConnectionUserInfo>) -> ConnectionStats {
+        return ConnectionStats {
+            id,
+            user,
+            created_at: Instant::now(),
+            last_ok: Mutex::new(true),
+            last_active: Mutex::new(Instant::now()),
+            in_use: AtomicBool::new(false),
+            closing: AtomicBool::new(false),
+            sftp_opened: AtomicU64::new(0),
+            sftp_reused: AtomicU64::new(0),
+            sftp_invalidated: AtomicU64::new(0),
+        };
+    }
+
+    pub(super) fn touch(&self) {
+        *self.last_active.lock().unwrap() = Instant::now();
+    }
+
+    pub(super) fn idle(&self) -> Duration {
+        return self.last_active.lock().unwrap().elapsed();
+    }
+
+    pub(super) fn id(&self) -> String {
+        return self.id.to_string();
+    }
+
+    pub(super) fn set_in_use(&self, in_use: bool) {
+        self.in_use.store(in_use, Ordering::SeqCst);
+    }
+
+    /// Clears the in use flag, returning whether it was set.
+    pub(super) fn take_in_use(&self) -> bool {
+        return self.in_use.swap(false, Ordering::SeqCst);
+    }
+
+    pub(super) fn close(&self) {
+        self.closing.store(true, Ordering::SeqCst);
+    }
+
+    pub(super) fn is_closing(&self) -> bool {
+        return self.closing.load(Ordering::SeqCst);
+    }
+
+    pub(super) fn info(&self) -> ConnectionInfo {
+        return ConnectionInfo {
+            id: self.id(),
+            age: self.created_at.elapsed().as_secs(),
+            idle: self.idle().as_secs(),
+            in_use: self.in_use.load(Ordering::SeqCst),
+            last_ok: *self.last_ok.lock().unwrap(),
+            user: self.user.clone(),
+            sftp: SftpStats {
+                opened: self.sftp_opened.load(Ordering::SeqCst),
+                reused: self.sftp_reused.load(Ordering::SeqCst),
+                invalidated: self.sftp_invalidated.load(Ordering::SeqCst),
+            },
+        };
+    }
+}
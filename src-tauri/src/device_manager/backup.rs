@@ -0,0 +1,145 @@
+use std::fs;
+use std::fs::create_dir_all;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::Value;
+
+use crate::device_manager::{Device, DeviceBackup};
+use crate::error::Error;
+
+const MAX_BACKUPS: usize = 10;
+
+/// Copies the device list into the backup directory, and drops the oldest backups.
+pub(crate) fn create(path: &Path) -> Result<(), Error> {
+    let dir = backup_dir(path)?;
+    create_dir_all(&dir)?;
+    let created_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+    // Writes landing in the same millisecond get a sequence number, instead of overwriting
+    let target = (0..)
+        .map(|seq| dir.join(backup_name(created_at, seq)))
+        .find(|target| !target.exists())
+        .unwrap();
+    fs::copy(path, target)?;
+    let backups = list(path)?;
+    for old in backups.iter().skip(MAX_BACKUPS) {
+        log::debug!("Removing old device list backup {}", old.name);
+        fs::remove_file(dir.join(&old.name)).unwrap_or(());
+    }
+    return Ok(());
+}
+
+/// Lists backups of the device list, newest first.
+pub(crate) fn list(path: &Path) -> Result<Vec<DeviceBackup>, Error> {
+    let dir = backup_dir(path)?;
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            return match e.kind() {
+                ErrorKind::NotFound => Ok(Vec::new()),
+                _ => Err(e.into()),
+            };
+        }
+    };
+    let mut backups = Vec::<(u64, DeviceBackup)>::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let Some((created_at, seq)) = parse_name(&name) else {
+            continue;
+        };
+        let backup = DeviceBackup {
+            name,
+            created_at,
+            size: entry.metadata()?.len(),
+        };
+        backups.push((seq, backup));
+    }
+    backups.sort_by_key(|(seq, b)| std::cmp::Reverse((b.created_at, *seq)));
+    return Ok(backups.into_iter().map(|(_, b)| b).collect());
+}
+
+/// Removes plaintext passwords and passphrases from all backups, once they've been moved into the
+/// vault. Entries of devices still in `devices` are pointed at their vault entries instead.
+pub(crate) fn scrub_secrets(path: &Path, devices: &[Device]) -> Result<(), Error> {
+    let dir = backup_dir(path)?;
+    for backup in list(path)? {
+        let backup_path = dir.join(&backup.name);
+        let mut entries = match serde_json::from_slice::<Vec<Value>>(&fs::read(&backup_path)?) {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::warn!("Skipping unreadable backup {}: {:?}", backup.name, e);
+                continue;
+            }
+        };
+        let mut changed = false;
+        for entry in &mut entries {
+            let Value::Object(map) = entry else {
+                continue;
+            };
+            let device = map
+                .get("name")
+                .and_then(|name| name.as_str())
+                .and_then(|name| devices.iter().find(|d| d.name == name));
+            let password_ref = device.and_then(|d| d.password_ref.clone());
+            let passphrase_ref = device.and_then(|d| d.passphrase_ref.clone());
+            let secrets = [
+                ("password", "passwordRef", password_ref),
+                ("passphrase", "passphraseRef", passphrase_ref),
+            ];
+            for (key, ref_key, id) in secrets {
+                if map.remove(key).is_none() {
+                    continue;
+                }
+                changed = true;
+                if let Some(id) = id {
+                    map.entry(ref_key).or_insert(Value::String(id));
+                }
+            }
+        }
+        if changed {
+            log::info!("Removing plaintext secrets from backup {}", backup.name);
+            fs::write(&backup_path, serde_json::to_vec_pretty(&entries)?)?;
+        }
+    }
+    return Ok(());
+}
+
+/// Reads a backup, making sure it's still a valid device list.
+pub(crate) fn read(path: &Path, name: &str) -> Result<Vec<u8>, Error> {
+    if name.contains(|c| c == '/' || c == '\\') {
+        return Err(Error::io(ErrorKind::InvalidInput));
+    }
+    let data = fs::read(backup_dir(path)?.join(name))?;
+    serde_json::from_slice::<Vec<Value>>(&data)?;
+    return Ok(data);
+}
+
+/// `novacom-devices-<millis>.json`, or `novacom-devices-<millis>-<seq>.json` after the first.
+fn backup_name(created_at: u128, seq: u64) -> String {
+    return match seq {
+        0 => format!("novacom-devices-{created_at}.json"),
+        seq => format!("novacom-devices-{created_at}-{seq}.json"),
+    };
+}
+
+fn parse_name(name: &str) -> Option<(u64, u64)> {
+    let stem = name
+        .strip_prefix("novacom-devices-")?
+        .strip_suffix(".json")?;
+    return match stem.split_once('-') {
+        Some((created_at, seq)) => Some((created_at.parse().ok()?, seq.parse().ok()?)),
+        None => Some((stem.parse().ok()?, 0)),
+    };
+}
+
+fn backup_dir(path: &Path) -> Result<PathBuf, Error> {
+    return path
+        .parent()
+        .map(|parent| parent.join("novacom-devices.backup"))
+        .ok_or_else(|| Error::bad_config());
+}
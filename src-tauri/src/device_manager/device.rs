@@ -1,7 +1,166 @@
-use crate::device_manager::Device;
+use std::path::Path;
+
+use serde_json::{Map, Value};
+
+use crate::device_manager::io::read_sync;
+use crate::device_manager::{AuthMethod, Device, JumpHost, PrivateKey, SshAgent};
+use crate::error::Error;
+
+const MAX_JUMPS: usize = 4;
 
 impl Device {
     pub(crate) fn valid_passphrase(&self) -> Option<String> {
         return self.passphrase.clone().filter(|s| !s.is_empty());
     }
+
+    /// Configured authentication methods, or the ones its credentials allow if not set.
+    pub(crate) fn auth_methods(&self) -> Vec<AuthMethod> {
+        if let Some(methods) = &self.auth_methods {
+            return methods.clone();
+        }
+        let mut methods = Vec::new();
+        if self.private_key.is_some() {
+            methods.push(AuthMethod::PublicKey);
+        }
+        if self.password.is_some() || self.password_ref.is_some() {
+            // Hosts often only ask for the password through keyboard-interactive
+            methods.push(AuthMethod::Password);
+            methods.push(AuthMethod::KeyboardInteractive);
+        }
+        if methods.is_empty() {
+            methods.push(AuthMethod::None);
+        }
+        return methods;
+    }
+
+    /// Hosts to tunnel through to reach this device, outermost first.
+    pub(crate) fn jump_chain(&self, conf_dir: Option<&Path>) -> Result<Vec<Device>, Error> {
+        let mut devices: Option<Vec<Device>> = None;
+        return self.resolve_jumps(|name| {
+            if devices.is_none() {
+                devices = Some(read_sync(conf_dir)?.devices);
+            }
+            return Ok(devices
+                .as_ref()
+                .unwrap()
+                .iter()
+                .find(|d| d.name == name)
+                .cloned());
+        });
+    }
+
+    /// Like [Device::jump_chain], with jump hosts looked up in `devices`.
+    pub(crate) fn jump_chain_in(&self, devices: &[Device]) -> Result<Vec<Device>, Error> {
+        return self.resolve_jumps(|name| Ok(devices.iter().find(|d| d.name == name).cloned()));
+    }
+
+    fn resolve_jumps<F>(&self, mut lookup: F) -> Result<Vec<Device>, Error>
+    where
+        F: FnMut(&str) -> Result<Option<Device>, Error>,
+    {
+        let mut chain = Vec::<Device>::new();
+        let mut current = self.clone();
+        while let Some(jump) = &current.jump_host {
+            let next = match jump {
+                JumpHost::Device { device: name } => lookup(name)?
+                    .ok_or_else(|| Error::new(format!("Jump host {name} not found")))?,
+                JumpHost::Host {
+                    host,
+                    port,
+                    username,
+                    private_key,
+                } => Device {
+                    order: None,
+                    default: None,
+                    profile: self.profile.clone(),
+                    name: format!("{username}@{host}:{port}"),
+                    description: None,
+                    host: host.clone(),
+                    port: *port,
+                    username: username.clone(),
+                    new: false,
+                    private_key: Some(
+                        private_key
+                            .clone()
+                            .unwrap_or(PrivateKey::Agent { agent: SshAgent }),
+                    ),
+                    files: None,
+                    passphrase: None,
+                    password: None,
+                    passphrase_ref: None,
+                    password_ref: None,
+                    auth_methods: None,
+                    jump_host: None,
+                    ssh_options: None,
+                    pool: None,
+                    log_daemon: None,
+                    no_port_forwarding: None,
+                    indelible: None,
+                    extras: Map::new(),
+                },
+            };
+            if next.name == self.name
+                || chain.iter().any(|d| d.name == next.name)
+                || chain.len() >= MAX_JUMPS
+            {
+                return Err(Error::new(format!(
+                    "Too many jump hosts, or a loop, in the way to {}",
+                    self.name
+                )));
+            }
+            chain.insert(0, next.clone());
+            current = next;
+        }
+        return Ok(chain);
+    }
+
+    /// Vault entries referenced by this device.
+    pub(crate) fn secret_refs(&self) -> Vec<String> {
+        return self
+            .password_ref
+            .iter()
+            .chain(self.passphrase_ref.iter())
+            .cloned()
+            .collect();
+    }
+
+    /// Hash of the fields that affect how a connection to this device is made, including the ones
+    /// of the hosts in its jump `chain`.
+    pub(crate) fn connection_fingerprint(&self, chain: &[Device]) -> String {
+        let fields: Vec<Value> = [self]
+            .into_iter()
+            .chain(chain)
+            .map(|device| device.connection_fields())
+            .collect();
+        return sha256::digest(Value::from(fields).to_string());
+    }
+
+    fn connection_fields(&self) -> Value {
+        return serde_json::json!([
+            self.name,
+            self.host,
+            self.port,
+            self.username,
+            self.private_key,
+            self.passphrase,
+            self.password,
+            self.passphrase_ref,
+            self.password_ref,
+            self.auth_methods,
+            self.jump_host,
+            self.ssh_options,
+            self.pool,
+        ]);
+    }
+}
+
+impl AuthMethod {
+    pub(crate) fn name(&self) -> &'static str {
+        return match self {
+            AuthMethod::PublicKey => "publickey",
+            AuthMethod::Password => "password",
+            AuthMethod::KeyboardInteractive => "keyboard-interactive",
+            AuthMethod::None => "none",
+        };
+    }
 }
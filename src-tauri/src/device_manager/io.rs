@@ -1,74 +1,188 @@
 use std::fs;
-use std::fs::{create_dir_all, File};
-use std::io::{BufReader, BufWriter, ErrorKind};
+use std::fs::{create_dir_all, rename, File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Write};
 use std::path::{Path, PathBuf};
 
+use fs2::FileExt;
 use serde_json::Value;
+use uuid::Uuid;
 
-use crate::device_manager::Device;
+use crate::device_manager::backup;
+use crate::device_manager::{Device, DeviceList, InvalidDevice};
 use crate::error::Error;
 
-pub(crate) async fn read(conf_dir: Option<&Path>) -> Result<Vec<Device>, Error> {
+/// Keys of the device editor form, never written by ares-cli or other tools.
+const FRONTEND_KEYS: [&str; 3] = ["address", "sshUsername", "sshAuth"];
+
+/// Advisory lock on the device list, held for the whole read-modify-write of a mutation.
+pub(crate) struct DevicesLock {
+    file: File,
+}
+
+pub(crate) async fn lock(conf_dir: Option<&Path>) -> Result<DevicesLock, Error> {
     let conf_dir = conf_dir.map(|conf_dir| conf_dir.to_path_buf());
-    return tokio::task::spawn_blocking(move || -> Result<Vec<Device>, Error> {
-        let path = devices_file_path(conf_dir.as_deref())?;
-        let file = match File::open(path.as_path()) {
-            Ok(file) => file,
+    return tokio::task::spawn_blocking(move || lock_sync(conf_dir.as_deref()))
+        .await
+        .expect("critical failure in app::io::lock task");
+}
+
+pub(crate) fn lock_sync(conf_dir: Option<&Path>) -> Result<DevicesLock, Error> {
+    let path = devices_file_path(conf_dir)?;
+    let parent = path.parent().ok_or_else(|| Error::bad_config())?;
+    create_dir_all(parent)?;
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .open(parent.join("novacom-devices.json.lock"))?;
+    file.lock_exclusive()?;
+    return Ok(DevicesLock { file });
+}
+
+pub(crate) async fn read(conf_dir: Option<&Path>) -> Result<DeviceList, Error> {
+    let conf_dir = conf_dir.map(|conf_dir| conf_dir.to_path_buf());
+    return tokio::task::spawn_blocking(move || read_sync(conf_dir.as_deref()))
+        .await
+        .expect("critical failure in app::io::read task");
+}
+
+pub(crate) fn read_sync(conf_dir: Option<&Path>) -> Result<DeviceList, Error> {
+    let path = devices_file_path(conf_dir)?;
+    let file = match File::open(path.as_path()) {
+        Ok(file) => file,
+        Err(e) => {
+            return match e.kind() {
+                ErrorKind::NotFound => Ok(DeviceList::default()),
+                _ => Err(e.into()),
+            };
+        }
+    };
+    let reader = BufReader::new(file);
+
+    let raw_list: Vec<Value> = serde_json::from_reader(reader)?;
+    let mut list = DeviceList::default();
+    for (index, raw) in raw_list.into_iter().enumerate() {
+        match serde_json::from_value::<Device>(raw.clone()) {
+            Ok(device) => list.devices.push(device),
             Err(e) => {
-                return match e.kind() {
-                    ErrorKind::NotFound => Ok(Vec::new()),
-                    _ => Err(e.into()),
-                };
+                log::warn!("Failed to parse device entry: {e}");
+                list.invalid.push(InvalidDevice {
+                    name: raw.get("name").and_then(|v| v.as_str()).map(String::from),
+                    reason: e.to_string(),
+                    raw,
+                    index,
+                });
             }
-        };
-        let reader = BufReader::new(file);
+        }
+    }
+    return Ok(list);
+}
 
-        let raw_list: Vec<Value> = serde_json::from_reader(reader)?;
-        return Ok(raw_list
-            .iter()
-            .filter_map(|v| serde_json::from_value::<Device>(v.clone()).ok())
-            .collect());
-    })
-    .await
-    .expect("critical failure in app::io::read task");
+/// Writes the device list back, with entries that failed to parse put back as they were read, at
+/// the position they were read from.
+pub(crate) async fn write(
+    list: DeviceList,
+    conf_dir: Option<&Path>,
+    lock: &DevicesLock,
+) -> Result<(), Error> {
+    return write_list(list, conf_dir, lock, true).await;
+}
+
+/// Like [write], but without backing up the current list, which holds secrets that shouldn't be
+/// kept around.
+pub(crate) async fn write_without_backup(
+    list: DeviceList,
+    conf_dir: Option<&Path>,
+    lock: &DevicesLock,
+) -> Result<(), Error> {
+    return write_list(list, conf_dir, lock, false).await;
 }
 
-pub(crate) async fn write(devices: Vec<Device>, conf_dir: Option<&Path>) -> Result<(), Error> {
+async fn write_list(
+    list: DeviceList,
+    conf_dir: Option<&Path>,
+    _lock: &DevicesLock,
+    backup: bool,
+) -> Result<(), Error> {
     let conf_dir = conf_dir.map(|conf_dir| conf_dir.to_path_buf());
     return tokio::task::spawn_blocking(move || -> Result<(), Error> {
         let path = devices_file_path(conf_dir.as_deref())?;
-        let file = match File::create(path.as_path()) {
-            Ok(file) => file,
-            Err(e) => {
-                match e.kind() {
-                    ErrorKind::PermissionDenied => {
-                        fix_devices_json_perm(path.clone())?;
-                    }
-                    ErrorKind::NotFound => {
-                        let parent = path.parent().ok_or_else(|| Error::bad_config())?;
-                        create_dir_all(parent)?;
-                    }
-                    _ => return Err(e.into()),
-                }
-                File::create(path.as_path())?
-            }
-        };
-        log::info!("make the file writable: {:?}", path);
-        file.metadata()?.permissions().set_readonly(false);
-        let writer = BufWriter::new(file);
-        serde_json::to_writer_pretty(writer, &devices)?;
-        return Ok(());
+        let mut raw_list = list
+            .devices
+            .iter()
+            .map(|device| device_value(device))
+            .collect::<Result<Vec<Value>, serde_json::Error>>()?;
+        for invalid in list.invalid {
+            raw_list.insert(invalid.index.min(raw_list.len()), invalid.raw);
+        }
+        return replace(&path, &serde_json::to_vec_pretty(&raw_list)?, backup);
     })
     .await
     .expect("critical failure in app::io::write task");
 }
 
-fn devices_file_path(conf_dir: Option<&Path>) -> Result<PathBuf, Error> {
+/// Backs up the current device list if `backup` is set, then atomically replaces it with `data`.
+pub(crate) fn replace(path: &Path, data: &[u8], backup: bool) -> Result<(), Error> {
+    let parent = path.parent().ok_or_else(|| Error::bad_config())?;
+    create_dir_all(parent)?;
+    if backup && path.exists() {
+        backup::create(path)?;
+    }
+    let temp_path = parent.join(format!(".novacom-devices.json.{}.tmp", Uuid::new_v4()));
+    let result = write_temp(&temp_path, data)
+        .and_then(|_| keep_permissions(path, &temp_path))
+        .and_then(|_| match rename(&temp_path, path) {
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+                fix_devices_json_perm(path.to_path_buf())?;
+                Ok(rename(&temp_path, path)?)
+            }
+            r => Ok(r?),
+        });
+    if result.is_err() {
+        fs::remove_file(&temp_path).unwrap_or(());
+    }
+    return result;
+}
+
+pub(crate) fn devices_file_path(conf_dir: Option<&Path>) -> Result<PathBuf, Error> {
     return conf_dir
         .map(|conf_dir| conf_dir.join("novacom-devices.json"))
         .ok_or_else(|| Error::bad_config());
 }
 
+/// Serializes the device, leaving out fields of the device editor form the UI may send along.
+fn device_value(device: &Device) -> Result<Value, serde_json::Error> {
+    let mut value = serde_json::to_value(device)?;
+    if let Value::Object(map) = &mut value {
+        for key in FRONTEND_KEYS {
+            map.remove(key);
+        }
+    }
+    return Ok(value);
+}
+
+/// Gives the file about to replace `path` the same permissions, since renaming drops them.
+fn keep_permissions(path: &Path, temp_path: &Path) -> Result<(), Error> {
+    match fs::metadata(path) {
+        Ok(metadata) => fs::set_permissions(temp_path, metadata.permissions())?,
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    return Ok(());
+}
+
+fn write_temp(path: &Path, data: &[u8]) -> Result<(), Error> {
+    let mut file = File::create(path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    return Ok(());
+}
+
+impl Drop for DevicesLock {
+    fn drop(&mut self) {
+        self.file.unlock().unwrap_or(());
+    }
+}
+
 #[cfg(not(unix))]
 fn fix_devices_json_perm(path: PathBuf) -> Result<(), Error> {
     let mut perm = fs::metadata(path.clone())?.permissions();
@@ -1,27 +1,44 @@
 use std::fs;
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use libssh_rs::SshKey;
 use tokio::fs::{remove_file, File};
 use tokio::io::AsyncWriteExt;
 
 use crate::app_dirs::{GetConfDir, GetSshDir, SetConfDir, SetSshDir};
-use crate::device_manager::io::{read, write};
-use crate::device_manager::{Device, DeviceManager, PrivateKey};
+use crate::device_manager::io::{
+    devices_file_path, lock, read, replace, write, write_without_backup, DevicesLock,
+};
+use crate::device_manager::{backup, watcher};
+use crate::device_manager::{
+    Device, DeviceBackup, DeviceList, DeviceManager, JumpHost, PrivateKey, RestoredDeviceList,
+};
 use crate::error::Error;
+use crate::known_hosts::KnownHosts;
+use crate::vault::SecretVault;
 
 impl DeviceManager {
-    pub async fn list(&self) -> Result<Vec<Device>, Error> {
-        let devices = read(self.get_conf_dir().as_deref()).await?;
-        *self.devices.lock().unwrap() = devices.clone();
-        return Ok(devices);
+    pub fn new(vault: Arc<SecretVault>) -> DeviceManager {
+        return DeviceManager {
+            vault,
+            ..Default::default()
+        };
+    }
+
+    pub async fn list(&self) -> Result<DeviceList, Error> {
+        let list = read(self.get_conf_dir().as_deref()).await?;
+        *self.devices.lock().unwrap() = list.devices.clone();
+        return Ok(list);
     }
 
     pub async fn set_default(&self, name: &str) -> Result<Option<Device>, Error> {
         let conf_dir = self.get_conf_dir();
-        let mut devices = read(conf_dir.as_deref()).await?;
+        let lock = lock(conf_dir.as_deref()).await?;
+        let mut list = read(conf_dir.as_deref()).await?;
         let mut result: Option<Device> = None;
-        for device in &mut devices {
+        for device in &mut list.devices {
             if device.name == name {
                 device.default = Some(true);
                 result = Some(device.clone());
@@ -29,49 +46,111 @@ impl DeviceManager {
                 device.default = None;
             }
         }
-        log::trace!("{:?}", devices);
-        write(devices, conf_dir.as_deref()).await?;
+        log::trace!("{:?}", list.devices);
+        self.commit(list, conf_dir.as_deref(), &lock).await?;
         return Ok(result);
     }
 
     pub async fn add(&self, device: &Device) -> Result<Device, Error> {
         let conf_dir = self.get_conf_dir();
         let mut device = device.clone();
-        if let Some(key) = &device.private_key {
-            match key {
-                PrivateKey::Path { name } => {
-                    let path = Path::new(name);
-                    if path.is_absolute() {
-                        let name = String::from(
-                            pathdiff::diff_paths(path, self.ensure_ssh_dir()?)
-                                .ok_or(Error::NotFound)?
-                                .to_string_lossy(),
-                        );
-                        device.private_key = Some(PrivateKey::Path { name });
+        self.save_key(&mut device).await?;
+        log::info!("Save device {}", device.name);
+        let lock = lock(conf_dir.as_deref()).await?;
+        let mut list = read(conf_dir.as_deref()).await?;
+        let sealed = self.seal(&mut device)?;
+        list.devices.push(device.clone());
+        let result = self.commit(list, conf_dir.as_deref(), &lock).await;
+        if result.is_err() {
+            self.remove_secrets(sealed);
+        }
+        result?;
+        return Ok(device);
+    }
+
+    /// Replaces the device named `name` with `device`, keeping its `default` and `order` flags.
+    ///
+    /// Devices jumping through a renamed device are changed to refer to its new name.
+    pub async fn update(&self, name: &str, device: &Device) -> Result<Device, Error> {
+        let conf_dir = self.get_conf_dir();
+        let mut device = device.clone();
+        log::info!("Update device {} => {}", name, device.name);
+        let lock = lock(conf_dir.as_deref()).await?;
+        let mut list = read(conf_dir.as_deref()).await?;
+        if device.name != name && list.devices.iter().any(|d| d.name == device.name) {
+            return Err(Error::io(ErrorKind::AlreadyExists));
+        }
+        let index = list
+            .devices
+            .iter()
+            .position(|d| d.name == name)
+            .ok_or(Error::NotFound)?;
+        self.save_key(&mut device).await?;
+        let sealed = self.seal(&mut device)?;
+        if device.name != name {
+            for other in &mut list.devices {
+                if let Some(JumpHost::Device { device: jump }) = &mut other.jump_host {
+                    if jump == name {
+                        *jump = device.name.clone();
                     }
                 }
-                PrivateKey::Data { data } => {
-                    let name = key.name(device.valid_passphrase())?;
-                    let key_path = self.ensure_ssh_dir()?.join(&name);
-                    let mut file = File::create(key_path).await?;
-                    file.write(data.as_bytes()).await?;
-                    device.private_key = Some(PrivateKey::Path { name });
+            }
+        }
+        let existing = &mut list.devices[index];
+        device.default = existing.default;
+        device.order = existing.order.clone();
+        for (key, value) in &existing.extras {
+            if !device.extras.contains_key(key) {
+                device.extras.insert(key.clone(), value.clone());
+            }
+        }
+        let old = std::mem::replace(existing, device.clone());
+        let new_secrets = device.secret_refs();
+        let orphan_secrets: Vec<String> = old
+            .secret_refs()
+            .into_iter()
+            .filter(|id| !new_secrets.contains(id))
+            .collect();
+        let orphan_key = match old.private_key {
+            Some(PrivateKey::Path { name }) if name.starts_with("webos_") => Some(name),
+            _ => None,
+        }
+        .filter(|key_name| {
+            !list.devices.iter().any(|d| match &d.private_key {
+                Some(PrivateKey::Path { name }) => name == key_name,
+                _ => false,
+            })
+        });
+        let result = self.commit(list, conf_dir.as_deref(), &lock).await;
+        if result.is_err() {
+            self.remove_secrets(sealed);
+        }
+        result?;
+        drop(lock);
+        // The device is saved already, what's left over is only cleaned up as far as possible
+        self.remove_secrets(orphan_secrets);
+        if let Some(key_name) = orphan_key {
+            self.remove_key(&key_name).await;
+        }
+        if device.name != name {
+            if let Some(conf_dir) = &conf_dir {
+                if let Err(e) = KnownHosts::new(conf_dir).rename(name, &device.name) {
+                    log::warn!("Failed to move host key of {}: {:?}", name, e);
                 }
             }
         }
-        log::info!("Save device {}", device.name);
-        let mut devices = read(conf_dir.as_deref()).await?;
-        devices.push(device.clone());
-        write(devices.clone(), conf_dir.as_deref()).await?;
         return Ok(device);
     }
 
     pub async fn remove(&self, name: &str, remove_key: bool) -> Result<(), Error> {
         let conf_dir = self.get_conf_dir();
-        let devices = read(conf_dir.as_deref()).await?;
+        let lock = lock(conf_dir.as_deref()).await?;
+        let mut list = read(conf_dir.as_deref()).await?;
         let (will_delete, mut will_keep): (Vec<Device>, Vec<Device>) =
-            devices.into_iter().partition(|d| d.name == name);
+            list.devices.into_iter().partition(|d| d.name == name);
+        let secrets: Vec<String> = will_delete.iter().flat_map(|d| d.secret_refs()).collect();
         let mut need_new_default = false;
+        let mut keys = Vec::<String>::new();
         if remove_key {
             for device in will_delete {
                 if device.default.unwrap_or(false) {
@@ -84,15 +163,174 @@ impl DeviceManager {
                     if !name.starts_with("webos_") {
                         continue;
                     }
-                    let key_path = self.ensure_ssh_dir()?.join(name);
-                    remove_file(key_path).await?;
+                    keys.push(name);
                 }
             }
         }
         if need_new_default && !will_keep.is_empty() {
             will_keep.first_mut().unwrap().default = Some(true);
         }
-        write(will_keep, conf_dir.as_deref()).await?;
+        list.devices = will_keep;
+        self.commit(list, conf_dir.as_deref(), &lock).await?;
+        drop(lock);
+        self.remove_secrets(secrets);
+        for key_name in keys {
+            self.remove_key(&key_name).await;
+        }
+        return Ok(());
+    }
+
+    /// Moves plaintext passwords and passphrases of all devices into the vault.
+    pub async fn migrate_secrets(&self) -> Result<usize, Error> {
+        let conf_dir = self.get_conf_dir();
+        let lock = lock(conf_dir.as_deref()).await?;
+        let mut list = read(conf_dir.as_deref()).await?;
+        let mut migrated = 0;
+        let mut sealed = Vec::<String>::new();
+        for device in &mut list.devices {
+            let created = self.seal(device)?;
+            if !created.is_empty() {
+                log::info!("Moved secrets of {} into vault", device.name);
+                migrated += 1;
+                sealed.extend(created);
+            }
+        }
+        let devices = list.devices.clone();
+        if migrated > 0 {
+            // A backup of the current list would keep the plaintext secrets on disk
+            let result = write_without_backup(list, conf_dir.as_deref(), &lock).await;
+            if result.is_err() {
+                self.remove_secrets(sealed);
+            }
+            result?;
+            *self.devices.lock().unwrap() = devices.clone();
+        }
+        let path = devices_file_path(conf_dir.as_deref())?;
+        tokio::task::spawn_blocking(move || backup::scrub_secrets(&path, &devices))
+            .await
+            .expect("critical failure in DeviceManager::migrate_secrets task")?;
+        return Ok(migrated);
+    }
+
+    pub async fn backups(&self) -> Result<Vec<DeviceBackup>, Error> {
+        let path = devices_file_path(self.get_conf_dir().as_deref())?;
+        return tokio::task::spawn_blocking(move || backup::list(&path))
+            .await
+            .expect("critical failure in DeviceManager::backups task");
+    }
+
+    pub async fn restore(&self, name: &str) -> Result<RestoredDeviceList, Error> {
+        let conf_dir = self.get_conf_dir();
+        let path = devices_file_path(conf_dir.as_deref())?;
+        let name = String::from(name);
+        log::info!("Restore device list from {}", name);
+        let lock = lock(conf_dir.as_deref()).await?;
+        tokio::task::spawn_blocking(move || -> Result<(), Error> {
+            let data = backup::read(&path, &name)?;
+            return replace(&path, &data, true);
+        })
+        .await
+        .expect("critical failure in DeviceManager::restore task")?;
+        let list = read(conf_dir.as_deref()).await?;
+        let old = std::mem::replace(&mut *self.devices.lock().unwrap(), list.devices.clone());
+        drop(lock);
+        // Unlike other changes, the UI doesn't know which devices a backup has changed
+        watcher::report(&self.callback, &old, &list.devices);
+        let missing_secrets: Vec<String> = list
+            .devices
+            .iter()
+            .filter(|d| {
+                d.secret_refs()
+                    .iter()
+                    .any(|id| matches!(self.vault.contains(id), Ok(false)))
+            })
+            .map(|d| d.name.clone())
+            .collect();
+        if !missing_secrets.is_empty() {
+            log::warn!(
+                "Restored devices refer to removed vault entries: {}",
+                missing_secrets.join(", ")
+            );
+        }
+        return Ok(RestoredDeviceList {
+            list,
+            missing_secrets,
+        });
+    }
+
+    /// Writes the device list, and caches it while still holding the lock, so the watcher doesn't
+    /// report changes made by this app.
+    async fn commit(
+        &self,
+        list: DeviceList,
+        conf_dir: Option<&Path>,
+        lock: &DevicesLock,
+    ) -> Result<(), Error> {
+        let devices = list.devices.clone();
+        write(list, conf_dir, lock).await?;
+        *self.devices.lock().unwrap() = devices;
+        return Ok(());
+    }
+
+    /// Moves the plaintext secrets of the device into the vault, returning the entries created,
+    /// which are removed again if the device can't be saved.
+    fn seal(&self, device: &mut Device) -> Result<Vec<String>, Error> {
+        let existing = device.secret_refs();
+        self.vault.seal(device)?;
+        return Ok(device
+            .secret_refs()
+            .into_iter()
+            .filter(|id| !existing.contains(id))
+            .collect());
+    }
+
+    /// Removes vault entries no device refers to anymore, logging failures.
+    fn remove_secrets(&self, ids: Vec<String>) {
+        for id in ids {
+            if let Err(e) = self.vault.remove(&id) {
+                log::warn!("Failed to remove vault entry {}: {:?}", id, e);
+            }
+        }
+    }
+
+    /// Removes a key file no device refers to anymore, logging failures.
+    async fn remove_key(&self, name: &str) {
+        log::info!("Remove unused key {}", name);
+        let result = match self.ensure_ssh_dir() {
+            Ok(ssh_dir) => remove_file(ssh_dir.join(name)).await.map_err(Error::from),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            log::warn!("Failed to remove key {}: {:?}", name, e);
+        }
+    }
+
+    /// Writes inline key data into the ssh dir, and makes key paths relative to it.
+    async fn save_key(&self, device: &mut Device) -> Result<(), Error> {
+        let Some(key) = &device.private_key else {
+            return Ok(());
+        };
+        match key {
+            PrivateKey::Path { name } => {
+                let path = Path::new(name);
+                if path.is_absolute() {
+                    let name = String::from(
+                        pathdiff::diff_paths(path, self.ensure_ssh_dir()?)
+                            .ok_or(Error::NotFound)?
+                            .to_string_lossy(),
+                    );
+                    device.private_key = Some(PrivateKey::Path { name });
+                }
+            }
+            PrivateKey::Data { data } => {
+                let name = key.name(device.valid_passphrase())?;
+                let key_path = self.ensure_ssh_dir()?.join(&name);
+                let mut file = File::create(key_path).await?;
+                file.write(data.as_bytes()).await?;
+                device.private_key = Some(PrivateKey::Path { name });
+            }
+            PrivateKey::Agent { .. } => {}
+        }
         return Ok(());
     }
 
@@ -150,6 +388,7 @@ impl GetConfDir for DeviceManager {
 
 impl SetConfDir for DeviceManager {
     fn set_conf_dir(&self, dir: PathBuf) {
-        *self.conf_dir.lock().unwrap() = Some(dir);
+        *self.conf_dir.lock().unwrap() = Some(dir.clone());
+        self.watch(dir);
     }
 }
@@ -1,12 +1,18 @@
 use std::path::PathBuf;
-use std::sync::Mutex;
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Mutex};
 
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 
+use crate::vault::SecretVault;
+
+mod backup;
 mod device;
 mod io;
 mod manager;
 mod privkey;
+mod watcher;
 
 #[derive(PartialEq, Eq, Hash)]
 pub struct DeviceSessionToken {
@@ -18,7 +24,27 @@ pub struct DeviceSessionToken {
 pub struct DeviceManager {
     ssh_dir: Mutex<Option<PathBuf>>,
     conf_dir: Mutex<Option<PathBuf>>,
-    devices: Mutex<Vec<Device>>,
+    devices: Arc<Mutex<Vec<Device>>>,
+    vault: Arc<SecretVault>,
+    callback: Arc<DevicesCallbackSlot>,
+    watch_generation: Arc<AtomicU64>,
+}
+
+pub trait DevicesCallback {
+    fn changed(&self, diff: &DeviceListDiff);
+}
+
+type DevicesCallbackSlot = Mutex<Option<Box<dyn DevicesCallback + Send + Sync>>>;
+
+/// Changes of the device list, found by comparing entries with the same name.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct DeviceListDiff {
+    pub added: Vec<Device>,
+    pub removed: Vec<String>,
+    pub modified: Vec<Device>,
+    /// Modified devices whose connection settings changed, so their connections are stale
+    #[serde(skip)]
+    pub reconnect: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -32,6 +58,85 @@ pub enum PrivateKey {
         #[serde(rename = "openSshData")]
         data: String,
     },
+    /// Authenticate with the keys held by ssh-agent, instead of a key file
+    Agent {
+        #[serde(rename = "sshAgent")]
+        agent: SshAgent,
+    },
+}
+
+/// Value of `sshAgent`, which can only be `true`.
+#[derive(Clone, Debug)]
+pub struct SshAgent;
+
+/// Host to tunnel the connection through, like `ProxyJump` of OpenSSH.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum JumpHost {
+    /// Another entry of the device list
+    Device { device: String },
+    Host {
+        host: String,
+        #[serde(default = "default_ssh_port")]
+        port: u16,
+        username: String,
+        /// Key to authenticate with, ssh-agent is used if not set
+        #[serde(
+            rename = "privateKey",
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        private_key: Option<PrivateKey>,
+    },
+}
+
+/// Algorithm lists replace the defaults, or modify them with OpenSSH style `+`, `^` and `-`
+/// prefixes.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SshOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ciphers: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kex: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub macs: Option<Vec<String>>,
+    #[serde(rename = "hostKeys", default, skip_serializing_if = "Option::is_none")]
+    pub host_keys: Option<Vec<String>>,
+    /// Seconds to wait for the connection, 10 by default or when 0
+    #[serde(
+        rename = "connectTimeout",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub connect_timeout: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PoolOptions {
+    /// Connections kept open at most, 3 by default
+    #[serde(rename = "maxSize", default, skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<u32>,
+    /// Seconds before an unused connection is closed, 900 by default, 0 to never close it
+    #[serde(
+        rename = "idleTimeout",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub idle_timeout: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthMethod {
+    #[serde(rename = "publickey")]
+    PublicKey,
+    #[serde(rename = "password")]
+    Password,
+    #[serde(rename = "keyboard-interactive")]
+    KeyboardInteractive,
+    #[serde(rename = "none")]
+    None,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -57,6 +162,31 @@ pub struct Device {
     pub passphrase: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub password: Option<String>,
+    /// Vault entry holding the passphrase, see [crate::vault::SecretVault].
+    #[serde(rename = "passphraseRef", skip_serializing_if = "Option::is_none")]
+    pub passphrase_ref: Option<String>,
+    /// Vault entry holding the password, see [crate::vault::SecretVault].
+    #[serde(rename = "passwordRef", skip_serializing_if = "Option::is_none")]
+    pub password_ref: Option<String>,
+    /// Authentication methods to try in order, see [Device::auth_methods].
+    #[serde(
+        rename = "authMethods",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub auth_methods: Option<Vec<AuthMethod>>,
+    #[serde(rename = "jumpHost", default, skip_serializing_if = "Option::is_none")]
+    pub jump_host: Option<JumpHost>,
+    /// Overrides of SSH connection settings, ignored by ares-cli.
+    #[serde(
+        rename = "sshOptions",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub ssh_options: Option<SshOptions>,
+    /// Connection pool settings, ignored by ares-cli.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool: Option<PoolOptions>,
     #[serde(rename = "logDaemon", skip_serializing_if = "Option::is_none")]
     pub log_daemon: Option<String>,
     #[serde(
@@ -67,6 +197,46 @@ pub struct Device {
     pub no_port_forwarding: Option<bool>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub indelible: Option<bool>,
+    /// Fields written by other tools, kept as-is so they survive a rewrite of the file.
+    #[serde(flatten)]
+    pub extras: Map<String, Value>,
+}
+
+/// Content of `novacom-devices.json`, including entries that can't be parsed as [Device].
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct DeviceList {
+    pub devices: Vec<Device>,
+    pub invalid: Vec<InvalidDevice>,
+}
+
+/// Device list brought back from a backup.
+#[derive(Serialize, Clone, Debug)]
+pub struct RestoredDeviceList {
+    #[serde(flatten)]
+    pub list: DeviceList,
+    /// Devices referring to vault entries removed since the backup, whose password or passphrase
+    /// has to be entered again
+    #[serde(rename = "missingSecrets")]
+    pub missing_secrets: Vec<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct DeviceBackup {
+    pub name: String,
+    #[serde(rename = "createdAt")]
+    pub created_at: u64,
+    pub size: u64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct InvalidDevice {
+    pub name: Option<String>,
+    pub reason: String,
+    #[serde(skip_serializing)]
+    pub(crate) raw: Value,
+    /// Position in the file, where the entry is put back when the list is written
+    #[serde(skip_serializing)]
+    pub(crate) index: usize,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -76,3 +246,7 @@ pub enum DeviceFileTransfer {
     #[serde(rename = "sftp")]
     Sftp,
 }
+
+fn default_ssh_port() -> u16 {
+    return 22;
+}
@@ -2,8 +2,10 @@ use std::io::Read;
 use std::path::Path;
 
 use libssh_rs::{PublicKeyHashType, SshKey};
+use serde::de::{Error as _, Unexpected};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-use crate::device_manager::PrivateKey;
+use crate::device_manager::{PrivateKey, SshAgent};
 use crate::error::Error;
 
 impl PrivateKey {
@@ -17,6 +19,7 @@ impl PrivateKey {
                 Ok(secret)
             }
             PrivateKey::Data { data } => Ok(data.clone()),
+            PrivateKey::Agent { .. } => Err(Error::Unsupported),
         };
     }
 
@@ -29,6 +32,28 @@ impl PrivateKey {
                         .get_public_key_hash(PublicKeyHashType::Sha256)?,
                 )[..10],
             )),
+            PrivateKey::Agent { .. } => Ok(String::from("ssh-agent")),
         };
     }
 }
+
+impl Serialize for SshAgent {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        return serializer.serialize_bool(true);
+    }
+}
+
+impl<'de> Deserialize<'de> for SshAgent {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        if !bool::deserialize(deserializer)? {
+            return Err(D::Error::invalid_value(Unexpected::Bool(false), &"true"));
+        }
+        return Ok(SshAgent);
+    }
+}
@@ -0,0 +1,134 @@
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::time::Duration;
+
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::device_manager::io::{devices_file_path, lock_sync, read_sync};
+use crate::device_manager::{
+    Device, DeviceListDiff, DeviceManager, DevicesCallback, DevicesCallbackSlot,
+};
+
+/// How often the watcher checks whether it has been replaced by another one.
+const GENERATION_CHECK: Duration = Duration::from_millis(1000);
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+impl DeviceManager {
+    pub fn set_callback(&self, callback: Box<dyn DevicesCallback + Send + Sync>) {
+        *self.callback.lock().unwrap() = Some(callback);
+    }
+
+    /// Watches the device file in the background, until the conf dir is changed again.
+    ///
+    /// Changes made by this app are not reported, since the device list is written and cached
+    /// under the lock the watcher takes before reading it.
+    pub(super) fn watch(&self, conf_dir: PathBuf) {
+        let generation = self.watch_generation.fetch_add(1, Ordering::SeqCst) + 1;
+        let current_generation = self.watch_generation.clone();
+        let devices = self.devices.clone();
+        let callback = self.callback.clone();
+        std::thread::spawn(move || {
+            let path = devices_file_path(Some(&conf_dir)).unwrap();
+            let (tx, rx) = channel();
+            // The file is replaced by renaming, so its directory is watched instead
+            let watcher =
+                RecommendedWatcher::new(tx, notify::Config::default()).and_then(|mut watcher| {
+                    watcher.watch(&conf_dir, RecursiveMode::NonRecursive)?;
+                    return Ok(watcher);
+                });
+            let _watcher = match watcher {
+                Ok(watcher) => watcher,
+                Err(e) => {
+                    log::warn!("Failed to watch {:?}: {e:?}", conf_dir);
+                    return;
+                }
+            };
+            log::debug!("Watching {:?}", path);
+            match read_sync(Some(&conf_dir)) {
+                Ok(list) => *devices.lock().unwrap() = list.devices,
+                Err(e) => log::warn!("Failed to read devices: {e:?}"),
+            }
+            while current_generation.load(Ordering::SeqCst) == generation {
+                match rx.recv_timeout(GENERATION_CHECK) {
+                    Ok(Ok(event))
+                        if event
+                            .paths
+                            .iter()
+                            .any(|p| p.file_name() == path.file_name()) => {}
+                    Ok(Ok(_)) | Err(RecvTimeoutError::Timeout) => continue,
+                    Ok(Err(e)) => {
+                        log::warn!("Failed to watch {:?}: {e:?}", path);
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+                // Waits for the writes to settle
+                while rx.recv_timeout(DEBOUNCE).is_ok() {}
+                let lock = match lock_sync(Some(&conf_dir)) {
+                    Ok(lock) => lock,
+                    Err(e) => {
+                        log::warn!("Failed to lock devices: {e:?}");
+                        continue;
+                    }
+                };
+                let list = match read_sync(Some(&conf_dir)) {
+                    Ok(list) => list,
+                    Err(e) => {
+                        log::warn!("Failed to read changed devices: {e:?}");
+                        continue;
+                    }
+                };
+                let old = std::mem::replace(&mut *devices.lock().unwrap(), list.devices.clone());
+                drop(lock);
+                report(&callback, &old, &list.devices);
+            }
+            log::debug!("Stopped watching {:?}", path);
+        });
+    }
+}
+
+/// Tells the callback how the device list changed, if it did.
+pub(super) fn report(callback: &DevicesCallbackSlot, old: &[Device], new: &[Device]) {
+    let diff = DeviceListDiff::new(old, new);
+    if diff.is_empty() {
+        return;
+    }
+    log::info!("Devices changed: {:?}", diff);
+    if let Some(callback) = callback.lock().unwrap().as_ref() {
+        callback.changed(&diff);
+    }
+}
+
+impl DeviceListDiff {
+    fn new(old: &[Device], new: &[Device]) -> DeviceListDiff {
+        let mut diff = DeviceListDiff::default();
+        for device in new {
+            match old.iter().find(|d| d.name == device.name) {
+                None => diff.added.push(device.clone()),
+                Some(prev) => {
+                    if serde_json::to_value(prev).ok() != serde_json::to_value(device).ok() {
+                        diff.modified.push(device.clone());
+                    }
+                    let prev_chain = prev.jump_chain_in(old).unwrap_or_default();
+                    let chain = device.jump_chain_in(new).unwrap_or_default();
+                    if prev.connection_fingerprint(&prev_chain)
+                        != device.connection_fingerprint(&chain)
+                    {
+                        diff.reconnect.push(device.name.clone());
+                    }
+                }
+            }
+        }
+        for device in old {
+            if !new.iter().any(|d| d.name == device.name) {
+                diff.removed.push(device.name.clone());
+            }
+        }
+        return diff;
+    }
+
+    fn is_empty(&self) -> bool {
+        return self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty();
+    }
+}
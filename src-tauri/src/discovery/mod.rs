@@ -0,0 +1,30 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use serde::Serialize;
+
+pub(crate) mod ssdp;
+
+/// Sends SSDP M-SEARCH requests, and collects replies from webOS TVs.
+///
+/// `target` is the SSDP multicast group by default, but can be any UDP responder.
+pub struct SsdpDiscovery {
+    target: SocketAddr,
+    timeout: Duration,
+    /// Ports of the Developer Mode key server and SSH server, probed on each TV found
+    ports: (u16, u16),
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct DiscoveredDevice {
+    pub address: String,
+    pub name: Option<String>,
+    pub model: Option<String>,
+    pub server: Option<String>,
+    pub usn: Option<String>,
+    /// Port 9991, serving the key for the Developer Mode app
+    #[serde(rename = "keyServer")]
+    pub key_server: bool,
+    /// Port 9922, the SSH server of Developer Mode
+    pub ssh: bool,
+}
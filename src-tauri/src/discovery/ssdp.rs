@@ -0,0 +1,347 @@
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use regex::Regex;
+use tauri::{AppHandle, Runtime};
+use tokio::net::TcpStream;
+use tokio::runtime::Handle;
+
+use crate::discovery::{DiscoveredDevice, SsdpDiscovery};
+use crate::error::Error;
+use crate::event_channel::{EventChannel, EventHandler};
+
+const SSDP_MULTICAST: &str = "239.255.255.250:1900";
+const SEARCH_TARGETS: [&str; 2] = [
+    "urn:lge-com:service:webos-second-screen:1",
+    "urn:dial-multiscreen-org:service:dial:1",
+];
+const SEARCH_INTERVAL: Duration = Duration::from_secs(2);
+const PROBE_TIMEOUT: Duration = Duration::from_millis(1500);
+
+pub(crate) async fn exec<R: Runtime>(
+    app: AppHandle<R>,
+    target: Option<String>,
+    timeout: Option<u64>,
+) -> Result<String, Error> {
+    let target = target
+        .as_deref()
+        .unwrap_or(SSDP_MULTICAST)
+        .parse::<SocketAddr>()
+        .map_err(|_| Error::io(ErrorKind::InvalidInput))?;
+    let discovery = SsdpDiscovery::new(target, Duration::from_secs(timeout.unwrap_or(10)));
+    let channel = EventChannel::new(app, "discovery");
+    channel.listen(DiscoveryChannelHandler {
+        started_lock: Arc::new((Mutex::new(false), Condvar::new())),
+        closed_lock: Mutex::new(false),
+    });
+    let token = channel.token();
+    let runtime = Handle::current();
+    tokio::task::spawn_blocking(move || {
+        if let Some(h) = channel.handler.lock().unwrap().as_ref() {
+            h.wait();
+        }
+        let cancelled = || {
+            return channel
+                .handler
+                .lock()
+                .unwrap()
+                .as_ref()
+                .map_or(false, |h| h.closed());
+        };
+        match discovery.run(&runtime, |device| channel.rx(device), cancelled) {
+            Ok(_) => channel.closed(None::<String>),
+            Err(e) => channel.closed(e),
+        }
+    });
+    return Ok(token);
+}
+
+impl SsdpDiscovery {
+    pub fn new(target: SocketAddr, timeout: Duration) -> SsdpDiscovery {
+        return SsdpDiscovery {
+            target,
+            timeout,
+            ports: (9991, 9922),
+        };
+    }
+
+    /// Searches until `timeout` elapses or `cancelled` returns true, reporting each TV once.
+    ///
+    /// Each TV is described by a task on `runtime`, so slow ones don't hold up the search. Those
+    /// still running when the search ends are waited for.
+    pub fn run<F, C>(&self, runtime: &Handle, found: F, cancelled: C) -> Result<(), Error>
+    where
+        F: Fn(DiscoveredDevice),
+        C: Fn() -> bool,
+    {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_read_timeout(Some(Duration::from_millis(200)))?;
+        socket.set_multicast_ttl_v4(2)?;
+        let (tx, rx) = mpsc::channel::<DiscoveredDevice>();
+        let started = Instant::now();
+        let mut last_search: Option<Instant> = None;
+        let mut seen = HashSet::<IpAddr>::new();
+        let mut buf = [0u8; 2048];
+        while started.elapsed() < self.timeout && !cancelled() {
+            rx.try_iter().for_each(&found);
+            if last_search.map_or(true, |t| t.elapsed() >= SEARCH_INTERVAL) {
+                for st in SEARCH_TARGETS {
+                    socket.send_to(m_search(st).as_bytes(), self.target)?;
+                }
+                last_search = Some(Instant::now());
+            }
+            let (len, from) = match socket.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let Some(reply) = SsdpReply::parse(&buf[..len]) else {
+                continue;
+            };
+            let ip = from.ip();
+            if !reply.is_webos() || !seen.insert(ip) {
+                continue;
+            }
+            log::info!("Discovered {} via SSDP: {:?}", ip, reply.server);
+            let tx = tx.clone();
+            let ports = self.ports;
+            runtime.spawn(async move {
+                tx.send(describe(ip, reply, ports).await).unwrap_or(());
+            });
+        }
+        // Ends once every task has sent its device, or was dropped along with the runtime
+        drop(tx);
+        rx.iter().for_each(&found);
+        return Ok(());
+    }
+}
+
+struct SsdpReply {
+    location: Option<String>,
+    server: Option<String>,
+    st: Option<String>,
+    usn: Option<String>,
+}
+
+impl SsdpReply {
+    fn parse(data: &[u8]) -> Option<SsdpReply> {
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut resp = httparse::Response::new(&mut headers);
+        resp.parse(data).ok()?;
+        if resp.code != Some(200) {
+            return None;
+        }
+        let header = |name: &str| {
+            resp.headers
+                .iter()
+                .find(|h| h.name.eq_ignore_ascii_case(name))
+                .map(|h| String::from_utf8_lossy(h.value).trim().to_string())
+        };
+        return Some(SsdpReply {
+            location: header("LOCATION"),
+            server: header("SERVER"),
+            st: header("ST"),
+            usn: header("USN"),
+        });
+    }
+
+    fn is_webos(&self) -> bool {
+        let lge = self.st.as_ref().map_or(false, |st| st.contains("lge-com"));
+        let webos = self
+            .server
+            .as_ref()
+            .map_or(false, |s| s.to_ascii_lowercase().contains("webos"));
+        return lge || webos;
+    }
+}
+
+/// Reads the UPnP description of the TV, and checks for Developer Mode ports.
+///
+/// The description is only read from the TV itself, a reply can't point it anywhere else.
+async fn describe(ip: IpAddr, reply: SsdpReply, ports: (u16, u16)) -> DiscoveredDevice {
+    let mut name: Option<String> = None;
+    let mut model: Option<String> = None;
+    let location = reply.location.as_deref().filter(|location| {
+        let same_host = is_host(location, ip);
+        if !same_host {
+            log::warn!("Ignoring description of {ip} at {location}");
+        }
+        return same_host;
+    });
+    if let Some(location) = location {
+        match fetch_description(location).await {
+            Ok(xml) => {
+                name = xml_element(&xml, "friendlyName");
+                model = xml_element(&xml, "modelName");
+            }
+            Err(e) => log::warn!("Failed to read description of {ip}: {e:?}"),
+        }
+    }
+    let (key_server, ssh) = tokio::join!(probe(ip, ports.0), probe(ip, ports.1));
+    return DiscoveredDevice {
+        address: ip.to_string(),
+        name,
+        model,
+        server: reply.server,
+        usn: reply.usn,
+        key_server,
+        ssh,
+    };
+}
+
+async fn fetch_description(location: &str) -> Result<String, Error> {
+    let client = reqwest::Client::builder().timeout(PROBE_TIMEOUT).build()?;
+    let resp = client.get(location).send().await?.error_for_status()?;
+    return Ok(resp.text().await?);
+}
+
+/// Whether `url` points at `ip`.
+fn is_host(url: &str, ip: IpAddr) -> bool {
+    return reqwest::Url::parse(url)
+        .ok()
+        .and_then(|url| {
+            url.host_str().map(|host| {
+                host.trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .parse::<IpAddr>()
+            })
+        })
+        .map_or(false, |host| host.ok() == Some(ip));
+}
+
+fn xml_element(xml: &str, name: &str) -> Option<String> {
+    let regex = Regex::new(&format!("<{name}>([^<]*)</{name}>")).unwrap();
+    return regex
+        .captures(xml)
+        .and_then(|c| c.get(1))
+        .map(|m| m.as_str().trim().to_string());
+}
+
+async fn probe(ip: IpAddr, port: u16) -> bool {
+    let connect = TcpStream::connect(SocketAddr::new(ip, port));
+    let result = tokio::time::timeout(PROBE_TIMEOUT, connect).await;
+    return matches!(result, Ok(Ok(_)));
+}
+
+fn m_search(st: &str) -> String {
+    return format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {SSDP_MULTICAST}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: {st}\r\n\r\n"
+    );
+}
+
+struct DiscoveryChannelHandler {
+    started_lock: Arc<(Mutex<bool>, Condvar)>,
+    closed_lock: Mutex<bool>,
+}
+
+impl EventHandler for DiscoveryChannelHandler {
+    fn tx(&self, _payload: Option<&str>) {
+        let (lock, cvar) = &*self.started_lock;
+        *lock.lock().unwrap() = true;
+        cvar.notify_one();
+    }
+
+    fn close(&self, _payload: Option<&str>) {
+        *self.closed_lock.lock().unwrap() = true;
+        log::debug!("Discovery requested to stop");
+    }
+}
+
+impl DiscoveryChannelHandler {
+    fn wait(&self) {
+        let (lock, cvar) = &*self.started_lock;
+        let mut started = lock.lock().unwrap();
+        while !*started {
+            started = cvar.wait(started).unwrap();
+        }
+    }
+
+    fn closed(&self) -> bool {
+        return self.closed_lock.lock().unwrap().clone();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, TcpListener, UdpSocket};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use crate::discovery::ssdp::{is_host, SsdpReply};
+    use crate::discovery::SsdpDiscovery;
+
+    const REPLY: &str = "HTTP/1.1 200 OK\r\nST: urn:lge-com:service:webos-second-screen:1\r\nSERVER: WebOS/4.1.0 UPnP/1.0\r\nUSN: uuid:test\r\nLOCATION: http://192.0.2.1:1234/description.xml\r\n\r\n";
+
+    #[test]
+    fn parse_reply() {
+        let reply = SsdpReply::parse(REPLY.as_bytes()).unwrap();
+        assert!(reply.is_webos());
+        assert_eq!(reply.usn.as_deref(), Some("uuid:test"));
+        assert!(SsdpReply::parse(b"HTTP/1.1 404 Not Found\r\n\r\n").is_none());
+        let other = SsdpReply::parse(b"HTTP/1.1 200 OK\r\nSERVER: Linux UPnP/1.0\r\n\r\n").unwrap();
+        assert!(!other.is_webos());
+    }
+
+    #[test]
+    fn description_host() {
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        assert!(is_host("http://192.0.2.1:1234/description.xml", ip));
+        assert!(!is_host("http://192.0.2.2:1234/description.xml", ip));
+        assert!(!is_host("http://example.com/description.xml", ip));
+        assert!(!is_host("not a url", ip));
+        assert!(is_host("http://[::1]/", "::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn discover_local_responder() {
+        let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+        responder
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let target = responder.local_addr().unwrap();
+        let responder = std::thread::spawn(move || {
+            let mut buf = [0u8; 2048];
+            let (len, from) = responder.recv_from(&mut buf).unwrap();
+            assert!(buf[..len].starts_with(b"M-SEARCH * HTTP/1.1\r\n"));
+            responder.send_to(REPLY.as_bytes(), from).unwrap();
+            responder.send_to(REPLY.as_bytes(), from).unwrap();
+        });
+        // One port listening, and one that was just closed
+        let key_server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let ssh_port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut discovery = SsdpDiscovery::new(target, Duration::from_secs(1));
+        discovery.ports = (key_server.local_addr().unwrap().port(), ssh_port);
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let found = Mutex::new(Vec::new());
+        discovery
+            .run(
+                runtime.handle(),
+                |device| found.lock().unwrap().push(device),
+                || false,
+            )
+            .unwrap();
+        responder.join().unwrap();
+
+        let found = found.into_inner().unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].address, "127.0.0.1");
+        assert_eq!(found[0].usn.as_deref(), Some("uuid:test"));
+        assert_eq!(found[0].name, None);
+        assert!(found[0].key_server);
+        assert!(!found[0].ssh);
+    }
+}
@@ -13,15 +13,34 @@ use serde::{Serialize, Serializer};
 pub enum Error {
     Authorization {
         message: String,
+        /// Identities offered to the host, when authenticating through ssh-agent
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        identities: Vec<String>,
+        /// Methods the host accepts, as listed after trying `none`
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        offered: Vec<String>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        attempts: Vec<AuthAttempt>,
     },
     BadPassphrase,
     BadPrivateKey,
+    Cancelled,
+    /// Removing a directory that still has entries, without `recursive`
+    DirectoryNotEmpty {
+        message: String,
+    },
     Disconnected,
     ExitStatus {
         message: String,
         exit_code: i32,
         stderr: Vec<u8>,
     },
+    #[serde(rename_all = "camelCase")]
+    HostKeyChanged {
+        name: String,
+        old_fingerprint: String,
+        new_fingerprint: String,
+    },
     IO {
         #[serde(serialize_with = "as_debug_string")]
         code: ErrorKind,
@@ -32,8 +51,24 @@ pub enum Error {
     },
     PassphraseRequired,
     NotFound,
+    /// The connection kept dropping, see [crate::session_manager::RetryPolicy]
+    RetriesExhausted {
+        message: String,
+        attempts: u32,
+        /// Error of the last attempt
+        #[serde(rename = "lastError")]
+        last_error: Box<Error>,
+    },
     Timeout,
     Unsupported,
+    VaultLocked,
+}
+
+/// Outcome of one method in the authentication chain.
+#[derive(Debug, Serialize, Clone)]
+pub struct AuthAttempt {
+    pub method: String,
+    pub message: String,
 }
 
 impl Error {
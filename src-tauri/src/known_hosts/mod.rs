@@ -0,0 +1,21 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+mod store;
+
+pub(crate) use store::fingerprint;
+
+/// Host key fingerprints pinned on first connection, keyed by device name.
+pub struct KnownHosts {
+    path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HostKey {
+    pub host: String,
+    pub port: u16,
+    pub fingerprint: String,
+    #[serde(rename = "pinnedAt")]
+    pub pinned_at: u64,
+}
@@ -0,0 +1,178 @@
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, remove_file, rename, File};
+use std::io::{BufReader, ErrorKind, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use libssh_rs::{PublicKeyHashType, Session};
+use uuid::Uuid;
+
+use crate::device_manager::Device;
+use crate::error::Error;
+use crate::known_hosts::{HostKey, KnownHosts};
+
+static STORE_LOCK: Mutex<()> = Mutex::new(());
+
+impl KnownHosts {
+    pub fn new(conf_dir: &Path) -> KnownHosts {
+        return KnownHosts {
+            path: conf_dir.join("devman-known-hosts.json"),
+        };
+    }
+
+    pub fn get(&self, name: &str) -> Result<Option<HostKey>, Error> {
+        let _guard = STORE_LOCK.lock().unwrap();
+        return Ok(self.read()?.remove(name));
+    }
+
+    /// Checks the server key of a connected session against the pinned one.
+    ///
+    /// The key is pinned if the device has never been seen. A device whose host or port has been
+    /// changed keeps its pinned key, which is moved to the new address only if it matches.
+    pub fn verify(&self, device: &Device, session: &Session) -> Result<(), Error> {
+        let fingerprint = fingerprint(session)?;
+        let _guard = STORE_LOCK.lock().unwrap();
+        let mut entries = self.read()?;
+        if let Some(pinned) = entries.get(&device.name) {
+            if pinned.fingerprint != fingerprint {
+                log::warn!(
+                    "Host key of {} ({}:{}) changed from {} to {}",
+                    device.name,
+                    device.host,
+                    device.port,
+                    pinned.fingerprint,
+                    fingerprint
+                );
+                return Err(Error::HostKeyChanged {
+                    name: device.name.clone(),
+                    old_fingerprint: pinned.fingerprint.clone(),
+                    new_fingerprint: fingerprint,
+                });
+            }
+            if pinned.host == device.host && pinned.port == device.port {
+                return Ok(());
+            }
+            log::info!(
+                "Moving host key {} of {} to {}:{}",
+                fingerprint,
+                device.name,
+                device.host,
+                device.port
+            );
+        } else {
+            log::info!("Pinning host key {} for {}", fingerprint, device.name);
+        }
+        entries.insert(device.name.clone(), HostKey::new(device, fingerprint));
+        return self.write(&entries);
+    }
+
+    /// Pins the server key of a connected session, replacing any existing entry.
+    ///
+    /// Fails with [Error::HostKeyChanged] if the session doesn't present the key with the
+    /// `expected` fingerprint, the one the user was asked to accept.
+    pub fn accept(
+        &self,
+        device: &Device,
+        session: &Session,
+        expected: &str,
+    ) -> Result<HostKey, Error> {
+        let key = HostKey::new(device, fingerprint(session)?);
+        if key.fingerprint != expected {
+            log::warn!(
+                "Host key of {} is {}, not {} as accepted",
+                device.name,
+                key.fingerprint,
+                expected
+            );
+            return Err(Error::HostKeyChanged {
+                name: device.name.clone(),
+                old_fingerprint: String::from(expected),
+                new_fingerprint: key.fingerprint,
+            });
+        }
+        let _guard = STORE_LOCK.lock().unwrap();
+        let mut entries = self.read()?;
+        entries.insert(device.name.clone(), key.clone());
+        self.write(&entries)?;
+        return Ok(key);
+    }
+
+    /// Moves the pinned key of a renamed device to its new name.
+    pub fn rename(&self, name: &str, new_name: &str) -> Result<(), Error> {
+        let _guard = STORE_LOCK.lock().unwrap();
+        let mut entries = self.read()?;
+        if let Some(key) = entries.remove(name) {
+            entries.insert(String::from(new_name), key);
+            self.write(&entries)?;
+        }
+        return Ok(());
+    }
+
+    pub fn forget(&self, name: &str) -> Result<Option<HostKey>, Error> {
+        let _guard = STORE_LOCK.lock().unwrap();
+        let mut entries = self.read()?;
+        let removed = entries.remove(name);
+        if removed.is_some() {
+            self.write(&entries)?;
+        }
+        return Ok(removed);
+    }
+
+    fn read(&self) -> Result<BTreeMap<String, HostKey>, Error> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) => {
+                return match e.kind() {
+                    ErrorKind::NotFound => Ok(BTreeMap::new()),
+                    _ => Err(e.into()),
+                };
+            }
+        };
+        return Ok(serde_json::from_reader(BufReader::new(file))?);
+    }
+
+    /// Writes to a temporary file first, so a failed write never leaves a truncated store.
+    fn write(&self, entries: &BTreeMap<String, HostKey>) -> Result<(), Error> {
+        let parent = self.path.parent().ok_or_else(|| Error::bad_config())?;
+        create_dir_all(parent)?;
+        let temp_path = parent.join(format!(".devman-known-hosts.json.{}.tmp", Uuid::new_v4()));
+        let data = serde_json::to_vec_pretty(entries)?;
+        let result =
+            write_temp(&temp_path, &data).and_then(|_| Ok(rename(&temp_path, &self.path)?));
+        if result.is_err() {
+            remove_file(&temp_path).unwrap_or(());
+        }
+        return result;
+    }
+}
+
+impl HostKey {
+    fn new(device: &Device, fingerprint: String) -> HostKey {
+        return HostKey {
+            host: device.host.clone(),
+            port: device.port,
+            fingerprint,
+            pinned_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        };
+    }
+}
+
+fn write_temp(path: &Path, data: &[u8]) -> Result<(), Error> {
+    let mut file = File::create(path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    return Ok(());
+}
+
+/// Formats the server key hash the same way as OpenSSH does, e.g. `SHA256:AAAA...`.
+pub(crate) fn fingerprint(session: &Session) -> Result<String, Error> {
+    let hash = session
+        .get_server_public_key()?
+        .get_public_key_hash(PublicKeyHashType::Sha256)?;
+    let encoded = openssl::base64::encode_block(&hash);
+    return Ok(format!("SHA256:{}", encoded.trim_end_matches('=')));
+}
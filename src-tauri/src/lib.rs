@@ -3,6 +3,7 @@ extern crate core;
 #[cfg(target_family = "windows")]
 use std::env;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use log::LevelFilter;
 #[cfg(feature = "mobile")]
@@ -11,27 +12,34 @@ use tauri::api::path::home_dir;
 use tauri::{AppHandle, Manager, RunEvent, Runtime};
 
 use crate::app_dirs::{GetConfDir, GetSshDir, SetConfDir, SetSshDir};
+use crate::conn_pool::InteractiveAuth;
 use crate::device_manager::DeviceManager;
 use crate::session_manager::SessionManager;
 use crate::shell_manager::ShellManager;
 use crate::spawn_manager::SpawnManager;
+use crate::vault::SecretVault;
 
 mod app_dirs;
 mod conn_pool;
 mod device_manager;
+mod discovery;
 mod error;
 mod event_channel;
+mod known_hosts;
 mod plugins;
 mod remote_files;
 mod session_manager;
 mod shell_manager;
 mod spawn_manager;
+mod vault;
 
 //#[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     env_logger::builder()
         .filter_level(LevelFilter::Debug)
         .init();
+    let vault = Arc::new(SecretVault::default());
+    let interactive = Arc::new(InteractiveAuth::default());
     let mut builder = tauri::Builder::default();
     #[cfg(feature = "single-instance")]
     {
@@ -49,10 +57,12 @@ pub fn run() {
         .plugin(plugins::file::plugin("remote-file"))
         .plugin(plugins::devmode::plugin("dev-mode"))
         .plugin(plugins::local_file::plugin("local-file"))
-        .manage(DeviceManager::default())
-        .manage(SessionManager::default())
+        .manage(DeviceManager::new(vault.clone()))
+        .manage(SessionManager::new(vault.clone(), interactive.clone()))
         .manage(SpawnManager::default())
-        .manage(ShellManager::default())
+        .manage(ShellManager::new(vault.clone(), interactive.clone()))
+        .manage(vault)
+        .manage(interactive)
         .on_page_load(|wnd, _payload| {
             let spawns = wnd.state::<SpawnManager>();
             spawns.clear();
@@ -68,6 +78,9 @@ pub fn run() {
                     }
                     if let Some(conf_dir) = app.get_conf_dir() {
                         app.state::<DeviceManager>().set_conf_dir(conf_dir.clone());
+                        app.state::<SessionManager>().set_conf_dir(conf_dir.clone());
+                        app.state::<ShellManager>().set_conf_dir(conf_dir.clone());
+                        app.state::<Arc<SecretVault>>().set_conf_dir(conf_dir.clone());
                     }
                 }
                 _ => {}
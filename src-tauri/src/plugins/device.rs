@@ -1,16 +1,42 @@
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::Serialize;
 use tauri::{
     plugin::{Builder, TauriPlugin},
     Runtime,
 };
-use tauri::{AppHandle, State};
+use tauri::{AppHandle, Manager, State};
 
-use crate::device_manager::{Device, DeviceManager};
+use crate::app_dirs::{GetConfDir, GetSshDir};
+use crate::conn_pool::diagnose;
+use crate::conn_pool::{
+    AuthPromptRequest, AuthPrompter, ConnectionState, ConnectionStateCallback, DeviceConnection,
+    DiagnosticReport, InteractiveAuth, JumpTunnel, PoolInfo,
+};
+use crate::device_manager::{
+    Device, DeviceBackup, DeviceList, DeviceListDiff, DeviceManager, DevicesCallback,
+    RestoredDeviceList,
+};
+use crate::discovery::ssdp;
 use crate::error::Error;
-use crate::app_dirs::GetSshDir;
+use crate::event_channel::{EventChannel, EventHandler};
+use crate::known_hosts::{HostKey, KnownHosts};
+use crate::session_manager::{RetryAttempt, RetryCallback, RetryOptions, SessionManager};
+use crate::vault::{SecretVault, VaultStatus};
+
+/// Less than the time the connection pool waits for a new connection.
+const AUTH_PROMPT_TIMEOUT: Duration = Duration::from_secs(25);
 
 #[tauri::command]
-async fn list(manager: State<'_, DeviceManager>) -> Result<Vec<Device>, Error> {
-    return manager.list().await;
+async fn list(
+    manager: State<'_, DeviceManager>,
+    sessions: State<'_, SessionManager>,
+) -> Result<DeviceList, Error> {
+    let list = manager.list().await?;
+    sessions.retain_devices(&list.devices);
+    return Ok(list);
 }
 
 #[tauri::command]
@@ -26,13 +52,97 @@ async fn add(manager: State<'_, DeviceManager>, device: Device) -> Result<Device
     return manager.add(&device).await;
 }
 
+#[tauri::command]
+async fn update(
+    manager: State<'_, DeviceManager>,
+    sessions: State<'_, SessionManager>,
+    name: String,
+    device: Device,
+) -> Result<Device, Error> {
+    let device = manager.update(&name, &device).await?;
+    sessions.evict(&name);
+    return Ok(device);
+}
+
 #[tauri::command]
 async fn remove(
     manager: State<'_, DeviceManager>,
+    sessions: State<'_, SessionManager>,
     name: String,
     remove_key: bool,
 ) -> Result<(), Error> {
-    return manager.remove(&name, remove_key).await;
+    manager.remove(&name, remove_key).await?;
+    sessions.evict(&name);
+    return Ok(());
+}
+
+#[tauri::command]
+async fn disconnect(sessions: State<'_, SessionManager>, device: Device) -> Result<(), Error> {
+    sessions.evict(&device.name);
+    return Ok(());
+}
+
+#[tauri::command]
+async fn pools(sessions: State<'_, SessionManager>) -> Result<Vec<PoolInfo>, Error> {
+    return Ok(sessions.pools());
+}
+
+#[tauri::command]
+async fn pool_close(sessions: State<'_, SessionManager>, name: String) -> Result<(), Error> {
+    return sessions.close_pool(&name);
+}
+
+#[tauri::command]
+async fn connection_close<R: Runtime>(
+    app: AppHandle<R>,
+    name: String,
+    id: String,
+) -> Result<(), Error> {
+    // Closing an idle connection validates the other idle ones too, which may block for a while
+    return tokio::task::spawn_blocking(move || {
+        app.state::<SessionManager>().close_connection(&name, &id)
+    })
+    .await
+    .expect("critical failure in device::connection_close task");
+}
+
+#[tauri::command]
+async fn retry_policy(sessions: State<'_, SessionManager>) -> Result<RetryOptions, Error> {
+    return Ok(RetryOptions::from(&sessions.retry_policy()));
+}
+
+/// Changes how operations are retried when their connection drops, until the app restarts.
+#[tauri::command]
+async fn retry_policy_set(
+    sessions: State<'_, SessionManager>,
+    policy: RetryOptions,
+) -> Result<(), Error> {
+    log::info!("Retry policy set to {:?}", policy);
+    sessions.set_retry_policy(policy.into());
+    return Ok(());
+}
+
+#[tauri::command]
+async fn backup_list(manager: State<'_, DeviceManager>) -> Result<Vec<DeviceBackup>, Error> {
+    return manager.backups().await;
+}
+
+#[tauri::command]
+async fn backup_restore(
+    manager: State<'_, DeviceManager>,
+    name: String,
+) -> Result<RestoredDeviceList, Error> {
+    return manager.restore(&name).await;
+}
+
+/// Searches for TVs on the LAN, returning a channel token that streams each TV found.
+#[tauri::command]
+async fn discover<R: Runtime>(
+    app: AppHandle<R>,
+    target: Option<String>,
+    timeout: Option<u64>,
+) -> Result<String, Error> {
+    return ssdp::exec(app, target, timeout).await;
 }
 
 #[tauri::command]
@@ -65,6 +175,117 @@ async fn privkey_read<R: Runtime>(app: AppHandle<R>, device: Device) -> Result<S
         .content(app.get_ssh_dir().as_deref())?);
 }
 
+/// Connects to the device stage by stage, reporting the outcome and timing of each one.
+#[tauri::command]
+async fn diagnose<R: Runtime>(
+    app: AppHandle<R>,
+    device: Device,
+) -> Result<DiagnosticReport, Error> {
+    let ssh_dir = app.get_ssh_dir();
+    let conf_dir = app.get_conf_dir();
+    let vault = app.state::<Arc<SecretVault>>().inner().clone();
+    let interactive = app.state::<Arc<InteractiveAuth>>().inner().clone();
+    return Ok(tokio::task::spawn_blocking(move || {
+        diagnose::run(
+            &device,
+            ssh_dir.as_deref(),
+            conf_dir.as_deref(),
+            &vault,
+            &interactive,
+        )
+    })
+    .await
+    .expect("critical failure in device::diagnose task"));
+}
+
+#[tauri::command]
+async fn host_key_read<R: Runtime>(
+    app: AppHandle<R>,
+    name: String,
+) -> Result<Option<HostKey>, Error> {
+    let conf_dir = app.ensure_conf_dir()?;
+    return KnownHosts::new(&conf_dir).get(&name);
+}
+
+/// Pins the host key of the device, if it's still the one with `fingerprint`, which is the
+/// `newFingerprint` of the [Error::HostKeyChanged] the user accepted.
+#[tauri::command]
+async fn host_key_accept<R: Runtime>(
+    app: AppHandle<R>,
+    device: Device,
+    fingerprint: String,
+) -> Result<HostKey, Error> {
+    let conf_dir = app.ensure_conf_dir()?;
+    let ssh_dir = app.get_ssh_dir();
+    let vault = app.state::<Arc<SecretVault>>().inner().clone();
+    let interactive = app.state::<Arc<InteractiveAuth>>().inner().clone();
+    return tokio::task::spawn_blocking(move || {
+        let chain = device.jump_chain(Some(&conf_dir))?;
+        let mut tunnel = JumpTunnel::open(
+            &device,
+            &chain,
+            ssh_dir.as_deref(),
+            Some(&conf_dir),
+            &vault,
+            &interactive,
+        )?;
+        let session = DeviceConnection::connect_session(&device, tunnel.as_mut())?;
+        return KnownHosts::new(&conf_dir).accept(&device, &session, &fingerprint);
+    })
+    .await
+    .expect("critical failure in device::host_key_accept task");
+}
+
+#[tauri::command]
+async fn host_key_forget<R: Runtime>(
+    app: AppHandle<R>,
+    name: String,
+) -> Result<Option<HostKey>, Error> {
+    let conf_dir = app.ensure_conf_dir()?;
+    return KnownHosts::new(&conf_dir).forget(&name);
+}
+
+#[tauri::command]
+async fn vault_status(vault: State<'_, Arc<SecretVault>>) -> Result<VaultStatus, Error> {
+    return vault.status();
+}
+
+/// Creates the vault and moves plaintext secrets of existing devices into it.
+#[tauri::command]
+async fn vault_create(
+    manager: State<'_, DeviceManager>,
+    vault: State<'_, Arc<SecretVault>>,
+    password: Option<String>,
+) -> Result<usize, Error> {
+    let vault = vault.inner().clone();
+    tokio::task::spawn_blocking(move || vault.create(password.as_deref()))
+        .await
+        .expect("critical failure in device::vault_create task")?;
+    return manager.migrate_secrets().await;
+}
+
+#[tauri::command]
+async fn vault_unlock(
+    vault: State<'_, Arc<SecretVault>>,
+    password: Option<String>,
+) -> Result<(), Error> {
+    let vault = vault.inner().clone();
+    return tokio::task::spawn_blocking(move || vault.unlock(password.as_deref()))
+        .await
+        .expect("critical failure in device::vault_unlock task");
+}
+
+/// Locks the vault, and closes pooled connections that may have been opened with its secrets.
+#[tauri::command]
+async fn vault_lock(
+    vault: State<'_, Arc<SecretVault>>,
+    sessions: State<'_, SessionManager>,
+) -> Result<(), Error> {
+    vault.lock();
+    sessions.retain_devices(&[]);
+    return Ok(());
+}
+
 /// Initializes the plugin.
 pub fn plugin<R: Runtime>(name: &'static str) -> TauriPlugin<R> {
     Builder::new(name)
@@ -72,10 +293,137 @@ pub fn plugin<R: Runtime>(name: &'static str) -> TauriPlugin<R> {
             list,
             set_default,
             add,
+            update,
             remove,
+            disconnect,
+            pools,
+            pool_close,
+            connection_close,
+            retry_policy,
+            retry_policy_set,
+            backup_list,
+            backup_restore,
+            discover,
             novacom_getkey,
             localkey_verify,
             privkey_read,
+            diagnose,
+            host_key_read,
+            host_key_accept,
+            host_key_forget,
+            vault_status,
+            vault_create,
+            vault_unlock,
+            vault_lock,
         ])
+        .setup(|app| {
+            app.state::<DeviceManager>()
+                .set_callback(Box::new(PluginDevicesCb { app: app.clone() }));
+            app.state::<Arc<InteractiveAuth>>()
+                .set_prompter(Box::new(PluginAuthPrompter { app: app.clone() }));
+            app.state::<SessionManager>()
+                .set_callback(Box::new(PluginSessionsCb { app: app.clone() }));
+            app.state::<SessionManager>()
+                .set_retry_callback(Box::new(PluginSessionsCb { app: app.clone() }));
+            return Ok(());
+        })
         .build()
 }
+
+struct PluginDevicesCb<R: Runtime> {
+    app: AppHandle<R>,
+}
+
+impl<R: Runtime> DevicesCallback for PluginDevicesCb<R> {
+    fn changed(&self, diff: &DeviceListDiff) {
+        let sessions = self.app.state::<SessionManager>();
+        for name in &diff.removed {
+            sessions.evict(name);
+        }
+        for name in &diff.reconnect {
+            sessions.evict(name);
+        }
+        self.app.emit_all("devices-changed", diff).unwrap_or(());
+    }
+}
+
+struct PluginSessionsCb<R: Runtime> {
+    app: AppHandle<R>,
+}
+
+#[derive(Serialize, Clone)]
+struct ConnectionStateEvent<'a> {
+    device: &'a str,
+    state: ConnectionState,
+}
+
+impl<R: Runtime> ConnectionStateCallback for PluginSessionsCb<R> {
+    fn changed(&self, device: &str, state: ConnectionState) {
+        self.app
+            .emit_all("connection-state", ConnectionStateEvent { device, state })
+            .unwrap_or(());
+    }
+}
+
+impl<R: Runtime> RetryCallback for PluginSessionsCb<R> {
+    fn retrying(&self, attempt: &RetryAttempt) {
+        self.app.emit_all("connection-retry", attempt).unwrap_or(());
+    }
+}
+
+/// Relays keyboard-interactive prompts to the frontend through an [EventChannel].
+///
+/// The channel token is announced with an `auth-prompt` event. The frontend sends the answers
+/// over the channel, or closes it to cancel.
+struct PluginAuthPrompter<R: Runtime> {
+    app: AppHandle<R>,
+}
+
+#[derive(Serialize, Clone)]
+struct AuthPromptEvent<'a> {
+    token: String,
+    #[serde(flatten)]
+    request: &'a AuthPromptRequest,
+}
+
+impl<R: Runtime> AuthPrompter for PluginAuthPrompter<R> {
+    fn prompt(&self, request: &AuthPromptRequest) -> Result<Option<Vec<String>>, Error> {
+        let (sender, receiver) = channel::<Option<Vec<String>>>();
+        let channel = EventChannel::new(self.app.clone(), "auth-prompt");
+        channel.listen(AuthPromptChannelHandler {
+            sender: Mutex::new(sender),
+        });
+        let token = channel.token();
+        self.app
+            .emit_all("auth-prompt", AuthPromptEvent { token, request })
+            .map_err(|e| Error::new(format!("Failed to send prompt: {e:?}")))?;
+        return match receiver.recv_timeout(AUTH_PROMPT_TIMEOUT) {
+            Ok(answers) => {
+                channel.closed(None::<String>);
+                Ok(answers)
+            }
+            Err(_) => {
+                channel.closed(Error::Timeout);
+                Err(Error::Timeout)
+            }
+        };
+    }
+}
+
+struct AuthPromptChannelHandler {
+    sender: Mutex<Sender<Option<Vec<String>>>>,
+}
+
+impl EventHandler for AuthPromptChannelHandler {
+    fn tx(&self, payload: Option<&str>) {
+        let answers = payload.and_then(|p| serde_json::from_str::<Vec<String>>(p).ok());
+        if answers.is_none() {
+            log::warn!("Malformed answers for auth prompt");
+        }
+        self.sender.lock().unwrap().send(answers).unwrap_or(());
+    }
+
+    fn close(&self, _payload: Option<&str>) {
+        self.sender.lock().unwrap().send(None).unwrap_or(());
+    }
+}
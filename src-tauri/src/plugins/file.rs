@@ -1,17 +1,20 @@
 use std::env::temp_dir;
-use std::fs::File;
-use std::io::{copy, Read, Write};
-use std::path::Path;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 
 use flate2::read::GzDecoder;
+use libssh_rs::Sftp;
 use tauri::{AppHandle, Manager, Runtime};
 use tauri::plugin::{Builder, TauriPlugin};
 use uuid::Uuid;
 
-use crate::device_manager::Device;
+use crate::conn_pool::DeviceConnection;
+use crate::device_manager::{Device, DeviceFileTransfer};
 use crate::error::Error;
-use crate::remote_files::{FileItem, PermInfo};
-use crate::remote_files::serve;
+use crate::remote_files::{FileItem, SyncOptions, TransferStats, TreeOptions};
+use crate::remote_files::{ops, serve, stream};
+use crate::remote_files::transfer::Transfer;
+use crate::remote_files::transfer_channel::{self, TransferJob};
 use crate::session_manager::SessionManager;
 
 #[tauri::command]
@@ -24,21 +27,139 @@ async fn ls<R: Runtime>(
         return Err(Error::new("Absolute path required"));
     }
     log::info!("ls {}", path);
-    return tokio::task::spawn_blocking(move || {
-        let sessions = app.state::<SessionManager>();
-        return sessions.with_session(device, |session| {
-            let sftp = session.sftp()?;
-            let entries = sftp.read_dir(&path)?;
-            let user = session.user.as_ref();
-            return Ok(entries
-                .iter()
-                .filter(|entry| entry.name() != Some(".") && entry.name() != Some(".."))
-                .map(|entry| FileItem::new(entry, None, user.map(|u| PermInfo::from(entry, &u))))
-                .collect());
-        });
-    })
-    .await
-    .expect("critical failure in file::ls task");
+    return with_files(
+        app,
+        device,
+        move |conn, sftp| FileItem::list(sftp, &path, conn.user.as_ref()),
+        move |conn| stream::list(conn, &path),
+    )
+    .await;
+}
+
+/// Stats a single file, not following it if it's a symbolic link.
+#[tauri::command]
+async fn stat<R: Runtime>(
+    app: AppHandle<R>,
+    device: Device,
+    path: String,
+) -> Result<FileItem, Error> {
+    if !path.starts_with("/") {
+        return Err(Error::new("Absolute path required"));
+    }
+    return with_files(
+        app,
+        device,
+        move |conn, sftp| FileItem::stat(sftp, &path, conn.user.as_ref()),
+        move |conn| stream::stat(conn, &path),
+    )
+    .await;
+}
+
+#[tauri::command]
+async fn rm<R: Runtime>(
+    app: AppHandle<R>,
+    device: Device,
+    path: String,
+    recursive: Option<bool>,
+) -> Result<(), Error> {
+    log::info!("rm {} recursive={:?}", path, recursive);
+    let recursive = recursive.unwrap_or(false);
+    let stream_path = path.clone();
+    return with_files(
+        app,
+        device,
+        move |_, sftp| ops::remove(sftp, &path, recursive),
+        move |conn| stream::remove(conn, &stream_path, recursive),
+    )
+    .await;
+}
+
+#[tauri::command]
+async fn mkdir<R: Runtime>(
+    app: AppHandle<R>,
+    device: Device,
+    path: String,
+    mode: Option<u32>,
+    parents: Option<bool>,
+) -> Result<(), Error> {
+    let (mode, parents) = (mode.unwrap_or(0o755), parents.unwrap_or(false));
+    let stream_path = path.clone();
+    return with_files(
+        app,
+        device,
+        move |_, sftp| ops::make_dir(sftp, &path, mode, parents),
+        move |conn| stream::make_dir(conn, &stream_path, mode, parents),
+    )
+    .await;
+}
+
+#[tauri::command]
+async fn rename<R: Runtime>(
+    app: AppHandle<R>,
+    device: Device,
+    from: String,
+    to: String,
+) -> Result<(), Error> {
+    let (stream_from, stream_to) = (from.clone(), to.clone());
+    return with_files(
+        app,
+        device,
+        move |_, sftp| ops::rename(sftp, &from, &to),
+        move |conn| stream::rename(conn, &stream_from, &stream_to),
+    )
+    .await;
+}
+
+#[tauri::command]
+async fn chmod<R: Runtime>(
+    app: AppHandle<R>,
+    device: Device,
+    path: String,
+    mode: u32,
+) -> Result<(), Error> {
+    let stream_path = path.clone();
+    return with_files(
+        app,
+        device,
+        move |_, sftp| ops::chmod(sftp, &path, mode),
+        move |conn| stream::chmod(conn, &stream_path, mode),
+    )
+    .await;
+}
+
+#[tauri::command]
+async fn chown<R: Runtime>(
+    app: AppHandle<R>,
+    device: Device,
+    path: String,
+    uid: u32,
+    gid: u32,
+) -> Result<(), Error> {
+    let stream_path = path.clone();
+    return with_files(
+        app,
+        device,
+        move |_, sftp| ops::chown(sftp, &path, uid, gid),
+        move |conn| stream::chown(conn, &stream_path, uid, gid),
+    )
+    .await;
+}
+
+#[tauri::command]
+async fn symlink<R: Runtime>(
+    app: AppHandle<R>,
+    device: Device,
+    target: String,
+    path: String,
+) -> Result<(), Error> {
+    let (stream_target, stream_path) = (target.clone(), path.clone());
+    return with_files(
+        app,
+        device,
+        move |_, sftp| ops::symlink(sftp, &target, &path),
+        move |conn| stream::symlink(conn, &stream_target, &stream_path),
+    )
+    .await;
 }
 
 #[tauri::command]
@@ -48,27 +169,20 @@ async fn read<R: Runtime>(
     path: String,
     encoding: Option<String>,
 ) -> Result<Vec<u8>, Error> {
-    return tokio::task::spawn_blocking(move || {
-        let sessions = app.state::<SessionManager>();
-        return sessions.with_session(device, |session| {
-            let sftp = session.sftp()?;
-            let mut file = sftp.open(&path, 0 /*O_RDONLY*/, 0)?;
-            let mut buf = Vec::<u8>::new();
-            if let Some(encoding) = &encoding {
-                if encoding == "gzip" {
-                    let mut decoder = GzDecoder::new(&mut file);
-                    decoder.read_to_end(&mut buf)?;
-                } else {
-                    return Err(Error::new(format!("Unsupported encoding {}", encoding)));
-                }
-            } else {
-                file.read_to_end(&mut buf)?;
-            }
-            return Ok(buf);
-        });
-    })
-    .await
-    .expect("critical failure in file::read task");
+    let (stream_path, stream_encoding) = (path.clone(), encoding.clone());
+    return with_files(
+        app,
+        device,
+        move |_, sftp| {
+            let file = sftp.open(&path, 0 /*O_RDONLY*/, 0)?;
+            return decode(file, encoding.as_deref());
+        },
+        move |conn| {
+            let content = stream::read(conn, &stream_path)?;
+            return decode(content.as_slice(), stream_encoding.as_deref());
+        },
+    )
+    .await;
 }
 
 #[tauri::command]
@@ -78,65 +192,138 @@ async fn write<R: Runtime>(
     path: String,
     content: Vec<u8>,
 ) -> Result<(), Error> {
-    return tokio::task::spawn_blocking(move || {
-        let sessions = app.state::<SessionManager>();
-        return Ok(sessions.with_session(device, |session| {
-            let sftp = session.sftp()?;
+    let (stream_path, stream_content) = (path.clone(), content.clone());
+    return with_files(
+        app,
+        device,
+        move |_, sftp| {
             let mut file = sftp.open(
                 &path, 0o1101, /*O_WRONLY | O_CREAT | O_TRUNC on Linux*/
                 0o644,
             )?;
             file.write_all(&content)?;
             return Ok(());
-        })?);
-    })
-    .await
-    .expect("critical failure in file::write task");
+        },
+        move |conn| stream::write(conn, &stream_path, &stream_content),
+    )
+    .await;
 }
 
+/// Downloads a file, `window` segments of big files are transferred in parallel.
+///
+/// Returns the token of a channel reporting the progress, see [transfer_channel::exec].
 #[tauri::command]
 async fn get<R: Runtime>(
     app: AppHandle<R>,
     device: Device,
     path: String,
     target: String,
-) -> Result<(), Error> {
-    return tokio::task::spawn_blocking(move || {
-        let sessions = app.state::<SessionManager>();
-        return sessions.with_session(device, |session| {
-            let sftp = session.sftp()?;
-            let mut sfile = sftp.open(&path, 0, 0)?;
-            let mut file = File::create(target.clone())?;
-            copy(&mut sfile, &mut file)?;
-            return Ok(());
-        });
-    })
-    .await
-    .expect("critical failure in file::get task");
+    window: Option<usize>,
+) -> Result<String, Error> {
+    let target = PathBuf::from(target);
+    return transfer_channel::exec(app, device, TransferJob::Get { path, target }, window).await;
 }
 
+/// Uploads a file, `window` segments of big files are transferred in parallel.
+///
+/// Returns the token of a channel reporting the progress, see [transfer_channel::exec].
 #[tauri::command]
 async fn put<R: Runtime>(
     app: AppHandle<R>,
     device: Device,
     path: String,
     source: String,
-) -> Result<(), Error> {
+    window: Option<usize>,
+) -> Result<String, Error> {
+    let source = PathBuf::from(source);
+    return transfer_channel::exec(app, device, TransferJob::Put { source, path }, window).await;
+}
+
+/// Downloads a directory tree, see [DirTransfer](crate::remote_files::dir_transfer::DirTransfer).
+///
+/// Returns the token of a channel reporting the progress, see [transfer_channel::exec].
+#[tauri::command]
+async fn get_dir<R: Runtime>(
+    app: AppHandle<R>,
+    device: Device,
+    path: String,
+    target: String,
+    window: Option<usize>,
+    options: Option<TreeOptions>,
+) -> Result<String, Error> {
+    let job = TransferJob::GetDir {
+        path,
+        target: PathBuf::from(target),
+        options: options.unwrap_or_default(),
+    };
+    return transfer_channel::exec(app, device, job, window).await;
+}
+
+/// Uploads a directory tree, see [DirTransfer](crate::remote_files::dir_transfer::DirTransfer).
+///
+/// Returns the token of a channel reporting the progress, see [transfer_channel::exec].
+#[tauri::command]
+async fn put_dir<R: Runtime>(
+    app: AppHandle<R>,
+    device: Device,
+    path: String,
+    source: String,
+    window: Option<usize>,
+    options: Option<TreeOptions>,
+) -> Result<String, Error> {
+    let job = TransferJob::PutDir {
+        source: PathBuf::from(source),
+        path,
+        options: options.unwrap_or_default(),
+    };
+    return transfer_channel::exec(app, device, job, window).await;
+}
+
+/// Uploads the files of a local directory tree that differ from the remote one, see
+/// [DirTransfer::sync](crate::remote_files::dir_transfer::DirTransfer::sync).
+///
+/// Returns the token of a channel reporting the progress, see [transfer_channel::exec].
+#[tauri::command]
+async fn sync<R: Runtime>(
+    app: AppHandle<R>,
+    device: Device,
+    source: String,
+    path: String,
+    window: Option<usize>,
+    options: Option<SyncOptions>,
+) -> Result<String, Error> {
+    let job = TransferJob::Sync {
+        source: PathBuf::from(source),
+        path,
+        options: options.unwrap_or_default(),
+    };
+    return transfer_channel::exec(app, device, job, window).await;
+}
+
+/// Downloads a file one segment at a time, then `window` segments at a time, to compare them.
+#[tauri::command]
+async fn benchmark<R: Runtime>(
+    app: AppHandle<R>,
+    device: Device,
+    path: String,
+    window: Option<usize>,
+) -> Result<Vec<TransferStats>, Error> {
     return tokio::task::spawn_blocking(move || {
         let sessions = app.state::<SessionManager>();
-        return sessions.with_session(device, |session| {
-            let sftp = session.sftp()?;
-            let mut sfile =
-                sftp.open(&path, 0x0301 /*O_WRONLY | O_CREAT | O_TRUNC*/, 0o644)?;
-            let mut file = File::open(source.clone())?;
-            copy(&mut file, &mut sfile)?;
-            return Ok(());
-        });
+        let target = temp_dir().join(format!("webos-dev-bench-{}", Uuid::new_v4()));
+        let mut results = Vec::new();
+        for window in [Some(1), window] {
+            let result = Transfer::new(&sessions, device.clone(), window).download(&path, &target);
+            std::fs::remove_file(&target).unwrap_or(());
+            results.push(result?);
+        }
+        return Ok(results);
     })
     .await
-    .expect("critical failure in file::put task");
+    .expect("critical failure in file::benchmark task");
 }
 
+/// Downloads a file to a temporary location, which the channel reports once done.
 #[tauri::command]
 async fn get_temp<R: Runtime>(
     app: AppHandle<R>,
@@ -147,14 +334,8 @@ async fn get_temp<R: Runtime>(
     let extension = source
         .extension()
         .map_or(String::new(), |s| format!(".{}", s.to_string_lossy()));
-    let temp_path = temp_dir().join(format!("webos-dev-tmp-{}{}", Uuid::new_v4(), extension));
-    let target = String::from(
-        temp_path
-            .to_str()
-            .expect(&format!("Bad temp_path {:?}", temp_path)),
-    );
-    get(app, device, path, target.clone()).await?;
-    return Ok(target);
+    let target = temp_dir().join(format!("webos-dev-tmp-{}{}", Uuid::new_v4(), extension));
+    return transfer_channel::exec(app, device, TransferJob::Get { path, target }, None).await;
 }
 
 #[tauri::command]
@@ -166,10 +347,49 @@ async fn serve<R: Runtime>(
     return serve::exec(app, device, path).await;
 }
 
+/// Runs `sftp` with the SFTP session of a pooled connection, or `stream` with the connection if
+/// the device has files transferred over exec channels, off the async runtime.
+async fn with_files<R, T, S, E>(
+    app: AppHandle<R>,
+    device: Device,
+    sftp: S,
+    stream: E,
+) -> Result<T, Error>
+where
+    R: Runtime,
+    T: Send + 'static,
+    S: Fn(&DeviceConnection, &Sftp) -> Result<T, Error> + Send + 'static,
+    E: Fn(&DeviceConnection) -> Result<T, Error> + Send + 'static,
+{
+    return tokio::task::spawn_blocking(move || {
+        let sessions = app.state::<SessionManager>();
+        return sessions.with_session(device, |session| {
+            let conn: &DeviceConnection = session;
+            return match conn.files() {
+                DeviceFileTransfer::Stream => stream(conn),
+                DeviceFileTransfer::Sftp => conn.with_sftp(|s| sftp(conn, s)),
+            };
+        });
+    })
+    .await
+    .expect("critical failure in file task");
+}
+
+fn decode<Rd: Read>(mut reader: Rd, encoding: Option<&str>) -> Result<Vec<u8>, Error> {
+    let mut buf = Vec::<u8>::new();
+    match encoding {
+        Some("gzip") => GzDecoder::new(reader).read_to_end(&mut buf)?,
+        Some(encoding) => return Err(Error::new(format!("Unsupported encoding {}", encoding))),
+        None => reader.read_to_end(&mut buf)?,
+    };
+    return Ok(buf);
+}
+
 pub fn plugin<R: Runtime>(name: &'static str) -> TauriPlugin<R> {
     Builder::new(name)
         .invoke_handler(tauri::generate_handler![
-            ls, read, write, get, put, get_temp, serve
+            ls, stat, rm, mkdir, rename, chmod, chown, symlink, read, write, get, put, get_dir,
+            put_dir, sync, get_temp, serve, benchmark
         ])
         .build()
 }
@@ -0,0 +1,578 @@
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use libssh_rs::{FileType, Metadata, SetAttributes, Sftp};
+use path_slash::PathBufExt;
+
+use crate::device_manager::{Device, DeviceFileTransfer};
+use crate::error::Error;
+use crate::remote_files::glob::PathFilter;
+use crate::remote_files::stream::TreeItem;
+use crate::remote_files::transfer::Transfer;
+use crate::remote_files::{DirTransferResult, EntryResult, TreeOptions};
+use crate::session_manager::SessionManager;
+
+mod stream;
+mod sync;
+
+/// Deeper than this is most likely a symbolic link loop.
+const MAX_DEPTH: usize = 64;
+
+/// Copies directory trees over SFTP, one file at a time with [Transfer]. Devices without SFTP
+/// have the tree listed with `find`, and its files sent in one `tar` archive instead.
+///
+/// The whole tree is listed first, so progress is reported against its total size. Entries that
+/// fail don't stop the others, unless the connection is gone.
+pub(crate) struct DirTransfer<'a> {
+    sessions: &'a SessionManager,
+    device: Device,
+    window: Option<usize>,
+    follow_links: bool,
+    filter: PathFilter,
+    progress: Option<&'a (dyn Fn(u64, u64) + Sync)>,
+    cancelled: Option<&'a AtomicBool>,
+}
+
+/// File or directory to copy, with its path relative to the root of the tree. The root itself is
+/// `.`.
+struct Entry {
+    path: String,
+    kind: EntryKind,
+    size: u64,
+    mode: u32,
+    mtime: Option<SystemTime>,
+    /// Set when the entry couldn't be inspected, so it's reported without trying to copy it
+    error: Option<Error>,
+}
+
+enum EntryKind {
+    File,
+    Dir,
+    /// Symbolic link recreated as is, pointing to this
+    Link(String),
+}
+
+impl<'a> DirTransfer<'a> {
+    pub fn new(
+        sessions: &'a SessionManager,
+        device: Device,
+        window: Option<usize>,
+        options: TreeOptions,
+    ) -> Result<Self, Error> {
+        return Ok(DirTransfer {
+            sessions,
+            device,
+            window,
+            follow_links: options.follow_links,
+            filter: PathFilter::new(&options.include, &options.exclude)?,
+            progress: None,
+            cancelled: None,
+        });
+    }
+
+    /// Calls `progress` with the bytes transferred so far and the size of all files in the tree.
+    pub fn progress(mut self, progress: &'a (dyn Fn(u64, u64) + Sync)) -> Self {
+        self.progress = Some(progress);
+        return self;
+    }
+
+    /// Stops with [Error::Cancelled] once `cancelled` is set. Files already copied are kept.
+    pub fn cancel_on(mut self, cancelled: &'a AtomicBool) -> Self {
+        self.cancelled = Some(cancelled);
+        return self;
+    }
+
+    pub fn download(&self, path: &str, target: &Path) -> Result<DirTransferResult, Error> {
+        let started = Instant::now();
+        if let DeviceFileTransfer::Stream = self.files()? {
+            return self.download_tar(path, target, started);
+        }
+        let entries = self.sessions.with_session(self.device.clone(), |session| {
+            return session.with_sftp(|sftp| self.remote_tree(sftp, path));
+        })?;
+        return self.run(
+            entries,
+            started,
+            |entry, progress| {
+                let local = target.join(&entry.path);
+                return match &entry.kind {
+                    EntryKind::Dir => {
+                        fs::create_dir_all(&local)?;
+                        Ok(0)
+                    }
+                    EntryKind::File => {
+                        let remote = remote_path(path, &entry.path);
+                        let stats = self.file_transfer(progress).download(&remote, &local)?;
+                        set_local_attrs(&local, entry);
+                        Ok(stats.bytes)
+                    }
+                    EntryKind::Link(dest) => {
+                        fs::remove_file(&local).unwrap_or(());
+                        local_symlink(dest, &local)?;
+                        Ok(0)
+                    }
+                };
+            },
+            |entry| set_local_attrs(&target.join(&entry.path), entry),
+        );
+    }
+
+    pub fn upload(&self, source: &Path, path: &str) -> Result<DirTransferResult, Error> {
+        let started = Instant::now();
+        let entries = self.local_tree(source)?;
+        if let DeviceFileTransfer::Stream = self.files()? {
+            return self.upload_tar(source, path, entries, started, &HashSet::new());
+        }
+        return self.run(
+            entries,
+            started,
+            |entry, progress| self.upload_entry(source, path, entry, progress),
+            |entry| self.set_remote_attrs(&remote_path(path, &entry.path), entry),
+        );
+    }
+
+    /// Copies an entry of the local tree at `source` to the remote tree at `path`.
+    fn upload_entry(
+        &self,
+        source: &Path,
+        path: &str,
+        entry: &Entry,
+        progress: &(dyn Fn(u64, u64) + Sync),
+    ) -> Result<u64, Error> {
+        let remote = remote_path(path, &entry.path);
+        return match &entry.kind {
+            EntryKind::Dir => self.with_sftp(|sftp| {
+                if let Err(e) = sftp.create_dir(&remote, 0o755) {
+                    // Servers tell nothing more than "failure" when it already exists
+                    let exists = sftp.metadata(&remote).is_ok_and(|m| {
+                        return matches!(m.file_type(), Some(FileType::Directory));
+                    });
+                    if !exists {
+                        return Err(e.into());
+                    }
+                }
+                return Ok(0);
+            }),
+            EntryKind::File => {
+                let local = source.join(&entry.path);
+                let stats = self.file_transfer(progress).upload(&local, &remote)?;
+                self.set_remote_attrs(&remote, entry);
+                Ok(stats.bytes)
+            }
+            EntryKind::Link(dest) => self.with_sftp(|sftp| {
+                sftp.remove_file(&remote).unwrap_or(());
+                sftp.symlink(dest, &remote)?;
+                return Ok(0);
+            }),
+        };
+    }
+
+    /// Copies the entries in order with `copy`, then calls `finish_dir` on the directories
+    /// created.
+    fn run<C, D>(
+        &self,
+        entries: Vec<Entry>,
+        started: Instant,
+        copy: C,
+        finish_dir: D,
+    ) -> Result<DirTransferResult, Error>
+    where
+        C: Fn(&Entry, &(dyn Fn(u64, u64) + Sync)) -> Result<u64, Error>,
+        D: Fn(&Entry),
+    {
+        let total: u64 = entries
+            .iter()
+            .filter(|e| matches!(e.kind, EntryKind::File))
+            .map(|e| e.size)
+            .sum();
+        let mut done = 0u64;
+        let mut fatal: Option<Error> = None;
+        let mut files = Vec::<EntryResult>::with_capacity(entries.len());
+        for entry in &entries {
+            if self.cancelled.is_some_and(|c| c.load(Ordering::SeqCst)) {
+                return Err(Error::Cancelled);
+            }
+            let result = match fatal.as_ref().or(entry.error.as_ref()) {
+                Some(e) => Err(e.clone()),
+                None => {
+                    let (offset, report) = (done, self.progress);
+                    copy(entry, &move |transferred: u64, _: u64| {
+                        if let Some(report) = report {
+                            report(offset + transferred, total);
+                        }
+                    })
+                }
+            };
+            if let EntryKind::File = entry.kind {
+                done += entry.size;
+            }
+            files.push(match result {
+                Ok(bytes) => EntryResult::new(&entry.path, entry.kind.abbrev(), bytes, None),
+                Err(Error::Cancelled) => return Err(Error::Cancelled),
+                Err(e) => {
+                    log::warn!("Failed to copy {}: {:?}", entry.path, e);
+                    if fatal.is_none() && is_fatal(&e) {
+                        fatal = Some(e.clone());
+                    }
+                    EntryResult::new(&entry.path, entry.kind.abbrev(), 0, Some(e))
+                }
+            });
+        }
+        if fatal.is_none() {
+            // Deepest first, as copying into a directory changes its mtime
+            for (entry, result) in entries.iter().zip(files.iter()).rev() {
+                if matches!(entry.kind, EntryKind::Dir) && result.error.is_none() {
+                    finish_dir(entry);
+                }
+            }
+        }
+        let bytes = files.iter().map(|f| f.bytes).sum();
+        let failed = files.iter().filter(|f| f.error.is_some()).count();
+        log::info!(
+            "Copied {} entries, {} bytes with {} in {:?}, {} failed",
+            files.len() - failed,
+            bytes,
+            self.device.name,
+            started.elapsed(),
+            failed
+        );
+        return Ok(DirTransferResult {
+            files,
+            bytes,
+            elapsed_ms: started.elapsed().as_millis() as u64,
+            failed,
+        });
+    }
+
+    fn remote_tree(&self, sftp: &Sftp, root: &str) -> Result<Vec<Entry>, Error> {
+        let stat = sftp.metadata(root)?;
+        if !matches!(stat.file_type(), Some(FileType::Directory)) {
+            return Err(Error::new(format!("{root} is not a directory")));
+        }
+        let mut entries = vec![Entry::remote(String::from("."), EntryKind::Dir, &stat)];
+        self.walk_remote(sftp, root, 0, 0, &mut entries);
+        return Ok(entries);
+    }
+
+    fn walk_remote(
+        &self,
+        sftp: &Sftp,
+        root: &str,
+        index: usize,
+        depth: usize,
+        entries: &mut Vec<Entry>,
+    ) {
+        if depth >= MAX_DEPTH {
+            entries[index].error = Some(Error::new("Too many levels of directories"));
+            return;
+        }
+        let dir = entries[index].path.clone();
+        let mut children = match sftp.read_dir(&remote_path(root, &dir)) {
+            Ok(children) => children,
+            Err(e) => {
+                entries[index].error = Some(e.into());
+                return;
+            }
+        };
+        children.sort_by(|a, b| a.name().cmp(&b.name()));
+        for stat in children {
+            let name = match stat.name() {
+                Some(".") | Some("..") | None => continue,
+                Some(name) => name,
+            };
+            let path = child_path(&dir, name);
+            let full = remote_path(root, &path);
+            let entry = match stat.file_type() {
+                Some(FileType::Symlink) if self.follow_links => match sftp.metadata(&full) {
+                    Ok(target) => match target.file_type() {
+                        Some(FileType::Directory) => Entry::remote(path, EntryKind::Dir, &target),
+                        Some(FileType::Regular) => Entry::remote(path, EntryKind::File, &target),
+                        _ => continue,
+                    },
+                    Err(e) => Entry::failed(path, EntryKind::File, e.into()),
+                },
+                Some(FileType::Symlink) => match sftp.read_link(&full) {
+                    Ok(dest) => Entry::remote(path, EntryKind::Link(dest), &stat),
+                    Err(e) => Entry::failed(path, EntryKind::Link(String::new()), e.into()),
+                },
+                Some(FileType::Directory) => Entry::remote(path, EntryKind::Dir, &stat),
+                Some(FileType::Regular) => Entry::remote(path, EntryKind::File, &stat),
+                _ => {
+                    log::debug!("Skipping special file {}", full);
+                    continue;
+                }
+            };
+            if self.push(entry, entries) {
+                self.walk_remote(sftp, root, entries.len() - 1, depth + 1, entries);
+                self.prune(entries);
+            }
+        }
+    }
+
+    fn local_tree(&self, root: &Path) -> Result<Vec<Entry>, Error> {
+        let meta = root.metadata()?;
+        if !meta.is_dir() {
+            return Err(Error::new(format!("{} is not a directory", root.display())));
+        }
+        let mut entries = vec![Entry::local(String::from("."), EntryKind::Dir, &meta)];
+        self.walk_local(root, 0, 0, &mut entries);
+        return Ok(entries);
+    }
+
+    fn walk_local(&self, root: &Path, index: usize, depth: usize, entries: &mut Vec<Entry>) {
+        if depth >= MAX_DEPTH {
+            entries[index].error = Some(Error::new("Too many levels of directories"));
+            return;
+        }
+        let dir = entries[index].path.clone();
+        let mut children =
+            match fs::read_dir(root.join(&dir)).and_then(|d| d.collect::<Result<Vec<_>, _>>()) {
+                Ok(children) => children,
+                Err(e) => {
+                    entries[index].error = Some(e.into());
+                    return;
+                }
+            };
+        children.sort_by_key(|c| c.file_name());
+        for child in children {
+            let name = child.file_name();
+            let Some(name) = name.to_str() else {
+                let path = child_path(&dir, &name.to_string_lossy());
+                let error = Error::new("File name is not valid UTF-8");
+                entries.push(Entry::failed(path, EntryKind::File, error));
+                continue;
+            };
+            let path = child_path(&dir, name);
+            let full = child.path();
+            let entry = match fs::symlink_metadata(&full) {
+                Ok(meta) if meta.is_symlink() && self.follow_links => match full.metadata() {
+                    Ok(target) if target.is_dir() => Entry::local(path, EntryKind::Dir, &target),
+                    Ok(target) if target.is_file() => Entry::local(path, EntryKind::File, &target),
+                    Ok(_) => continue,
+                    Err(e) => Entry::failed(path, EntryKind::File, e.into()),
+                },
+                Ok(meta) if meta.is_symlink() => match fs::read_link(&full) {
+                    Ok(dest) => {
+                        let dest = dest.to_slash_lossy().to_string();
+                        Entry::local(path, EntryKind::Link(dest), &meta)
+                    }
+                    Err(e) => Entry::failed(path, EntryKind::Link(String::new()), e.into()),
+                },
+                Ok(meta) if meta.is_dir() => Entry::local(path, EntryKind::Dir, &meta),
+                Ok(meta) if meta.is_file() => Entry::local(path, EntryKind::File, &meta),
+                Ok(_) => {
+                    log::debug!("Skipping special file {:?}", full);
+                    continue;
+                }
+                Err(e) => Entry::failed(path, EntryKind::File, e.into()),
+            };
+            if self.push(entry, entries) {
+                self.walk_local(root, entries.len() - 1, depth + 1, entries);
+                self.prune(entries);
+            }
+        }
+    }
+
+    /// Adds the entry if the filter takes it. Returns true if it's a directory to walk into.
+    fn push(&self, entry: Entry, entries: &mut Vec<Entry>) -> bool {
+        let walk = match entry.kind {
+            EntryKind::Dir if !self.filter.dir(&entry.path) => return false,
+            EntryKind::Dir => entry.error.is_none(),
+            _ if !self.filter.file(&entry.path) => return false,
+            _ => false,
+        };
+        entries.push(entry);
+        return walk;
+    }
+
+    /// Drops the directory just walked if nothing in it was included.
+    fn prune(&self, entries: &mut Vec<Entry>) {
+        let last = entries.last().expect("walked directory");
+        if self.filter.has_include() && matches!(last.kind, EntryKind::Dir) && last.error.is_none()
+        {
+            entries.pop();
+        }
+    }
+
+    fn files(&self) -> Result<DeviceFileTransfer, Error> {
+        return self
+            .sessions
+            .with_session(self.device.clone(), |session| Ok(session.files()));
+    }
+
+    fn file_transfer<'b>(&'b self, progress: &'b (dyn Fn(u64, u64) + Sync)) -> Transfer<'b> {
+        let transfer =
+            Transfer::new(self.sessions, self.device.clone(), self.window).progress(progress);
+        return match self.cancelled {
+            Some(cancelled) => transfer.cancel_on(cancelled),
+            None => transfer,
+        };
+    }
+
+    fn with_sftp<T, F>(&self, action: F) -> Result<T, Error>
+    where
+        F: Fn(&Sftp) -> Result<T, Error>,
+    {
+        return self.sessions.with_session(self.device.clone(), |session| {
+            return session.with_sftp(|sftp| action(sftp));
+        });
+    }
+
+    /// Best effort, as the content is what matters.
+    fn set_remote_attrs(&self, path: &str, entry: &Entry) {
+        let attrs = SetAttributes {
+            size: None,
+            uid_gid: None,
+            permissions: Some(entry.mode),
+            atime_mtime: entry.mtime.map(|t| (t, t)),
+        };
+        self.with_sftp(|sftp| Ok(sftp.set_metadata(path, &attrs)?))
+            .unwrap_or_else(|e| log::warn!("Failed to set mode and mtime of {}: {:?}", path, e));
+    }
+}
+
+impl Entry {
+    fn remote(path: String, kind: EntryKind, stat: &Metadata) -> Entry {
+        return Entry {
+            path,
+            kind,
+            size: stat.len().unwrap_or(0),
+            mode: stat.permissions().unwrap_or(0o644) & 0o7777,
+            mtime: stat.modified(),
+            error: None,
+        };
+    }
+
+    fn stream(path: String, kind: EntryKind, item: &TreeItem) -> Entry {
+        return Entry {
+            path,
+            kind,
+            size: item.size,
+            mode: item.mode,
+            mtime: Some(UNIX_EPOCH + Duration::from_secs(item.mtime)),
+            error: None,
+        };
+    }
+
+    fn local(path: String, kind: EntryKind, meta: &fs::Metadata) -> Entry {
+        return Entry {
+            path,
+            kind,
+            size: meta.len(),
+            mode: local_mode(meta),
+            mtime: meta.modified().ok(),
+            error: None,
+        };
+    }
+
+    fn failed(path: String, kind: EntryKind, error: Error) -> Entry {
+        return Entry {
+            path,
+            kind,
+            size: 0,
+            mode: 0,
+            mtime: None,
+            error: Some(error),
+        };
+    }
+}
+
+impl EntryKind {
+    fn abbrev(&self) -> char {
+        return match self {
+            EntryKind::File => '-',
+            EntryKind::Dir => 'd',
+            EntryKind::Link(_) => 'l',
+        };
+    }
+}
+
+/// Errors after which copying the rest of the tree is pointless.
+fn is_fatal(e: &Error) -> bool {
+    return matches!(
+        e,
+        Error::Disconnected | Error::RetriesExhausted { .. } | Error::Timeout
+    );
+}
+
+fn child_path(dir: &str, name: &str) -> String {
+    return if dir == "." {
+        String::from(name)
+    } else {
+        format!("{dir}/{name}")
+    };
+}
+
+fn remote_path(root: &str, path: &str) -> String {
+    return if path == "." {
+        String::from(root)
+    } else {
+        format!("{}/{}", root.trim_end_matches('/'), path)
+    };
+}
+
+/// SFTP and `tar` only have whole seconds.
+fn secs(time: Option<SystemTime>) -> Option<u64> {
+    return time.and_then(|t| t.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs()));
+}
+
+/// Best effort, as the content is what matters. The mtime goes first, as the new mode might
+/// not let us write.
+fn set_local_attrs(path: &Path, entry: &Entry) {
+    if let Some(mtime) = entry.mtime {
+        let file = if path.is_dir() {
+            File::open(path)
+        } else {
+            OpenOptions::new().write(true).open(path)
+        };
+        if let Err(e) = file.and_then(|f| f.set_modified(mtime)) {
+            log::warn!("Failed to set mtime of {:?}: {:?}", path, e);
+        }
+    }
+    if let Err(e) = set_local_mode(path, entry.mode) {
+        log::warn!("Failed to set mode of {:?}: {:?}", path, e);
+    }
+}
+
+#[cfg(unix)]
+fn local_mode(meta: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    return meta.permissions().mode() & 0o7777;
+}
+
+#[cfg(not(unix))]
+fn local_mode(meta: &fs::Metadata) -> u32 {
+    let mode = if meta.is_dir() { 0o755 } else { 0o644 };
+    return if meta.permissions().readonly() {
+        mode & !0o222
+    } else {
+        mode
+    };
+}
+
+#[cfg(unix)]
+fn set_local_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    return fs::set_permissions(path, fs::Permissions::from_mode(mode));
+}
+
+#[cfg(not(unix))]
+fn set_local_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    let mut permissions = path.metadata()?.permissions();
+    permissions.set_readonly(mode & 0o200 == 0);
+    return fs::set_permissions(path, permissions);
+}
+
+#[cfg(unix)]
+fn local_symlink(dest: &str, path: &Path) -> Result<(), Error> {
+    std::os::unix::fs::symlink(dest, path)?;
+    return Ok(());
+}
+
+#[cfg(not(unix))]
+fn local_symlink(_dest: &str, _path: &Path) -> Result<(), Error> {
+    return Err(Error::Unsupported);
+}
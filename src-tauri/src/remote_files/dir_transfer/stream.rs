@@ -0,0 +1,466 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+use tar::{Archive, Builder, Entries, EntryType, Header};
+use uuid::Uuid;
+
+use crate::conn_pool::DeviceConnection;
+use crate::error::Error;
+use crate::remote_files::dir_transfer::{
+    local_symlink, remote_path, secs, set_local_attrs, DirTransfer, Entry, EntryKind, MAX_DEPTH,
+};
+use crate::remote_files::stream::{self, TreeItem};
+use crate::remote_files::transfer::download_temp;
+use crate::remote_files::DirTransferResult;
+
+const COPY_BUF_SIZE: usize = 32768;
+
+/// Members of the archive `tar` sends, taken in the order their files were listed.
+struct Members<'a, R: Read> {
+    entries: Entries<'a, R>,
+    /// Position of each file in the list
+    order: HashMap<String, usize>,
+    /// Read ahead, as it belongs to a file further down the list
+    pending: Option<tar::Entry<'a, R>>,
+    /// Set once the archive couldn't be read, which is the end of it
+    error: Option<Error>,
+    /// Files `tar` didn't send, most likely as it failed to read them
+    left_out: HashSet<String>,
+}
+
+/// File added to the archive, padded with zeros if it can't be read whole, so that the archive
+/// stays intact. Such files are left in the staging directory, see [DirTransfer::upload_tar].
+struct ArchivedFile<'a> {
+    file: File,
+    size: u64,
+    read: u64,
+    error: Option<io::Error>,
+    progress: &'a (dyn Fn(u64, u64) + Sync),
+    cancelled: Option<&'a AtomicBool>,
+}
+
+impl DirTransfer<'_> {
+    /// Lists the tree at `root` with [stream::tree], taking entries like the SFTP walk does.
+    pub(super) fn stream_tree(
+        &self,
+        conn: &DeviceConnection,
+        root: &str,
+    ) -> Result<Vec<Entry>, Error> {
+        let mut root_item: Option<TreeItem> = None;
+        let mut children = HashMap::<String, Vec<TreeItem>>::new();
+        for item in stream::tree(conn, root, self.follow_links, MAX_DEPTH)? {
+            if item.path == "." {
+                root_item = Some(item);
+                continue;
+            }
+            let parent = match item.path.rsplit_once('/') {
+                Some((parent, _)) => String::from(parent),
+                None => String::from("."),
+            };
+            children.entry(parent).or_default().push(item);
+        }
+        let root_item = root_item
+            .filter(|item| item.r#type == Some('d'))
+            .ok_or_else(|| Error::new(format!("Failed to list {root}")))?;
+        let mut entries = vec![Entry::stream(String::from("."), EntryKind::Dir, &root_item)];
+        self.walk_stream(&mut children, 0, 0, &mut entries);
+        return Ok(entries);
+    }
+
+    fn walk_stream(
+        &self,
+        children: &mut HashMap<String, Vec<TreeItem>>,
+        index: usize,
+        depth: usize,
+        entries: &mut Vec<Entry>,
+    ) {
+        if depth >= MAX_DEPTH {
+            entries[index].error = Some(Error::new("Too many levels of directories"));
+            return;
+        }
+        let mut items = children.remove(&entries[index].path).unwrap_or_default();
+        items.sort_by(|a, b| a.path.cmp(&b.path));
+        for item in items {
+            let path = item.path.clone();
+            let entry = match item.r#type {
+                Some('d') => Entry::stream(path, EntryKind::Dir, &item),
+                Some('-') => Entry::stream(path, EntryKind::File, &item),
+                Some('l') => Entry::stream(path, EntryKind::Link(item.target.clone()), &item),
+                Some(_) => {
+                    log::debug!("Skipping special file {}", path);
+                    continue;
+                }
+                None => Entry::failed(path, EntryKind::File, Error::io(ErrorKind::NotFound)),
+            };
+            if self.push(entry, entries) {
+                self.walk_stream(children, entries.len() - 1, depth + 1, entries);
+                self.prune(entries);
+            }
+        }
+    }
+
+    /// Copies the tree at `path` to `target`, with its files sent by `tar` in one archive.
+    pub(super) fn download_tar(
+        &self,
+        path: &str,
+        target: &Path,
+        started: Instant,
+    ) -> Result<DirTransferResult, Error> {
+        let entries = self.sessions.with_session(self.device.clone(), |session| {
+            return self.stream_tree(session, path);
+        })?;
+        let files: Vec<String> = entries
+            .iter()
+            .filter(|e| matches!(e.kind, EntryKind::File) && e.error.is_none())
+            .map(|e| e.path.clone())
+            .collect();
+        let session = self.sessions.session(self.device.clone())?;
+        let ch = stream::tar_reader(&session, path, &files)?;
+        let mut archive = Archive::new(ch.stdout());
+        let members = RefCell::new(Members::new(archive.entries()?, &files));
+        let mut result = self.run(
+            entries,
+            started,
+            |entry, progress| {
+                let local = target.join(&entry.path);
+                return match &entry.kind {
+                    EntryKind::Dir => {
+                        fs::create_dir_all(&local)?;
+                        Ok(0)
+                    }
+                    EntryKind::File => {
+                        let bytes =
+                            self.extract(&mut members.borrow_mut(), entry, target, progress)?;
+                        set_local_attrs(&local, entry);
+                        Ok(bytes)
+                    }
+                    EntryKind::Link(dest) => {
+                        fs::remove_file(&local).unwrap_or(());
+                        local_symlink(dest, &local)?;
+                        Ok(0)
+                    }
+                };
+            },
+            |entry| set_local_attrs(&target.join(&entry.path), entry),
+        )?;
+        let left_out = members.into_inner().left_out;
+        drop(archive);
+        // What follows the last member is padding
+        io::copy(&mut ch.stdout(), &mut io::sink())?;
+        if let Err(e) = stream::finish(ch) {
+            log::warn!("tar failed on {} of {}: {:?}", path, self.device.name, e);
+            for file in &mut result.files {
+                if left_out.contains(&file.path) {
+                    file.error = Some(e.clone());
+                }
+            }
+        }
+        session.mark_last_ok();
+        return Ok(result);
+    }
+
+    /// Writes the file of `entry` from the archive, to a temporary file renamed over it once
+    /// complete.
+    fn extract<R: Read>(
+        &self,
+        members: &mut Members<R>,
+        entry: &Entry,
+        target: &Path,
+        progress: &(dyn Fn(u64, u64) + Sync),
+    ) -> Result<u64, Error> {
+        let mut member = members.take(&entry.path)?;
+        let local = target.join(&entry.path);
+        let temp = download_temp(&local)?;
+        let result = if member.header().entry_type().is_hard_link() {
+            // Same file as one extracted before
+            let linked = member
+                .link_name_bytes()
+                .map(|name| {
+                    String::from_utf8_lossy(&name)
+                        .trim_start_matches("./")
+                        .to_string()
+                })
+                .ok_or_else(|| Error::io(ErrorKind::InvalidData))?;
+            fs::copy(target.join(linked), &temp).map_err(Error::from)
+        } else {
+            let size = member.size();
+            File::create(&temp)
+                .map_err(Error::from)
+                .and_then(|mut file| self.copy(&mut member, &mut file, size, progress))
+                .and_then(|copied| {
+                    if copied < size {
+                        return Err(Error::io(ErrorKind::UnexpectedEof));
+                    }
+                    return Ok(copied);
+                })
+        };
+        let result = result.and_then(|bytes| {
+            fs::rename(&temp, &local)?;
+            return Ok(bytes);
+        });
+        if result.is_err() {
+            fs::remove_file(&temp).unwrap_or(());
+        }
+        return result;
+    }
+
+    /// Copies `entries` of the local tree at `source` to `path`, in one archive extracted by
+    /// `tar`, replacing the remote entries at `replaced`.
+    ///
+    /// The archive is extracted to a staging directory in `path`, and only entries that went in
+    /// whole are moved into place, once `tar` is done. If it fails, nothing is, and all entries
+    /// are reported with its error.
+    pub(super) fn upload_tar(
+        &self,
+        source: &Path,
+        path: &str,
+        entries: Vec<Entry>,
+        started: Instant,
+        replaced: &HashSet<String>,
+    ) -> Result<DirTransferResult, Error> {
+        let session = self.sessions.session(self.device.clone())?;
+        let staging = remote_path(path, &format!(".tar.{}.part", Uuid::new_v4()));
+        let ch = stream::tar_writer(&session, &staging)?;
+        let builder = RefCell::new(Builder::new(ch.stdin()));
+        let dirs = RefCell::new(Vec::<(String, u32, Option<u64>)>::new());
+        let result = self.run(
+            entries,
+            started,
+            |entry, progress| self.append(&mut builder.borrow_mut(), source, entry, progress),
+            |entry| {
+                let remote = remote_path(path, &entry.path);
+                dirs.borrow_mut()
+                    .push((remote, entry.mode, secs(entry.mtime)));
+            },
+        );
+        let written = builder.into_inner().into_inner().map(|_| ());
+        let finished = match written {
+            Ok(()) => ch
+                .send_eof()
+                .map_err(Error::from)
+                .and_then(|_| stream::finish(ch)),
+            Err(e) => Err(e.into()),
+        };
+        let mut result = match result {
+            Ok(result) => result,
+            Err(e) => {
+                stream::remove(&session, &staging, true).unwrap_or(());
+                return Err(e);
+            }
+        };
+        let moved = finished.and_then(|_| {
+            let staged: Vec<(&str, bool, bool)> = result
+                .files
+                .iter()
+                .filter(|file| file.error.is_none() && file.path != ".")
+                .map(|file| {
+                    let replace = replaced.contains(&file.path);
+                    return (file.path.as_str(), file.r#type == 'd', replace);
+                })
+                .collect();
+            return stream::move_staged(&session, &staging, path, &staged);
+        });
+        let unmoved = match moved {
+            Ok(unmoved) => unmoved,
+            Err(e) => {
+                log::warn!("tar failed on {} of {}: {:?}", path, self.device.name, e);
+                stream::remove(&session, &staging, true).unwrap_or(());
+                for file in &mut result.files {
+                    if file.error.is_none() {
+                        file.bytes = 0;
+                        file.error = Some(e.clone());
+                    }
+                }
+                result.bytes = 0;
+                result.failed = result.files.len();
+                return Ok(result);
+            }
+        };
+        for file in &mut result.files {
+            if unmoved.contains(&file.path) {
+                log::warn!("Failed to move {} into place in {}", file.path, path);
+                result.bytes -= file.bytes;
+                result.failed += 1;
+                file.bytes = 0;
+                file.error = Some(Error::new("Failed to move into place"));
+            }
+        }
+        // Directories were added writable, and had their mtimes changed by what went in them
+        for (dir, mode, mtime) in dirs.into_inner() {
+            let attrs = stream::chmod(&session, &dir, mode).and_then(|_| match mtime {
+                Some(mtime) => stream::set_mtime(&session, &dir, mtime),
+                None => Ok(()),
+            });
+            if let Err(e) = attrs {
+                log::warn!("Failed to set mode and mtime of {}: {:?}", dir, e);
+            }
+        }
+        session.mark_last_ok();
+        return Ok(result);
+    }
+
+    /// Adds an entry of the local tree at `source` to the archive. The root is left out, as it's
+    /// where the archive is extracted.
+    fn append<W: Write>(
+        &self,
+        builder: &mut Builder<W>,
+        source: &Path,
+        entry: &Entry,
+        progress: &(dyn Fn(u64, u64) + Sync),
+    ) -> Result<u64, Error> {
+        let mut header = Header::new_gnu();
+        header.set_mode(entry.mode);
+        header.set_mtime(secs(entry.mtime).unwrap_or(0));
+        header.set_size(0);
+        return match &entry.kind {
+            EntryKind::Dir if entry.path == "." => Ok(0),
+            EntryKind::Dir => {
+                header.set_entry_type(EntryType::Directory);
+                header.set_mode(0o755);
+                builder.append_data(&mut header, &entry.path, io::empty())?;
+                Ok(0)
+            }
+            EntryKind::File => {
+                let file = File::open(source.join(&entry.path))?;
+                let size = file.metadata()?.len();
+                header.set_entry_type(EntryType::Regular);
+                header.set_size(size);
+                let mut data = ArchivedFile {
+                    file,
+                    size,
+                    read: 0,
+                    error: None,
+                    progress,
+                    cancelled: self.cancelled,
+                };
+                if let Err(e) = builder.append_data(&mut header, &entry.path, &mut data) {
+                    if self.cancelled.is_some_and(|c| c.load(Ordering::SeqCst)) {
+                        return Err(Error::Cancelled);
+                    }
+                    return Err(e.into());
+                }
+                if let Some(e) = data.error {
+                    return Err(e.into());
+                }
+                Ok(size)
+            }
+            EntryKind::Link(dest) => {
+                header.set_entry_type(EntryType::Symlink);
+                builder.append_link(&mut header, &entry.path, dest)?;
+                Ok(0)
+            }
+        };
+    }
+
+    /// Like [std::io::copy], but reports progress and stops when cancelled.
+    fn copy<Rd: Read, W: Write>(
+        &self,
+        reader: &mut Rd,
+        writer: &mut W,
+        size: u64,
+        progress: &(dyn Fn(u64, u64) + Sync),
+    ) -> Result<u64, Error> {
+        let mut buf = vec![0u8; COPY_BUF_SIZE];
+        let mut copied = 0u64;
+        loop {
+            if self.cancelled.is_some_and(|c| c.load(Ordering::SeqCst)) {
+                return Err(Error::Cancelled);
+            }
+            let n = match reader.read(&mut buf) {
+                Ok(0) => return Ok(copied),
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            writer.write_all(&buf[..n])?;
+            copied += n as u64;
+            progress(copied, size);
+        }
+    }
+}
+
+impl<'a, R: Read> Members<'a, R> {
+    fn new(entries: Entries<'a, R>, files: &[String]) -> Self {
+        return Members {
+            entries,
+            order: files
+                .iter()
+                .enumerate()
+                .map(|(i, f)| (f.clone(), i))
+                .collect(),
+            pending: None,
+            error: None,
+            left_out: HashSet::new(),
+        };
+    }
+
+    /// Member holding the file at `path`. Members of files not listed are skipped.
+    fn take(&mut self, path: &str) -> Result<tar::Entry<'a, R>, Error> {
+        if let Some(e) = &self.error {
+            return Err(e.clone());
+        }
+        let index = self.order.get(path).copied();
+        loop {
+            let member = match self.pending.take() {
+                Some(member) => member,
+                None => match self.entries.next() {
+                    Some(Ok(member)) => member,
+                    Some(Err(e)) => {
+                        let e = Error::from(e);
+                        self.error = Some(e.clone());
+                        return Err(e);
+                    }
+                    None => break,
+                },
+            };
+            let name = String::from_utf8_lossy(&member.path_bytes())
+                .trim_start_matches("./")
+                .to_string();
+            if name == path {
+                return Ok(member);
+            }
+            if self.order.get(&name).copied() > index {
+                self.pending = Some(member);
+                break;
+            }
+        }
+        self.left_out.insert(String::from(path));
+        return Err(Error::new("Left out of the archive by tar"));
+    }
+}
+
+impl Read for ArchivedFile<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.cancelled.is_some_and(|c| c.load(Ordering::SeqCst)) {
+            return Err(io::Error::other("Cancelled"));
+        }
+        let len = (self.size - self.read).min(buf.len() as u64) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+        let mut n = 0;
+        while self.error.is_none() {
+            match self.file.read(&mut buf[..len]) {
+                Ok(0) => self.error = Some(io::Error::from(ErrorKind::UnexpectedEof)),
+                Ok(read) => {
+                    n = read;
+                    break;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => self.error = Some(e),
+            }
+        }
+        if self.error.is_some() {
+            buf[..len].fill(0);
+            n = len;
+        }
+        self.read += n as u64;
+        (self.progress)(self.read, self.size);
+        return Ok(n);
+    }
+}
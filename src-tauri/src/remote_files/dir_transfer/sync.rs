@@ -0,0 +1,450 @@
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
+use crate::device_manager::DeviceFileTransfer;
+use crate::error::Error;
+use crate::remote_files::dir_transfer::{remote_path, secs, DirTransfer, Entry, EntryKind};
+use crate::remote_files::{
+    ops, stream, DirTransferResult, EntryResult, SyncAction, SyncActionKind, SyncOptions,
+    SyncReason, SyncResult,
+};
+
+impl DirTransfer<'_> {
+    /// Makes the remote tree at `path` like the local one at `source`, uploading only files that
+    /// differ in size or mtime, or with [SyncOptions::checksum], in SHA-256. Extra remote entries
+    /// are deleted with [SyncOptions::delete].
+    ///
+    /// Directories are only created, their mtimes aren't compared. Devices without SFTP get the
+    /// entries to upload in one `tar` archive.
+    pub fn sync(
+        &self,
+        source: &Path,
+        path: &str,
+        options: &SyncOptions,
+    ) -> Result<SyncResult, Error> {
+        let started = Instant::now();
+        let local = self.local_tree(source)?;
+        let files = self.files()?;
+        let remote = self.sessions.with_session(self.device.clone(), |session| {
+            let tree = match files {
+                DeviceFileTransfer::Stream => self.stream_tree(session, path),
+                DeviceFileTransfer::Sftp => session.with_sftp(|sftp| self.remote_tree(sftp, path)),
+            };
+            return match tree {
+                Err(Error::IO {
+                    code: ErrorKind::NotFound,
+                    ..
+                }) => Ok(Vec::new()),
+                result => result,
+            };
+        })?;
+        let remote_entries: HashMap<&str, &Entry> =
+            remote.iter().map(|e| (e.path.as_str(), e)).collect();
+
+        let mut reasons = Vec::<Option<SyncReason>>::with_capacity(local.len());
+        let mut compare = Vec::<usize>::new();
+        for (index, entry) in local.iter().enumerate() {
+            let theirs = remote_entries.get(entry.path.as_str()).copied();
+            match reason(entry, theirs, options.checksum) {
+                Some(SyncReason::Checksum) => {
+                    compare.push(index);
+                    reasons.push(None);
+                }
+                reason => reasons.push(reason),
+            }
+        }
+        if !compare.is_empty() {
+            for index in self.changed(source, path, &local, &compare)? {
+                reasons[index] = Some(SyncReason::Checksum);
+            }
+        }
+
+        let extras = if options.delete {
+            extras(&local, &remote)
+        } else {
+            Vec::new()
+        };
+        let Plan {
+            actions,
+            pending,
+            replaced,
+            unchanged,
+        } = plan(local, reasons, &extras);
+        log::info!(
+            "Sync to {} of {}: {} actions, {} unchanged",
+            self.device.name,
+            path,
+            actions.len(),
+            unchanged
+        );
+        if options.dry_run {
+            return Ok(SyncResult {
+                actions,
+                dry_run: true,
+                unchanged,
+                transfer: DirTransferResult {
+                    files: Vec::new(),
+                    bytes: 0,
+                    elapsed_ms: started.elapsed().as_millis() as u64,
+                    failed: 0,
+                },
+            });
+        }
+
+        let mut deleted = Vec::<EntryResult>::new();
+        for entry in &extras {
+            if self.cancelled.is_some_and(|c| c.load(Ordering::SeqCst)) {
+                return Err(Error::Cancelled);
+            }
+            let error = self.remove(&remote_path(path, &entry.path)).err();
+            deleted.push(EntryResult::new(&entry.path, entry.kind.abbrev(), 0, error));
+        }
+        let mut transfer = match files {
+            DeviceFileTransfer::Stream => {
+                self.upload_tar(source, path, pending, started, &replaced)?
+            }
+            DeviceFileTransfer::Sftp => self.run(
+                pending,
+                started,
+                |entry, progress| {
+                    if replaced.contains(&entry.path) {
+                        self.remove(&remote_path(path, &entry.path))?;
+                    }
+                    return self.upload_entry(source, path, entry, progress);
+                },
+                |entry| self.set_remote_attrs(&remote_path(path, &entry.path), entry),
+            )?,
+        };
+        transfer.failed += deleted.iter().filter(|d| d.error.is_some()).count();
+        deleted.append(&mut transfer.files);
+        transfer.files = deleted;
+        return Ok(SyncResult {
+            actions,
+            dry_run: false,
+            unchanged,
+            transfer,
+        });
+    }
+
+    fn remove(&self, path: &str) -> Result<(), Error> {
+        return self.sessions.with_session(self.device.clone(), |session| {
+            return match session.files() {
+                DeviceFileTransfer::Stream => stream::remove(session, path, true),
+                DeviceFileTransfer::Sftp => session.with_sftp(|sftp| ops::remove(sftp, path, true)),
+            };
+        });
+    }
+
+    /// Indices of the files at `indices` of `local` whose SHA-256 differs from the remote one.
+    fn changed(
+        &self,
+        source: &Path,
+        path: &str,
+        local: &[Entry],
+        indices: &[usize],
+    ) -> Result<Vec<usize>, Error> {
+        let paths: Vec<&str> = indices.iter().map(|i| local[*i].path.as_str()).collect();
+        let sums = self.sessions.with_session(self.device.clone(), |session| {
+            return stream::sha256sums(session, path, &paths);
+        })?;
+        return Ok(indices
+            .iter()
+            .copied()
+            .filter(|i| {
+                let entry = &local[*i];
+                let ours = sha256::try_digest(source.join(&entry.path).as_path()).ok();
+                return ours.is_none() || sums.get(&entry.path) != ours.as_ref();
+            })
+            .collect());
+    }
+}
+
+/// What a sync does, which is all a dry run reports.
+struct Plan {
+    actions: Vec<SyncAction>,
+    /// Local entries to upload
+    pending: Vec<Entry>,
+    /// Pending entries replacing a remote entry of another type
+    replaced: HashSet<String>,
+    /// Files already up to date
+    unchanged: usize,
+}
+
+/// Why the local entry `ours` is uploaded over the remote one, if at all.
+///
+/// With `checksum`, files of the same size get [SyncReason::Checksum], to be uploaded only if
+/// their SHA-256 turns out to differ.
+fn reason(ours: &Entry, theirs: Option<&Entry>, checksum: bool) -> Option<SyncReason> {
+    if ours.error.is_some() {
+        return Some(SyncReason::Unreadable);
+    }
+    let Some(theirs) = theirs else {
+        return Some(SyncReason::Missing);
+    };
+    return match (&ours.kind, &theirs.kind) {
+        (EntryKind::Dir, EntryKind::Dir) => None,
+        (EntryKind::Link(dest), EntryKind::Link(target)) => {
+            (dest != target).then_some(SyncReason::Target)
+        }
+        (EntryKind::File, EntryKind::File) if ours.size != theirs.size => Some(SyncReason::Size),
+        (EntryKind::File, EntryKind::File) if checksum => Some(SyncReason::Checksum),
+        (EntryKind::File, EntryKind::File) => {
+            (secs(ours.mtime) != secs(theirs.mtime)).then_some(SyncReason::Mtime)
+        }
+        _ => Some(SyncReason::Type),
+    };
+}
+
+/// Deletes `extras`, then uploads the entries of `local` that have a reason to.
+fn plan(local: Vec<Entry>, reasons: Vec<Option<SyncReason>>, extras: &[&Entry]) -> Plan {
+    let mut plan = Plan {
+        actions: Vec::new(),
+        pending: Vec::new(),
+        replaced: HashSet::new(),
+        unchanged: 0,
+    };
+    for entry in extras {
+        plan.actions.push(SyncAction::new(
+            entry,
+            SyncActionKind::Delete,
+            SyncReason::Extra,
+        ));
+    }
+    for (entry, reason) in local.into_iter().zip(reasons) {
+        let Some(reason) = reason else {
+            if let EntryKind::File = entry.kind {
+                plan.unchanged += 1;
+            }
+            continue;
+        };
+        plan.actions
+            .push(SyncAction::new(&entry, SyncActionKind::Upload, reason));
+        if reason == SyncReason::Type {
+            plan.replaced.insert(entry.path.clone());
+        }
+        plan.pending.push(entry);
+    }
+    return plan;
+}
+
+impl SyncAction {
+    fn new(entry: &Entry, action: SyncActionKind, reason: SyncReason) -> Self {
+        return SyncAction {
+            path: entry.path.clone(),
+            r#type: entry.kind.abbrev(),
+            action,
+            reason,
+            bytes: match (action, &entry.kind) {
+                (SyncActionKind::Upload, EntryKind::File) => entry.size,
+                _ => 0,
+            },
+        };
+    }
+}
+
+/// Remote entries not in the local tree, leaving out those inside another one, which goes with
+/// it.
+///
+/// What's in a local directory that couldn't be read is unknown, so nothing in it is extra.
+fn extras<'e>(local: &[Entry], remote: &'e [Entry]) -> Vec<&'e Entry> {
+    let unread: Vec<String> = local
+        .iter()
+        .filter(|e| e.error.is_some() && matches!(e.kind, EntryKind::Dir))
+        .map(|e| match e.path.as_str() {
+            "." => String::new(),
+            path => format!("{path}/"),
+        })
+        .collect();
+    let local: HashSet<&str> = local.iter().map(|e| e.path.as_str()).collect();
+    let mut extras = Vec::<&Entry>::new();
+    for entry in remote {
+        let inside = extras.last().is_some_and(|dir: &&Entry| {
+            return entry.path.starts_with(&format!("{}/", dir.path));
+        });
+        let unknown = unread
+            .iter()
+            .any(|dir| entry.path.starts_with(dir.as_str()));
+        if !inside && !unknown && !local.contains(entry.path.as_str()) {
+            extras.push(entry);
+        }
+    }
+    return extras;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crate::error::Error;
+    use crate::remote_files::dir_transfer::sync::{extras, plan, reason};
+    use crate::remote_files::dir_transfer::{Entry, EntryKind};
+    use crate::remote_files::{SyncActionKind, SyncReason};
+
+    fn entry(path: &str, kind: EntryKind) -> Entry {
+        return Entry {
+            path: String::from(path),
+            kind,
+            size: 0,
+            mode: 0,
+            mtime: None,
+            error: None,
+        };
+    }
+
+    fn tree(paths: &[&str]) -> Vec<Entry> {
+        return paths
+            .iter()
+            .map(|p| match p.strip_suffix('/') {
+                Some(dir) => entry(dir, EntryKind::Dir),
+                None => entry(p, EntryKind::File),
+            })
+            .collect();
+    }
+
+    fn paths(extras: Vec<&Entry>) -> Vec<&str> {
+        return extras.iter().map(|e| e.path.as_str()).collect();
+    }
+
+    fn file(size: u64, mtime: u64) -> Entry {
+        let mut file = entry("f", EntryKind::File);
+        file.size = size;
+        file.mtime = Some(UNIX_EPOCH + Duration::from_millis(mtime));
+        return file;
+    }
+
+    #[test]
+    fn same_tree() {
+        let local = tree(&["a/", "a/b", "c"]);
+        let remote = tree(&["a/", "a/b", "c"]);
+        assert!(extras(&local, &remote).is_empty());
+    }
+
+    #[test]
+    fn missing_locally() {
+        let local = tree(&["a/", "a/b"]);
+        let remote = tree(&["a/", "a/b", "a/c", "d"]);
+        assert_eq!(paths(extras(&local, &remote)), vec!["a/c", "d"]);
+    }
+
+    #[test]
+    fn inside_extra_dir() {
+        let local = tree(&["c"]);
+        let remote = tree(&["a/", "a/b/", "a/b/c", "a/d", "c"]);
+        assert_eq!(paths(extras(&local, &remote)), vec!["a"]);
+    }
+
+    #[test]
+    fn shared_prefix() {
+        let local = tree(&["a/", "a/b"]);
+        let remote = tree(&["a/", "a/b", "a-b", "ab/", "ab/c"]);
+        assert_eq!(paths(extras(&local, &remote)), vec!["a-b", "ab"]);
+
+        let local = tree(&["a-b"]);
+        let remote = tree(&["a/", "a/b", "a-b"]);
+        assert_eq!(paths(extras(&local, &remote)), vec!["a"]);
+    }
+
+    #[test]
+    fn inside_unreadable_dir() {
+        let mut local = tree(&["./", "a/", "b/", "c"]);
+        local[1].error = Some(Error::new("Permission denied"));
+        let remote = tree(&["./", "a/", "a/x", "a/y/", "b/", "b/x", "c", "d"]);
+        assert_eq!(paths(extras(&local, &remote)), vec!["b/x", "d"]);
+    }
+
+    #[test]
+    fn unreadable_root() {
+        let mut local = tree(&["./"]);
+        local[0].error = Some(Error::new("Permission denied"));
+        let remote = tree(&["./", "a/", "a/x", "b"]);
+        assert!(extras(&local, &remote).is_empty());
+    }
+
+    #[test]
+    fn file_reasons() {
+        assert_eq!(reason(&file(10, 1000), Some(&file(10, 1000)), false), None);
+        // Only whole seconds are compared
+        assert_eq!(reason(&file(10, 1000), Some(&file(10, 1999)), false), None);
+        assert_eq!(
+            reason(&file(10, 1000), Some(&file(10, 2000)), false),
+            Some(SyncReason::Mtime)
+        );
+        assert_eq!(
+            reason(&file(10, 1000), Some(&file(11, 1000)), false),
+            Some(SyncReason::Size)
+        );
+        assert_eq!(
+            reason(&file(10, 1000), Some(&file(11, 1000)), true),
+            Some(SyncReason::Size)
+        );
+        assert_eq!(
+            reason(&file(10, 1000), Some(&file(10, 2000)), true),
+            Some(SyncReason::Checksum)
+        );
+        assert_eq!(
+            reason(&file(10, 1000), None, false),
+            Some(SyncReason::Missing)
+        );
+        let mut unreadable = file(10, 1000);
+        unreadable.error = Some(Error::new("Permission denied"));
+        assert_eq!(
+            reason(&unreadable, Some(&file(10, 1000)), false),
+            Some(SyncReason::Unreadable)
+        );
+    }
+
+    #[test]
+    fn other_reasons() {
+        let dir = entry("d", EntryKind::Dir);
+        let link = |target: &str| entry("l", EntryKind::Link(String::from(target)));
+        assert_eq!(reason(&dir, Some(&dir), false), None);
+        assert_eq!(
+            reason(&dir, Some(&file(0, 0)), false),
+            Some(SyncReason::Type)
+        );
+        assert_eq!(reason(&link("a"), Some(&link("a")), false), None);
+        assert_eq!(
+            reason(&link("a"), Some(&link("b")), false),
+            Some(SyncReason::Target)
+        );
+        assert_eq!(
+            reason(&link("a"), Some(&dir), false),
+            Some(SyncReason::Type)
+        );
+    }
+
+    #[test]
+    fn dry_run_plan() {
+        let local = tree(&["./", "a/", "a/b", "c", "d/"]);
+        let remote = tree(&["./", "a/", "a/b", "a/x", "d"]);
+        let extras = extras(&local, &remote);
+        let reasons = vec![
+            None,
+            None,
+            None,
+            Some(SyncReason::Missing),
+            Some(SyncReason::Type),
+        ];
+        let plan = plan(local, reasons, &extras);
+        let actions: Vec<(&str, SyncActionKind, SyncReason)> = plan
+            .actions
+            .iter()
+            .map(|a| (a.path.as_str(), a.action, a.reason))
+            .collect();
+        assert_eq!(
+            actions,
+            vec![
+                ("a/x", SyncActionKind::Delete, SyncReason::Extra),
+                ("c", SyncActionKind::Upload, SyncReason::Missing),
+                ("d", SyncActionKind::Upload, SyncReason::Type),
+            ]
+        );
+        assert_eq!(plan.unchanged, 1);
+        assert_eq!(plan.pending.len(), 2);
+        assert!(plan.replaced.contains("d"));
+        assert!(!plan.replaced.contains("c"));
+    }
+}
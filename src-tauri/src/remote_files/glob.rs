@@ -0,0 +1,263 @@
+use regex::Regex;
+
+use crate::error::Error;
+
+/// Picks the entries of a directory tree by their path relative to its root, with `/` as the
+/// separator.
+///
+/// Patterns support `*`, `?`, `[...]` and `**` spanning directories. Like in `.gitignore`, a
+/// pattern without `/` other than a trailing one matches the name at any depth, and one with a
+/// trailing `/` only matches directories. Including a directory includes everything in it.
+#[derive(Debug, Default)]
+pub(crate) struct PathFilter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+#[derive(Debug)]
+struct Pattern {
+    regex: Regex,
+    /// Set for patterns with a trailing `/`
+    dir_only: bool,
+}
+
+impl PathFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<PathFilter, Error> {
+        return Ok(PathFilter {
+            include: include
+                .iter()
+                .map(|p| Pattern::new(p))
+                .collect::<Result<_, _>>()?,
+            exclude: exclude
+                .iter()
+                .map(|p| Pattern::new(p))
+                .collect::<Result<_, _>>()?,
+        });
+    }
+
+    /// Files need to match an include pattern, or be in a directory that does, if there's any
+    /// include pattern. They also need to match no exclude pattern.
+    pub fn file(&self, path: &str) -> bool {
+        if self.exclude.iter().any(|p| p.file(path)) {
+            return false;
+        }
+        if self.include.is_empty() {
+            return true;
+        }
+        let mut dirs = path.match_indices('/').map(|(i, _)| &path[..i]);
+        return self.include.iter().any(|p| p.file(path))
+            || dirs.any(|dir| self.include.iter().any(|p| p.regex.is_match(dir)));
+    }
+
+    pub fn has_include(&self) -> bool {
+        return !self.include.is_empty();
+    }
+
+    /// Directories are walked unless they match an exclude pattern.
+    pub fn dir(&self, path: &str) -> bool {
+        return !self.exclude.iter().any(|p| p.regex.is_match(path));
+    }
+}
+
+impl Pattern {
+    fn new(pattern: &str) -> Result<Pattern, Error> {
+        return Ok(Pattern {
+            regex: glob_regex(pattern)?,
+            dir_only: pattern.ends_with('/'),
+        });
+    }
+
+    fn file(&self, path: &str) -> bool {
+        return !self.dir_only && self.regex.is_match(path);
+    }
+}
+
+fn glob_regex(pattern: &str) -> Result<Regex, Error> {
+    let pattern = pattern.trim_end_matches('/');
+    let mut regex = String::from(if pattern.contains('/') {
+        "^"
+    } else {
+        "(?:^|/)"
+    });
+    let mut chars = pattern.trim_start_matches('/').chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                let negated = chars.next_if_eq(&'!').is_some();
+                let mut members = Vec::<char>::new();
+                // A leading `]` is a member rather than the end of the class
+                if let Some(c) = chars.next_if_eq(&']') {
+                    members.push(c);
+                }
+                loop {
+                    match chars.next() {
+                        Some(']') => break,
+                        Some(c) => members.push(c),
+                        None => return Err(Error::new(format!("Unclosed [ in {pattern}"))),
+                    }
+                }
+                // Like `?`, classes never match the separator
+                if negated {
+                    regex.push_str("[^/");
+                    regex.push_str(&class_members(&members));
+                    regex.push(']');
+                } else {
+                    regex.push('[');
+                    regex.push_str(&class_members(&members));
+                    regex.push_str("&&[^/]]");
+                }
+            }
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    return Regex::new(&regex).map_err(|e| Error::new(format!("Bad pattern {pattern}: {e}")));
+}
+
+/// Escapes the members of a `[...]` class for the regex, keeping `-` between two of them as a
+/// range.
+fn class_members(members: &[char]) -> String {
+    let mut class = String::new();
+    // Whether the last member was a range operator, or the end of a range
+    let mut ranging = false;
+    let mut ended = true;
+    for (i, &c) in members.iter().enumerate() {
+        if c == '-' && !ranging && !ended && i + 1 < members.len() {
+            class.push('-');
+            ranging = true;
+            continue;
+        }
+        ended = ranging;
+        ranging = false;
+        if "\\[]&~-^".contains(c) {
+            class.push('\\');
+        }
+        class.push(c);
+    }
+    return class;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::remote_files::glob::{glob_regex, PathFilter};
+
+    fn matches(pattern: &str, path: &str) -> bool {
+        return glob_regex(pattern).unwrap().is_match(path);
+    }
+
+    #[test]
+    fn unanchored() {
+        assert!(matches("*.log", "app.log"));
+        assert!(matches("*.log", "var/log/app.log"));
+        assert!(!matches("*.log", "app.log.1"));
+        assert!(matches("node_modules", "a/b/node_modules"));
+        assert!(!matches("node_modules", "a/node_modules_old"));
+    }
+
+    #[test]
+    fn anchored() {
+        assert!(matches("src/*.rs", "src/main.rs"));
+        assert!(!matches("src/*.rs", "lib/src/main.rs"));
+        assert!(!matches("src/*.rs", "src/bin/main.rs"));
+        assert!(matches("/build", "build"));
+        assert!(!matches("/build", "sub/build"));
+    }
+
+    #[test]
+    fn double_star() {
+        assert!(matches("**/*.ipk", "app.ipk"));
+        assert!(matches("**/*.ipk", "out/deep/app.ipk"));
+        assert!(matches("src/**/test.js", "src/test.js"));
+        assert!(matches("src/**/test.js", "src/a/b/test.js"));
+        assert!(!matches("src/**/test.js", "lib/src/a/test.js"));
+        assert!(matches("src/**", "src/a/b"));
+    }
+
+    #[test]
+    fn single_char() {
+        assert!(matches("file?.txt", "file1.txt"));
+        assert!(!matches("file?.txt", "file10.txt"));
+        assert!(!matches("a?b", "a/b"));
+    }
+
+    #[test]
+    fn char_class() {
+        assert!(matches("[abc].txt", "b.txt"));
+        assert!(!matches("[abc].txt", "d.txt"));
+        assert!(matches("[!abc].txt", "d.txt"));
+        assert!(!matches("[!abc].txt", "a.txt"));
+        assert!(matches("v[0-9]", "v7"));
+        assert!(glob_regex("[abc").is_err());
+    }
+
+    #[test]
+    fn char_class_separator() {
+        assert!(!matches("a[!b]c", "a/c"));
+        assert!(!matches("a[./]c", "a/c"));
+        assert!(matches("a[./]c", "a.c"));
+    }
+
+    #[test]
+    fn char_class_literals() {
+        assert!(matches("[[]", "["));
+        assert!(matches("[]a]", "]"));
+        assert!(matches("[!]]", "a"));
+        assert!(!matches("[!]]", "]"));
+        assert!(matches("[&&x]", "&"));
+        assert!(!matches("[&&x]", "y"));
+        assert!(matches("[~~]", "~"));
+        assert!(matches("[a-]", "-"));
+        assert!(matches("[-a]", "-"));
+        assert!(matches("[a-c-e]", "-"));
+        assert!(!matches("[a-c-e]", "d"));
+        assert!(matches("[--0]", "."));
+        assert!(matches("[^]", "^"));
+        assert!(matches("[\\]", "\\"));
+    }
+
+    #[test]
+    fn trailing_slash() {
+        assert!(matches("cache/", "cache"));
+        assert!(matches("cache/", "a/cache"));
+        assert!(matches("out/cache/", "out/cache"));
+        assert!(!matches("out/cache/", "a/out/cache"));
+    }
+
+    #[test]
+    fn include_dir() {
+        let filter = PathFilter::new(&[String::from("cache/")], &[]).unwrap();
+        assert!(filter.file("cache/a.bin"));
+        assert!(filter.file("x/cache/sub/a.bin"));
+        assert!(!filter.file("cache"));
+        assert!(!filter.file("cached/a.bin"));
+        let filter = PathFilter::new(&[String::from("src")], &[]).unwrap();
+        assert!(filter.file("src"));
+        assert!(filter.file("src/main.rs"));
+    }
+
+    #[test]
+    fn exclude_dir_only() {
+        let filter = PathFilter::new(&[], &[String::from("cache/")]).unwrap();
+        assert!(filter.file("cache"));
+        assert!(!filter.dir("cache"));
+        assert!(filter.dir("cached"));
+    }
+
+    #[test]
+    fn escaped_literals() {
+        assert!(matches("a.b", "a.b"));
+        assert!(!matches("a.b", "axb"));
+        assert!(matches("(x)+", "(x)+"));
+    }
+}
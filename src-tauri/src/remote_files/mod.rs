@@ -1,7 +1,15 @@
 use serde::{Deserialize, Serialize};
 
+use crate::error::Error;
+
+pub(crate) mod dir_transfer;
+mod glob;
+pub(crate) mod ops;
 pub(crate) mod serve;
 mod sftp;
+pub(crate) mod stream;
+pub(crate) mod transfer;
+pub(crate) mod transfer_channel;
 
 #[derive(Serialize, Clone, Debug)]
 pub struct FileItem {
@@ -20,6 +28,125 @@ pub struct FileItem {
 pub struct LinkInfo {
     target: Option<String>,
     broken: Option<bool>,
+    /// Type of what the link points to, as in [FileItem], unless broken
+    r#type: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TransferStats {
+    pub bytes: u64,
+    #[serde(rename = "elapsedMs")]
+    pub elapsed_ms: u64,
+    pub window: usize,
+    /// Connections the file was transferred over, fewer than `window` if the pool was busy
+    pub connections: usize,
+}
+
+/// Which entries of a directory tree to copy.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct TreeOptions {
+    /// Copy what symbolic links point to, instead of the links themselves
+    #[serde(rename = "followLinks", default)]
+    pub follow_links: bool,
+    /// Glob patterns of files to copy, all of them if empty
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Glob patterns of files and directories to skip
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct DirTransferResult {
+    pub files: Vec<EntryResult>,
+    pub bytes: u64,
+    #[serde(rename = "elapsedMs")]
+    pub elapsed_ms: u64,
+    /// Entries not copied, which have an `error`
+    pub failed: usize,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct SyncOptions {
+    #[serde(flatten)]
+    pub tree: TreeOptions,
+    /// Compare files of the same size by SHA-256, instead of by mtime
+    #[serde(default)]
+    pub checksum: bool,
+    /// Delete remote entries that aren't in the local tree, other than in unreadable directories
+    #[serde(default)]
+    pub delete: bool,
+    /// Only tell what would be done
+    #[serde(rename = "dryRun", default)]
+    pub dry_run: bool,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SyncResult {
+    /// What was done, or would be for a dry run
+    pub actions: Vec<SyncAction>,
+    #[serde(rename = "dryRun")]
+    pub dry_run: bool,
+    /// Files already up to date
+    pub unchanged: usize,
+    /// Outcome of the actions, empty for a dry run
+    #[serde(flatten)]
+    pub transfer: DirTransferResult,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SyncAction {
+    pub path: String,
+    pub r#type: char,
+    pub action: SyncActionKind,
+    pub reason: SyncReason,
+    /// Size of the file to upload
+    pub bytes: u64,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncActionKind {
+    Upload,
+    Delete,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncReason {
+    /// Not on the device yet
+    Missing,
+    /// A file on one side, and a directory or a link on the other
+    Type,
+    Size,
+    Mtime,
+    Checksum,
+    /// Links pointing elsewhere
+    Target,
+    /// Couldn't be read locally, so it's reported as failed
+    Unreadable,
+    /// Not in the local tree
+    Extra,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct EntryResult {
+    /// Relative to the directory copied, which itself is `.`
+    pub path: String,
+    pub r#type: char,
+    pub bytes: u64,
+    pub error: Option<Error>,
+}
+
+impl EntryResult {
+    pub fn new(path: &str, r#type: char, bytes: u64, error: Option<Error>) -> Self {
+        return EntryResult {
+            path: String::from(path),
+            r#type,
+            bytes,
+            error,
+        };
+    }
 }
 
 #[derive(Serialize, Clone, Debug)]
@@ -0,0 +1,136 @@
+use std::io::ErrorKind;
+
+use libssh_rs::{FileType, Metadata, SetAttributes, Sftp};
+
+use crate::error::Error;
+
+/// Removes a file, symbolic link or empty directory, or with `recursive`, a directory and
+/// everything in it.
+pub(crate) fn remove(sftp: &Sftp, path: &str, recursive: bool) -> Result<(), Error> {
+    if path.trim_end_matches('/').is_empty() {
+        return Err(Error::new("Refusing to remove /"));
+    }
+    let stat = sftp.symlink_metadata(path)?;
+    if !is_dir(&stat) {
+        sftp.remove_file(path)
+            .map_err(|e| explain(sftp, e.into(), path, None))?;
+        return Ok(());
+    }
+    if !recursive {
+        return sftp.remove_dir(path).map_err(|e| {
+            let has_entries = sftp.read_dir(path).is_ok_and(|children| {
+                children
+                    .iter()
+                    .any(|child| !matches!(child.name(), Some(".") | Some("..") | None))
+            });
+            if has_entries {
+                return Error::DirectoryNotEmpty {
+                    message: format!("{path} is not empty"),
+                };
+            }
+            return explain(sftp, e.into(), path, None);
+        });
+    }
+    for child in sftp.read_dir(path)? {
+        let name = match child.name() {
+            Some(".") | Some("..") | None => continue,
+            Some(name) => name,
+        };
+        let child_path = format!("{}/{}", path.trim_end_matches('/'), name);
+        if is_dir(&child) {
+            remove(sftp, &child_path, true)?;
+        } else {
+            sftp.remove_file(&child_path)
+                .map_err(|e| explain(sftp, e.into(), &child_path, None))?;
+        }
+    }
+    sftp.remove_dir(path)
+        .map_err(|e| explain(sftp, e.into(), path, None))?;
+    return Ok(());
+}
+
+/// Creates a directory, and with `parents`, the missing ones above it, like `mkdir -p`.
+pub(crate) fn make_dir(sftp: &Sftp, path: &str, mode: u32, parents: bool) -> Result<(), Error> {
+    if parents && parent(path) != "/" && sftp.metadata(parent(path)).is_err() {
+        make_dir(sftp, parent(path), mode, true)?;
+    }
+    if let Err(e) = sftp.create_dir(path, mode as _) {
+        if parents && sftp.metadata(path).is_ok_and(|stat| is_dir(&stat)) {
+            return Ok(());
+        }
+        return Err(explain(sftp, e.into(), parent(path), Some(path)));
+    }
+    return Ok(());
+}
+
+pub(crate) fn rename(sftp: &Sftp, from: &str, to: &str) -> Result<(), Error> {
+    sftp.rename(from, to)
+        .map_err(|e| explain(sftp, e.into(), from, Some(to)))?;
+    return Ok(());
+}
+
+pub(crate) fn chmod(sftp: &Sftp, path: &str, mode: u32) -> Result<(), Error> {
+    let attrs = SetAttributes {
+        size: None,
+        uid_gid: None,
+        permissions: Some(mode & 0o7777),
+        atime_mtime: None,
+    };
+    sftp.set_metadata(path, &attrs)
+        .map_err(|e| explain(sftp, e.into(), path, None))?;
+    return Ok(());
+}
+
+pub(crate) fn chown(sftp: &Sftp, path: &str, uid: u32, gid: u32) -> Result<(), Error> {
+    let attrs = SetAttributes {
+        size: None,
+        uid_gid: Some((uid, gid)),
+        permissions: None,
+        atime_mtime: None,
+    };
+    sftp.set_metadata(path, &attrs)
+        .map_err(|e| explain(sftp, e.into(), path, None))?;
+    return Ok(());
+}
+
+/// Creates a symbolic link at `path` pointing to `target`.
+pub(crate) fn symlink(sftp: &Sftp, target: &str, path: &str) -> Result<(), Error> {
+    sftp.symlink(target, path)
+        .map_err(|e| explain(sftp, e.into(), parent(path), Some(path)))?;
+    return Ok(());
+}
+
+/// Servers speaking SFTP v3 report most failures as a bare `SSH_FX_FAILURE`, so this looks at
+/// the paths involved to tell the usual causes apart: `path` should exist, and `created` not.
+///
+/// The failure is only replaced when a stat confirms the cause, any other outcome of the stat
+/// leaves it as it was.
+fn explain(sftp: &Sftp, error: Error, path: &str, created: Option<&str>) -> Error {
+    if !matches!(error, Error::Message { .. }) {
+        return error;
+    }
+    if created.is_some_and(|created| sftp.symlink_metadata(created).is_ok()) {
+        return Error::io(ErrorKind::AlreadyExists);
+    }
+    let stat = sftp.symlink_metadata(path).map_err(Error::from);
+    if let Err(Error::IO {
+        code: ErrorKind::NotFound,
+        ..
+    }) = stat
+    {
+        return Error::io(ErrorKind::NotFound);
+    }
+    return error;
+}
+
+fn parent(path: &str) -> &str {
+    return match path.trim_end_matches('/').rsplit_once('/') {
+        Some(("", _)) => "/",
+        Some((parent, _)) => parent,
+        None => ".",
+    };
+}
+
+fn is_dir(stat: &Metadata) -> bool {
+    return matches!(stat.file_type(), Some(FileType::Directory));
+}
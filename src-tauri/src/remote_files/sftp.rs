@@ -1,8 +1,9 @@
 use std::time::UNIX_EPOCH;
 
 use crate::conn_pool::DeviceConnectionUserInfo;
-use libssh_rs::{FileType, Metadata};
+use libssh_rs::{FileType, Metadata, Sftp};
 
+use crate::error::Error;
 use crate::remote_files::{FileItem, LinkInfo, PermInfo};
 
 impl From<&Metadata> for FileItem {
@@ -12,9 +13,61 @@ impl From<&Metadata> for FileItem {
 }
 
 impl FileItem {
+    /// Lists a directory, telling where symbolic links in it point to.
+    pub(crate) fn list(
+        sftp: &Sftp,
+        dir: &str,
+        user: Option<&DeviceConnectionUserInfo>,
+    ) -> Result<Vec<FileItem>, Error> {
+        return Ok(sftp
+            .read_dir(dir)?
+            .iter()
+            .filter(|entry| entry.name() != Some(".") && entry.name() != Some(".."))
+            .map(|entry| {
+                let path = format!("{}/{}", dir.trim_end_matches('/'), entry.name().unwrap());
+                return FileItem::inspect(sftp, entry, &path, user);
+            })
+            .collect());
+    }
+
+    /// A single file, as [FileItem::list] would show it.
+    pub(crate) fn stat(
+        sftp: &Sftp,
+        path: &str,
+        user: Option<&DeviceConnectionUserInfo>,
+    ) -> Result<FileItem, Error> {
+        let stat = sftp.symlink_metadata(path)?;
+        let mut item = FileItem::inspect(sftp, &stat, path, user);
+        if stat.name().is_none() {
+            item.filename = match path.trim_end_matches('/').rsplit_once('/') {
+                Some((_, name)) if !name.is_empty() => String::from(name),
+                Some(_) => String::from("/"),
+                None => String::from(path),
+            };
+        }
+        return Ok(item);
+    }
+
+    /// Permissions of symbolic links are those of their targets, as that's what access checks.
+    fn inspect(
+        sftp: &Sftp,
+        stat: &Metadata,
+        path: &str,
+        user: Option<&DeviceConnectionUserInfo>,
+    ) -> FileItem {
+        let (link, target) = if let Some(FileType::Symlink) = stat.file_type() {
+            let (link, target) = LinkInfo::read(sftp, path);
+            (Some(link), target)
+        } else {
+            (None, None)
+        };
+        let access = user.map(|u| PermInfo::from(target.as_ref().unwrap_or(stat), u));
+        return FileItem::new(stat, link, access);
+    }
+
     pub(crate) fn new(stat: &Metadata, link: Option<LinkInfo>, access: Option<PermInfo>) -> Self {
         return FileItem {
-            filename: String::from(stat.name().unwrap()),
+            filename: stat.name().map(String::from).unwrap_or_default(),
             r#type: format!(
                 "{}",
                 abbrev_type(stat.file_type().unwrap_or(FileType::Unknown))
@@ -35,10 +88,35 @@ impl FileItem {
     }
 }
 
+impl LinkInfo {
+    /// Where the symbolic link at `path` points to, along with the stat of its target if that
+    /// exists.
+    fn read(sftp: &Sftp, path: &str) -> (LinkInfo, Option<Metadata>) {
+        let target = sftp.metadata(path).ok();
+        let link = LinkInfo {
+            target: sftp.read_link(path).ok(),
+            broken: Some(target.is_none()),
+            r#type: target
+                .as_ref()
+                .map(|t| abbrev_type(t.file_type().unwrap_or(FileType::Unknown)).to_string()),
+        };
+        return (link, target);
+    }
+}
+
 impl PermInfo {
     pub fn from(stat: &Metadata, user: &DeviceConnectionUserInfo) -> Self {
-        let perms = stat.permissions().unwrap_or(0);
-        if user.uid.id == stat.uid().unwrap_or(0) {
+        return PermInfo::new(
+            stat.permissions().unwrap_or(0),
+            stat.uid().unwrap_or(0),
+            stat.gid().unwrap_or(0),
+            user,
+        );
+    }
+
+    /// What `user` can do with a file of mode `perms` owned by `uid` and `gid`.
+    pub(crate) fn new(perms: u32, uid: u32, gid: u32, user: &DeviceConnectionUserInfo) -> Self {
+        if user.uid.id == uid {
             return PermInfo {
                 read: (perms & 0o400) != 0,
                 write: (perms & 0o200) != 0,
@@ -46,7 +124,7 @@ impl PermInfo {
             };
         }
         for group in &user.groups {
-            if group.id == stat.gid().unwrap_or(0) {
+            if group.id == gid {
                 return PermInfo {
                     read: (perms & 0o040) != 0,
                     write: (perms & 0o020) != 0,
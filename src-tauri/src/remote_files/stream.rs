@@ -0,0 +1,580 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{ErrorKind, Read, Write};
+use std::time::Duration;
+
+use libssh_rs::Channel;
+
+use crate::conn_pool::{DeviceConnection, DeviceConnectionUserInfo};
+use crate::error::Error;
+use crate::remote_files::{FileItem, LinkInfo, PermInfo};
+
+/// Shell function printing, for the file given, its lstat and name, then where it points to and
+/// the mode of that if it's a symbolic link, all NUL terminated.
+const STAT_FUNCTION: &str = r#"entry() {
+  printf '%s\0%s\0' "$(stat -c '%f %s %Y %u %g %U %G' -- "$1")" "$1"
+  if [ -L "$1" ]; then
+    printf '%s\0%s\0' "$(readlink -- "$1")" "$(stat -L -c '%f' -- "$1" 2>/dev/null)"
+  else
+    printf '\0\0'
+  fi
+}
+"#;
+
+/// How long reading one of stdout and stderr waits, before looking at the other.
+const DRAIN_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Entry of a tree listed by [tree].
+pub(crate) struct TreeItem {
+    /// Relative to the root of the tree, which itself is `.`
+    pub path: String,
+    /// As in [FileItem], or `None` if `stat` failed, like on a broken link to follow
+    pub r#type: Option<char>,
+    pub mode: u32,
+    pub size: u64,
+    pub mtime: u64,
+    /// Where a symbolic link points to
+    pub target: String,
+}
+
+/// Lists a directory with `stat`, for devices without SFTP.
+pub(crate) fn list(conn: &DeviceConnection, dir: &str) -> Result<Vec<FileItem>, Error> {
+    let script = format!(
+        r#"{STAT_FUNCTION}cd -- {} || exit 1
+for f in .* *; do
+  [ "$f" = . ] || [ "$f" = .. ] && continue
+  [ -e "$f" ] || [ -L "$f" ] || continue
+  entry "$f"
+done"#,
+        quote(dir)
+    );
+    return parse_entries(conn, &exec(conn, &script, None)?);
+}
+
+/// Like [FileItem::stat], for devices without SFTP.
+pub(crate) fn stat(conn: &DeviceConnection, path: &str) -> Result<FileItem, Error> {
+    let path = quote(path);
+    let script = format!(
+        r#"{STAT_FUNCTION}[ -e {path} ] || [ -L {path} ] || {{ echo {path}: No such file or directory >&2; exit 1; }}
+entry {path}"#
+    );
+    let mut item = parse_entries(conn, &exec(conn, &script, None)?)?
+        .pop()
+        .ok_or(Error::io(ErrorKind::NotFound))?;
+    if let Some((_, name)) = item.filename.trim_end_matches('/').rsplit_once('/') {
+        if !name.is_empty() {
+            item.filename = String::from(name);
+        }
+    }
+    return Ok(item);
+}
+
+/// Lists the tree at `root` with `find`, down to `max_depth` levels. What is in directories `find`
+/// can't read is left out, as are names with a line break.
+pub(crate) fn tree(
+    conn: &DeviceConnection,
+    root: &str,
+    follow_links: bool,
+    max_depth: usize,
+) -> Result<Vec<TreeItem>, Error> {
+    let follow = if follow_links { "-L" } else { "" };
+    let root = quote(root);
+    let script = format!(
+        r#"[ -e {root} ] || [ -L {root} ] || {{ echo {root}: No such file or directory >&2; exit 1; }}
+cd -- {root} || exit 1
+find {follow} . -maxdepth {max_depth} | while IFS= read -r f; do
+  printf '%s\0%s\0' "$f" "$(stat {follow} -c '%f %s %Y' -- "$f" 2>/dev/null)"
+  if [ -L "$f" ]; then printf '%s\0' "$(readlink -- "$f")"; else printf '\0'; fi
+done"#
+    );
+    return Ok(parse_tree(&exec(conn, &script, None)?));
+}
+
+/// Size of the file, following symbolic links.
+pub(crate) fn size(conn: &DeviceConnection, path: &str) -> Result<u64, Error> {
+    let output = exec(conn, &format!("stat -L -c '%s' -- {}", quote(path)), None)?;
+    return String::from_utf8_lossy(&output)
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| Error::io(ErrorKind::InvalidData));
+}
+
+pub(crate) fn read(conn: &DeviceConnection, path: &str) -> Result<Vec<u8>, Error> {
+    return exec(conn, &format!("cat -- {}", quote(path)), None);
+}
+
+pub(crate) fn write(conn: &DeviceConnection, path: &str, content: &[u8]) -> Result<(), Error> {
+    exec(conn, &format!("cat > {}", quote(path)), Some(content))?;
+    return Ok(());
+}
+
+/// Starts `cat` on the file, whose content is then read from the stdout of the channel. Call
+/// [finish] once done.
+pub(crate) fn reader(conn: &DeviceConnection, path: &str) -> Result<Channel, Error> {
+    let ch = spawn(conn, &format!("cat -- {}", quote(path)))?;
+    ch.send_eof()?;
+    return Ok(ch);
+}
+
+/// Starts `cat` writing to the file what's written to the stdin of the channel. Send EOF, then
+/// call [finish] once done.
+pub(crate) fn writer(conn: &DeviceConnection, path: &str) -> Result<Channel, Error> {
+    return spawn(conn, &format!("cat > {}", quote(path)));
+}
+
+/// Starts `tar` archiving the files at `paths` in `dir`, in that order, to the stdout of the
+/// channel. Symbolic links are followed. Call [finish] once done.
+pub(crate) fn tar_reader(
+    conn: &DeviceConnection,
+    dir: &str,
+    paths: &[String],
+) -> Result<Channel, Error> {
+    // The whole list is read before `tar` starts, so it can't block on writing the archive while
+    // we still write the list. GNU tar would take listed names starting with - for options.
+    let command = format!(
+        r#"cd -- {} || exit 1
+list="$(cat)"
+[ -n "$list" ] || exit 0
+printf '%s\n' "$list" | tar -chf - -T -"#,
+        quote(dir)
+    );
+    let ch = spawn(conn, &command)?;
+    let mut list = String::new();
+    for path in paths {
+        list.push_str(&format!("./{path}\n"));
+    }
+    ch.stdin().write_all(list.as_bytes())?;
+    ch.send_eof()?;
+    return Ok(ch);
+}
+
+/// Starts `tar` extracting into `dir`, which is created if missing, the archive written to the
+/// stdin of the channel. Owners aren't restored. Send EOF, then call [finish] once done.
+///
+/// Extract into a staging directory, then [move_staged] what came through whole.
+pub(crate) fn tar_writer(conn: &DeviceConnection, dir: &str) -> Result<Channel, Error> {
+    let command = format!(
+        "mkdir -p -- {dir} && cd -- {dir} && tar -xof -",
+        dir = quote(dir)
+    );
+    return spawn(conn, &command);
+}
+
+/// Moves `entries` extracted by [tar_writer] from `staging` to `dir`, then removes `staging`.
+///
+/// Entries are given as their path relative to both, whether they're a directory, and whether
+/// what's at their path in `dir` is removed first. Directories are created rather than moved, and
+/// must come before what's in them. Returns the entries that couldn't be moved.
+pub(crate) fn move_staged(
+    conn: &DeviceConnection,
+    staging: &str,
+    dir: &str,
+    entries: &[(&str, bool, bool)],
+) -> Result<HashSet<String>, Error> {
+    // Files aren't moved over directories, which `mv` would move them into
+    let script = format!(
+        r#"[ -d {staging} ] || exit 1
+while IFS= read -r line; do
+  f="${{line#?? }}"
+  t={dir}/"$f"
+  case "$line" in ?r*) rm -rf -- "$t" ;; esac
+  case "$line" in
+    d*) mkdir -p -- "$t" ;;
+    *) [ ! -d "$t" ] || [ -L "$t" ] && mkdir -p -- "$(dirname -- "$t")" && mv -f -- {staging}/"$f" "$t" ;;
+  esac || printf '%s\n' "$f"
+done
+rm -rf -- {staging}"#,
+        staging = quote(staging),
+        dir = quote(dir)
+    );
+    let mut list = String::new();
+    for (path, is_dir, replace) in entries {
+        let kind = if *is_dir { 'd' } else { 'f' };
+        let replace = if *replace { 'r' } else { '-' };
+        list.push_str(&format!("{kind}{replace} ./{path}\n"));
+    }
+    let output = exec(conn, &script, Some(list.as_bytes()))?;
+    return Ok(String::from_utf8_lossy(&output)
+        .lines()
+        .map(|line| String::from(line.trim_start_matches("./")))
+        .collect());
+}
+
+/// Waits for the command to exit, failing with what it printed to stderr if it didn't succeed.
+pub(crate) fn finish(ch: Channel) -> Result<(), Error> {
+    let mut stderr = Vec::<u8>::new();
+    ch.stderr().read_to_end(&mut stderr)?;
+    return exit_status(ch, stderr);
+}
+
+fn exit_status(ch: Channel, stderr: Vec<u8>) -> Result<(), Error> {
+    let exit_code = ch.get_exit_status();
+    ch.close()?;
+    return match exit_code {
+        Some(0) => Ok(()),
+        Some(exit_code) => Err(exit_error(exit_code, stderr)),
+        // Killed by a signal, which isn't success either
+        None => Err(Error::new(format!(
+            "Command exited without a status: {}",
+            String::from_utf8_lossy(&stderr).trim()
+        ))),
+    };
+}
+
+pub(crate) fn remove(conn: &DeviceConnection, path: &str, recursive: bool) -> Result<(), Error> {
+    if path.trim_end_matches('/').is_empty() {
+        return Err(Error::new("Refusing to remove /"));
+    }
+    let path = quote(path);
+    // `rm -d` is missing from older BusyBox
+    let command = if recursive {
+        format!("rm -r -- {path}")
+    } else {
+        format!("if [ -d {path} ] && [ ! -L {path} ]; then rmdir -- {path}; else rm -- {path}; fi")
+    };
+    exec(conn, &command, None)?;
+    return Ok(());
+}
+
+pub(crate) fn make_dir(
+    conn: &DeviceConnection,
+    path: &str,
+    mode: u32,
+    parents: bool,
+) -> Result<(), Error> {
+    let flags = if parents { "-p" } else { "" };
+    let command = format!("mkdir {flags} -m {:o} -- {}", mode & 0o7777, quote(path));
+    exec(conn, &command, None)?;
+    return Ok(());
+}
+
+pub(crate) fn rename(conn: &DeviceConnection, from: &str, to: &str) -> Result<(), Error> {
+    // Fails like SFTP would, instead of moving into `to` if it's a directory
+    let command = format!(
+        "[ -e {to} ] || [ -L {to} ] && {{ echo {to}: File exists >&2; exit 1; }}; mv -- {from} {to}",
+        from = quote(from),
+        to = quote(to)
+    );
+    exec(conn, &command, None)?;
+    return Ok(());
+}
+
+/// Moves `from` over `to`, keeping the permissions of the file it replaces.
+pub(crate) fn replace(conn: &DeviceConnection, from: &str, to: &str) -> Result<(), Error> {
+    let command = format!(
+        "{{ [ ! -e {to} ] || chmod \"$(stat -c %a -- {to})\" -- {from}; }} && mv -f -- {from} {to}",
+        from = quote(from),
+        to = quote(to)
+    );
+    exec(conn, &command, None)?;
+    return Ok(());
+}
+
+pub(crate) fn chmod(conn: &DeviceConnection, path: &str, mode: u32) -> Result<(), Error> {
+    let command = format!("chmod {:o} -- {}", mode & 0o7777, quote(path));
+    exec(conn, &command, None)?;
+    return Ok(());
+}
+
+pub(crate) fn set_mtime(conn: &DeviceConnection, path: &str, mtime: u64) -> Result<(), Error> {
+    let command = format!("touch -c -d @{mtime} -- {}", quote(path));
+    exec(conn, &command, None)?;
+    return Ok(());
+}
+
+pub(crate) fn chown(conn: &DeviceConnection, path: &str, uid: u32, gid: u32) -> Result<(), Error> {
+    exec(conn, &format!("chown {uid}:{gid} -- {}", quote(path)), None)?;
+    return Ok(());
+}
+
+pub(crate) fn symlink(conn: &DeviceConnection, target: &str, path: &str) -> Result<(), Error> {
+    let command = format!("ln -s -- {} {}", quote(target), quote(path));
+    exec(conn, &command, None)?;
+    return Ok(());
+}
+
+/// SHA-256 of files in `dir`, by their path relative to it, with `sha256sum`. Files are left out
+/// if it fails on any of the batch they're in.
+pub(crate) fn sha256sums(
+    conn: &DeviceConnection,
+    dir: &str,
+    paths: &[&str],
+) -> Result<HashMap<String, String>, Error> {
+    let mut sums = HashMap::<String, String>::new();
+    // Keeps the command line well below ARG_MAX
+    for chunk in paths.chunks(100) {
+        let files: Vec<String> = chunk.iter().map(|p| quote(p)).collect();
+        let command = format!("cd -- {} && sha256sum -- {}", quote(dir), files.join(" "));
+        let output = match exec(conn, &command, None) {
+            Ok(output) => output,
+            Err(e @ Error::ExitStatus { .. }) | Err(e @ Error::IO { .. }) => {
+                log::warn!("sha256sum failed in {}: {:?}", dir, e);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        for line in String::from_utf8_lossy(&output).lines() {
+            if let Some((sum, path)) = line.split_once("  ") {
+                sums.insert(String::from(path), String::from(sum));
+            }
+        }
+    }
+    return Ok(sums);
+}
+
+/// Runs `command`, feeding it `stdin`, and returns its stdout if it succeeds.
+fn exec(conn: &DeviceConnection, command: &str, stdin: Option<&[u8]>) -> Result<Vec<u8>, Error> {
+    let ch = spawn(conn, command)?;
+    if let Some(stdin) = stdin {
+        ch.stdin().write_all(stdin)?;
+    }
+    ch.send_eof()?;
+    let (stdout, stderr) = drain(&ch)?;
+    exit_status(ch, stderr)?;
+    return Ok(stdout);
+}
+
+/// Reads stdout and stderr of the command until it closes them, taking turns so that it can't
+/// block writing to one while the other is read.
+fn drain(ch: &Channel) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let mut stdout = Vec::<u8>::new();
+    let mut stderr = Vec::<u8>::new();
+    let mut buf = [0u8; 8192];
+    loop {
+        // Checked first, as reading may take in what was sent before the EOF
+        let eof = ch.is_eof() || ch.is_closed();
+        let out = ch.read_timeout(&mut buf, false, Some(DRAIN_INTERVAL))?;
+        stdout.extend_from_slice(&buf[..out]);
+        let err = ch.read_timeout(&mut buf, true, Some(DRAIN_INTERVAL))?;
+        stderr.extend_from_slice(&buf[..err]);
+        if eof && out == 0 && err == 0 {
+            return Ok((stdout, stderr));
+        }
+    }
+}
+
+fn spawn(conn: &DeviceConnection, command: &str) -> Result<Channel, Error> {
+    let ch = conn.new_channel()?;
+    ch.open_session()?;
+    ch.request_exec(command)?;
+    return Ok(ch);
+}
+
+fn parse_entries(conn: &DeviceConnection, output: &[u8]) -> Result<Vec<FileItem>, Error> {
+    let output = String::from_utf8_lossy(output);
+    let fields: Vec<&str> = output.split('\0').collect();
+    let mut items = Vec::<FileItem>::new();
+    for entry in fields.chunks_exact(4) {
+        let [stat, name, target, target_mode] = entry else {
+            continue;
+        };
+        let Some(item) = parse_stat(conn.user.as_ref(), stat, name, target, target_mode) else {
+            log::warn!("Unexpected stat output for {}: {}", name, stat);
+            continue;
+        };
+        items.push(item);
+    }
+    return Ok(items);
+}
+
+/// Parses the output of [STAT_FUNCTION], which `stat` formatted as `%f %s %Y %u %g %U %G`.
+fn parse_stat(
+    user: Option<&DeviceConnectionUserInfo>,
+    stat: &str,
+    name: &str,
+    target: &str,
+    target_mode: &str,
+) -> Option<FileItem> {
+    let fields: Vec<&str> = stat.split(' ').collect();
+    let [mode, size, mtime, uid, gid, uname, gname] = fields.as_slice() else {
+        return None;
+    };
+    let mode = u32::from_str_radix(mode, 16).ok()?;
+    let uid = uid.parse::<u32>().ok()?;
+    let gid = gid.parse::<u32>().ok()?;
+    let target_mode = u32::from_str_radix(target_mode, 16).ok();
+    let link = (abbrev_mode(mode) == 'l').then(|| LinkInfo {
+        target: Some(String::from(target)).filter(|t| !t.is_empty()),
+        broken: Some(target_mode.is_none()),
+        r#type: target_mode.map(|m| abbrev_mode(m).to_string()),
+    });
+    let access = user.map(|u| PermInfo::new(target_mode.unwrap_or(mode), uid, gid, u));
+    return Some(FileItem {
+        filename: String::from(name),
+        r#type: abbrev_mode(mode).to_string(),
+        mode: unix_mode::to_string(mode),
+        user: Some(String::from(*uname)).filter(|u| u != "UNKNOWN"),
+        group: Some(String::from(*gname)).filter(|g| g != "UNKNOWN"),
+        size: size.parse::<usize>().ok()?,
+        mtime: mtime.parse::<f64>().ok()?,
+        link,
+        access,
+    });
+}
+
+/// Parses the output of [tree], where `stat` formatted the entries as `%f %s %Y`.
+fn parse_tree(output: &[u8]) -> Vec<TreeItem> {
+    let output = String::from_utf8_lossy(output);
+    let fields: Vec<&str> = output.split('\0').collect();
+    let mut items = Vec::<TreeItem>::new();
+    for entry in fields.chunks_exact(3) {
+        let [path, stat, target] = entry else {
+            continue;
+        };
+        let path = match path.strip_prefix("./") {
+            Some(path) => path,
+            None if *path == "." => path,
+            None => continue,
+        };
+        let stat: Option<(u32, u64, u64)> = match stat.split(' ').collect::<Vec<&str>>()[..] {
+            [mode, size, mtime] => u32::from_str_radix(mode, 16)
+                .ok()
+                .zip(size.parse::<u64>().ok())
+                .zip(mtime.parse::<u64>().ok())
+                .map(|((mode, size), mtime)| (mode, size, mtime)),
+            _ => None,
+        };
+        let (mode, size, mtime) = stat.unwrap_or_default();
+        items.push(TreeItem {
+            path: String::from(path),
+            r#type: stat.map(|_| abbrev_mode(mode)),
+            mode: mode & 0o7777,
+            size,
+            mtime,
+            target: String::from(*target),
+        });
+    }
+    return items;
+}
+
+fn abbrev_mode(mode: u32) -> char {
+    return match mode & 0o170000 {
+        0o040000 => 'd',
+        0o100000 => '-',
+        0o120000 => 'l',
+        0o020000 => 'c',
+        0o060000 => 'b',
+        0o010000 => 'p',
+        0o140000 => 's',
+        _ => ' ',
+    };
+}
+
+/// Guesses the IO error from the message `cat` and friends print, which is the same for GNU and
+/// BusyBox.
+fn exit_error(exit_code: i32, stderr: Vec<u8>) -> Error {
+    let message = String::from_utf8_lossy(&stderr).trim().to_string();
+    if message.contains("Directory not empty") {
+        return Error::DirectoryNotEmpty { message };
+    }
+    for (pattern, code) in [
+        ("No such file or directory", ErrorKind::NotFound),
+        ("Permission denied", ErrorKind::PermissionDenied),
+        ("File exists", ErrorKind::AlreadyExists),
+    ] {
+        if message.contains(pattern) {
+            return Error::IO { code, message };
+        }
+    }
+    return Error::ExitStatus {
+        message,
+        exit_code,
+        stderr,
+    };
+}
+
+fn quote(s: &str) -> String {
+    return format!("'{}'", s.replace('\'', "'\\''"));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conn_pool::{DeviceConnectionUserInfo, Id};
+    use crate::remote_files::stream::{parse_stat, parse_tree};
+
+    fn user(uid: u32, groups: &[u32]) -> DeviceConnectionUserInfo {
+        let id = |id: u32| Id { id, name: None };
+        return DeviceConnectionUserInfo {
+            uid: id(uid),
+            gid: id(groups[0]),
+            groups: groups.iter().map(|g| id(*g)).collect(),
+        };
+    }
+
+    #[test]
+    fn regular_file() {
+        let item = parse_stat(None, "81a4 123 1700000000 0 0 root root", "a.txt", "", "").unwrap();
+        assert_eq!(item.filename, "a.txt");
+        assert_eq!(item.r#type, "-");
+        assert_eq!(item.mode, "-rw-r--r--");
+        assert_eq!(item.size, 123);
+        assert_eq!(item.mtime, 1700000000.0);
+        assert_eq!(item.user.as_deref(), Some("root"));
+        assert_eq!(item.group.as_deref(), Some("root"));
+        assert!(item.link.is_none());
+        assert!(item.access.is_none());
+    }
+
+    #[test]
+    fn directory_access() {
+        let stat = "41ed 4096 1700000000 1000 1000 prisoner prisoner";
+        let owner = parse_stat(Some(&user(1000, &[1000])), stat, "d", "", "").unwrap();
+        assert_eq!(owner.r#type, "d");
+        let access = owner.access.unwrap();
+        assert!(access.read && access.write && access.execute);
+        let other = parse_stat(Some(&user(0, &[0])), stat, "d", "", "").unwrap();
+        let access = other.access.unwrap();
+        assert!(access.read && !access.write && access.execute);
+    }
+
+    #[test]
+    fn symlinks() {
+        let stat = "a1ff 7 1700000000 0 0 root root";
+        let link = parse_stat(None, stat, "ln", "/usr/bin", "41ed").unwrap();
+        assert_eq!(link.r#type, "l");
+        let info = link.link.unwrap();
+        assert_eq!(info.target.as_deref(), Some("/usr/bin"));
+        assert_eq!(info.broken, Some(false));
+        assert_eq!(info.r#type.as_deref(), Some("d"));
+
+        let broken = parse_stat(None, stat, "ln", "nowhere", "").unwrap();
+        let info = broken.link.unwrap();
+        assert_eq!(info.broken, Some(true));
+        assert!(info.r#type.is_none());
+    }
+
+    #[test]
+    fn unknown_owner() {
+        let item = parse_stat(None, "81a4 0 0 1234 1234 UNKNOWN UNKNOWN", "f", "", "").unwrap();
+        assert!(item.user.is_none());
+        assert!(item.group.is_none());
+    }
+
+    #[test]
+    fn malformed_stat() {
+        assert!(parse_stat(None, "", "f", "", "").is_none());
+        assert!(parse_stat(None, "81a4 0 0 0 0 root", "f", "", "").is_none());
+        assert!(parse_stat(None, "zz 0 0 0 0 root root", "f", "", "").is_none());
+        assert!(parse_stat(None, "81a4 -1 0 0 0 root root", "f", "", "").is_none());
+    }
+
+    #[test]
+    fn tree() {
+        let output = b".\x0041ed 4096 1700000000\x00\x00\
+./a b\x0081a4 5 1700000001\x00\x00\
+./ln\x00a1ff 5 1700000002\x00a b\x00\
+./broken\x00\x00nowhere\x00";
+        let items = parse_tree(output);
+        assert_eq!(items.len(), 4);
+        assert_eq!(items[0].path, ".");
+        assert_eq!(items[0].r#type, Some('d'));
+        assert_eq!(items[0].mode, 0o755);
+        assert_eq!(items[1].path, "a b");
+        assert_eq!(items[1].r#type, Some('-'));
+        assert_eq!(items[1].size, 5);
+        assert_eq!(items[1].mtime, 1700000001);
+        assert_eq!(items[2].r#type, Some('l'));
+        assert_eq!(items[2].target, "a b");
+        assert_eq!(items[3].path, "broken");
+        assert!(items[3].r#type.is_none());
+    }
+}
@@ -0,0 +1,379 @@
+use std::cmp::min;
+use std::fs::{remove_file, rename, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+use crate::conn_pool::ManagedDeviceConnection;
+use crate::device_manager::{Device, DeviceFileTransfer};
+use crate::error::Error;
+use crate::remote_files::{ops, stream, TransferStats};
+use crate::session_manager::SessionManager;
+
+const SEGMENT_SIZE: u64 = 1 << 20;
+const COPY_BUF_SIZE: usize = 32768;
+/// Connections other than the first that can't be had by then are done without.
+const EXTRA_CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Copies files over SFTP, splitting big ones into segments transferred in parallel over several
+/// pooled connections, so that more than one request is in flight at a time.
+///
+/// Devices without SFTP have files piped through `cat` instead, one segment at a time.
+pub(crate) struct Transfer<'a> {
+    sessions: &'a SessionManager,
+    device: Device,
+    window: usize,
+    progress: Option<&'a (dyn Fn(u64, u64) + Sync)>,
+    cancelled: Option<&'a AtomicBool>,
+    total: AtomicU64,
+    transferred: AtomicU64,
+}
+
+/// Hands out the segments of a file to the workers.
+struct Segments {
+    size: u64,
+    next: AtomicU64,
+    aborted: AtomicBool,
+}
+
+impl<'a> Transfer<'a> {
+    pub fn new(sessions: &'a SessionManager, device: Device, window: Option<usize>) -> Self {
+        // One segment per connection the pool holds by default, workers beyond the pool size
+        // would only wait for a connection, then give up
+        let pool_size = sessions.pool_size(device.clone()).max(1);
+        return Transfer {
+            sessions,
+            window: window.unwrap_or(pool_size).clamp(1, pool_size),
+            device,
+            progress: None,
+            cancelled: None,
+            total: AtomicU64::new(0),
+            transferred: AtomicU64::new(0),
+        };
+    }
+
+    /// Calls `progress` with the bytes transferred so far and the size of the file, after each
+    /// chunk.
+    pub fn progress(mut self, progress: &'a (dyn Fn(u64, u64) + Sync)) -> Self {
+        self.progress = Some(progress);
+        return self;
+    }
+
+    /// Stops the transfer with [Error::Cancelled] once `cancelled` is set, removing the partially
+    /// written file.
+    pub fn cancel_on(mut self, cancelled: &'a AtomicBool) -> Self {
+        self.cancelled = Some(cancelled);
+        return self;
+    }
+
+    /// Downloads to a temporary file next to `target`, which replaces `target` only once the
+    /// whole file is there.
+    pub fn download(&self, path: &str, target: &Path) -> Result<TransferStats, Error> {
+        let temp = download_temp(target)?;
+        let result = self.download_inner(path, &temp).and_then(|stats| {
+            rename(&temp, target)?;
+            return Ok(stats);
+        });
+        if let Err(e) = &result {
+            log::info!("Download of {} failed: {:?}, removing {:?}", path, e, temp);
+            remove_file(&temp).unwrap_or(());
+        }
+        return result;
+    }
+
+    /// Uploads to a temporary file next to `path`, which replaces `path` only once the whole
+    /// file is there.
+    pub fn upload(&self, source: &Path, path: &str) -> Result<TransferStats, Error> {
+        let temp = upload_temp(path);
+        let result = self.upload_inner(source, &temp).and_then(|stats| {
+            self.sessions.with_session(self.device.clone(), |session| {
+                return replace(session, &temp, path);
+            })?;
+            return Ok(stats);
+        });
+        if let Err(e) = &result {
+            log::info!("Upload to {} failed: {:?}, removing {}", path, e, temp);
+            self.sessions
+                .with_session(self.device.clone(), |session| {
+                    return match session.files() {
+                        DeviceFileTransfer::Stream => stream::remove(session, &temp, false),
+                        DeviceFileTransfer::Sftp => {
+                            session.with_sftp(|sftp| Ok(sftp.remove_file(&temp)?))
+                        }
+                    };
+                })
+                .unwrap_or_else(|e| log::warn!("Failed to remove {}: {:?}", temp, e));
+        }
+        return result;
+    }
+
+    fn download_inner(&self, path: &str, target: &Path) -> Result<TransferStats, Error> {
+        let started = Instant::now();
+        let files = self.files()?;
+        let size = self.sessions.with_session(self.device.clone(), |session| {
+            return match files {
+                DeviceFileTransfer::Stream => stream::size(session, path),
+                DeviceFileTransfer::Sftp => {
+                    session.with_sftp(|sftp| Ok(sftp.metadata(path)?.len().unwrap_or(0)))
+                }
+            };
+        })?;
+        self.total.store(size, Ordering::SeqCst);
+        if let DeviceFileTransfer::Stream = files {
+            self.sessions.with_session(self.device.clone(), |session| {
+                let ch = stream::reader(session, path)?;
+                self.transferred.store(0, Ordering::SeqCst);
+                let copied = self.copy(&mut ch.stdout(), &mut File::create(target)?)?;
+                stream::finish(ch)?;
+                if copied < size {
+                    return Err(Error::io(ErrorKind::UnexpectedEof));
+                }
+                return Ok(());
+            })?;
+            return Ok(self.stats(size, 1, started));
+        }
+        if !self.is_parallel(size) {
+            self.sessions.with_session(self.device.clone(), |session| {
+                return session.with_sftp(|sftp| {
+                    let mut sfile = sftp.open(path, 0, 0)?;
+                    self.transferred.store(0, Ordering::SeqCst);
+                    self.copy(&mut sfile, &mut File::create(target)?)?;
+                    return Ok(());
+                });
+            })?;
+            return Ok(self.stats(size, 1, started));
+        }
+        File::create(target)?.set_len(size)?;
+        let connections = self.parallel(size, |session, segments| {
+            return session.with_sftp(|sftp| {
+                let mut sfile = sftp.open(path, 0, 0)?;
+                let mut file = OpenOptions::new().write(true).open(target)?;
+                while let Some((offset, len)) = segments.take() {
+                    sfile.seek(SeekFrom::Start(offset))?;
+                    file.seek(SeekFrom::Start(offset))?;
+                    if self.copy(&mut Read::by_ref(&mut sfile).take(len), &mut file)? < len {
+                        return Err(Error::io(ErrorKind::UnexpectedEof));
+                    }
+                }
+                return Ok(());
+            });
+        })?;
+        return Ok(self.stats(size, connections, started));
+    }
+
+    fn upload_inner(&self, source: &Path, path: &str) -> Result<TransferStats, Error> {
+        let started = Instant::now();
+        let size = source.metadata()?.len();
+        self.total.store(size, Ordering::SeqCst);
+        if let DeviceFileTransfer::Stream = self.files()? {
+            self.sessions.with_session(self.device.clone(), |session| {
+                let ch = stream::writer(session, path)?;
+                self.transferred.store(0, Ordering::SeqCst);
+                let copied = self.copy(&mut File::open(source)?, &mut ch.stdin())?;
+                ch.send_eof()?;
+                stream::finish(ch)?;
+                if copied < size {
+                    return Err(Error::io(ErrorKind::UnexpectedEof));
+                }
+                return Ok(());
+            })?;
+            return Ok(self.stats(size, 1, started));
+        }
+        if !self.is_parallel(size) {
+            self.sessions.with_session(self.device.clone(), |session| {
+                return session.with_sftp(|sftp| {
+                    let mut sfile =
+                        sftp.open(path, 0x0301 /*O_WRONLY | O_CREAT | O_TRUNC*/, 0o644)?;
+                    self.transferred.store(0, Ordering::SeqCst);
+                    self.copy(&mut File::open(source)?, &mut sfile)?;
+                    return Ok(());
+                });
+            })?;
+            return Ok(self.stats(size, 1, started));
+        }
+        self.sessions.with_session(self.device.clone(), |session| {
+            return session.with_sftp(|sftp| {
+                sftp.open(path, 0x0301 /*O_WRONLY | O_CREAT | O_TRUNC*/, 0o644)?;
+                return Ok(());
+            });
+        })?;
+        let connections = self.parallel(size, |session, segments| {
+            return session.with_sftp(|sftp| {
+                let mut sfile = sftp.open(path, 1 /*O_WRONLY*/, 0o644)?;
+                let mut file = File::open(source)?;
+                while let Some((offset, len)) = segments.take() {
+                    sfile.seek(SeekFrom::Start(offset))?;
+                    file.seek(SeekFrom::Start(offset))?;
+                    if self.copy(&mut Read::by_ref(&mut file).take(len), &mut sfile)? < len {
+                        return Err(Error::io(ErrorKind::UnexpectedEof));
+                    }
+                }
+                return Ok(());
+            });
+        })?;
+        return Ok(self.stats(size, connections, started));
+    }
+
+    /// Like [std::io::copy], but reports progress and stops when cancelled.
+    fn copy<Rd: Read, W: Write>(&self, reader: &mut Rd, writer: &mut W) -> Result<u64, Error> {
+        let mut buf = vec![0u8; COPY_BUF_SIZE];
+        let mut copied = 0u64;
+        loop {
+            if self.cancelled.is_some_and(|c| c.load(Ordering::SeqCst)) {
+                return Err(Error::Cancelled);
+            }
+            let n = match reader.read(&mut buf) {
+                Ok(0) => return Ok(copied),
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            writer.write_all(&buf[..n])?;
+            copied += n as u64;
+            let transferred = self.transferred.fetch_add(n as u64, Ordering::SeqCst) + n as u64;
+            if let Some(progress) = self.progress {
+                progress(transferred, self.total.load(Ordering::SeqCst));
+            }
+        }
+    }
+
+    fn files(&self) -> Result<DeviceFileTransfer, Error> {
+        return self
+            .sessions
+            .with_session(self.device.clone(), |session| Ok(session.files()));
+    }
+
+    fn is_parallel(&self, size: u64) -> bool {
+        return self.window > 1 && size >= 2 * SEGMENT_SIZE;
+    }
+
+    /// Runs `worker` on up to `window` connections until all segments are copied. Returns the
+    /// number of connections used.
+    fn parallel<F>(&self, size: u64, worker: F) -> Result<usize, Error>
+    where
+        F: Fn(&ManagedDeviceConnection, &Segments) -> Result<(), Error> + Sync,
+    {
+        let segments = Segments {
+            size,
+            next: AtomicU64::new(0),
+            aborted: AtomicBool::new(false),
+        };
+        let workers = min(self.window as u64, (size + SEGMENT_SIZE - 1) / SEGMENT_SIZE) as usize;
+        let results: Vec<Result<bool, Error>> = thread::scope(|scope| {
+            let handles: Vec<_> = (0..workers)
+                .map(|i| {
+                    let (segments, worker) = (&segments, &worker);
+                    scope.spawn(move || {
+                        let session = if i == 0 {
+                            self.sessions.session(self.device.clone())
+                        } else {
+                            self.sessions
+                                .session_timeout(self.device.clone(), EXTRA_CONNECTION_TIMEOUT)
+                        };
+                        let result = match session {
+                            Ok(session) => worker(&session, segments).map(|_| {
+                                session.mark_last_ok();
+                                true
+                            }),
+                            Err(e) if i > 0 => {
+                                log::debug!("Transferring without worker {i}: {e:?}");
+                                Ok(false)
+                            }
+                            Err(e) => Err(e),
+                        };
+                        if result.is_err() {
+                            segments.aborted.store(true, Ordering::SeqCst);
+                        }
+                        return result;
+                    })
+                })
+                .collect();
+            return handles
+                .into_iter()
+                .map(|h| h.join().expect("critical failure in transfer worker"))
+                .collect();
+        });
+        let mut connections = 0;
+        for result in results {
+            if result? {
+                connections += 1;
+            }
+        }
+        return Ok(connections);
+    }
+
+    fn stats(&self, bytes: u64, connections: usize, started: Instant) -> TransferStats {
+        let elapsed = started.elapsed();
+        log::info!(
+            "Transferred {} bytes with {} in {:?} over {} connections, {:.0} KiB/s",
+            bytes,
+            self.device.name,
+            elapsed,
+            connections,
+            bytes as f64 / 1024.0 / elapsed.as_secs_f64().max(0.001)
+        );
+        return TransferStats {
+            bytes,
+            elapsed_ms: elapsed.as_millis() as u64,
+            window: self.window,
+            connections,
+        };
+    }
+}
+
+/// Hidden file in the same directory as `target`, so it can be renamed over it.
+pub(crate) fn download_temp(target: &Path) -> Result<PathBuf, Error> {
+    let file_name = target
+        .file_name()
+        .ok_or_else(|| Error::io(ErrorKind::InvalidInput))?;
+    return Ok(target.with_file_name(format!(
+        ".{}.{}.part",
+        file_name.to_string_lossy(),
+        Uuid::new_v4()
+    )));
+}
+
+/// Hidden file in the same directory as `path`, so it can be renamed over it.
+fn upload_temp(path: &str) -> String {
+    let id = Uuid::new_v4();
+    return match path.rsplit_once('/') {
+        Some((dir, name)) => format!("{dir}/.{name}.{id}.part"),
+        None => format!(".{path}.{id}.part"),
+    };
+}
+
+/// Moves the uploaded file over `path`, keeping the permissions of the file it replaces.
+fn replace(session: &ManagedDeviceConnection, temp: &str, path: &str) -> Result<(), Error> {
+    return match session.files() {
+        DeviceFileTransfer::Stream => stream::replace(session, temp, path),
+        DeviceFileTransfer::Sftp => session.with_sftp(|sftp| {
+            if let Some(mode) = sftp.metadata(path).ok().and_then(|m| m.permissions()) {
+                ops::chmod(sftp, temp, mode)?;
+            }
+            if sftp.rename(temp, path).is_ok() {
+                return Ok(());
+            }
+            // Servers without POSIX rename refuse to replace an existing file
+            sftp.remove_file(path).unwrap_or(());
+            return ops::rename(sftp, temp, path);
+        }),
+    };
+}
+
+impl Segments {
+    /// Offset and length of the next segment, or `None` when done or aborted.
+    fn take(&self) -> Option<(u64, u64)> {
+        if self.aborted.load(Ordering::SeqCst) {
+            return None;
+        }
+        let offset = self.next.fetch_add(SEGMENT_SIZE, Ordering::SeqCst);
+        if offset >= self.size {
+            return None;
+        }
+        return Some((offset, min(SEGMENT_SIZE, self.size - offset)));
+    }
+}
@@ -0,0 +1,216 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tauri::{AppHandle, Manager, Runtime};
+
+use crate::device_manager::Device;
+use crate::error::Error;
+use crate::event_channel::{EventChannel, EventHandler};
+use crate::remote_files::dir_transfer::DirTransfer;
+use crate::remote_files::transfer::Transfer;
+use crate::remote_files::{DirTransferResult, SyncOptions, SyncResult, TransferStats, TreeOptions};
+use crate::session_manager::SessionManager;
+
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+pub(crate) enum TransferJob {
+    Get {
+        path: String,
+        target: PathBuf,
+    },
+    Put {
+        source: PathBuf,
+        path: String,
+    },
+    GetDir {
+        path: String,
+        target: PathBuf,
+        options: TreeOptions,
+    },
+    PutDir {
+        source: PathBuf,
+        path: String,
+        options: TreeOptions,
+    },
+    Sync {
+        source: PathBuf,
+        path: String,
+        options: SyncOptions,
+    },
+}
+
+/// Runs the transfer in the background, returning the token of a channel that reports its
+/// progress.
+///
+/// The transfer starts once the frontend sends anything over the channel, and closing the channel
+/// cancels it. The channel is closed with [TransferResult], [DirTransferResult] for directories
+/// or [SyncResult], when done, or with the error.
+pub(crate) async fn exec<R: Runtime>(
+    app: AppHandle<R>,
+    device: Device,
+    job: TransferJob,
+    window: Option<usize>,
+) -> Result<String, Error> {
+    let channel = EventChannel::new(app.clone(), "");
+    channel.listen(TransferChannelHandler {
+        started_lock: Arc::new((Mutex::new(false), Condvar::new())),
+        cancelled: AtomicBool::new(false),
+    });
+    let token = channel.token();
+    tokio::task::spawn_blocking(move || transfer_worker(app, device, channel, job, window));
+    return Ok(token);
+}
+
+fn transfer_worker<R: Runtime>(
+    app: AppHandle<R>,
+    device: Device,
+    channel: EventChannel<R, TransferChannelHandler>,
+    job: TransferJob,
+    window: Option<usize>,
+) {
+    let Some(handler) = channel.handler.lock().unwrap().clone() else {
+        return;
+    };
+    handler.wait();
+    if handler.cancelled.load(Ordering::SeqCst) {
+        channel.closed(Error::Cancelled);
+        return;
+    }
+    let sessions = app.state::<SessionManager>();
+    let started = Instant::now();
+    let last_report = Mutex::new(Instant::now());
+    let progress = |transferred: u64, total: u64| {
+        let mut last_report = last_report.lock().unwrap();
+        if last_report.elapsed() < PROGRESS_INTERVAL && transferred < total {
+            return;
+        }
+        *last_report = Instant::now();
+        channel.rx(TransferProgress {
+            transferred,
+            total,
+            rate: (transferred as f64 / started.elapsed().as_secs_f64().max(0.001)) as u64,
+        });
+    };
+    let cancelled = &handler.cancelled;
+    let result = match job {
+        TransferJob::Get { path, target } => Transfer::new(&sessions, device, window)
+            .progress(&progress)
+            .cancel_on(cancelled)
+            .download(&path, &target)
+            .map(|stats| TransferOutcome::file(stats, &target)),
+        TransferJob::Put { source, path } => Transfer::new(&sessions, device, window)
+            .progress(&progress)
+            .cancel_on(cancelled)
+            .upload(&source, &path)
+            .map(|stats| TransferOutcome::file(stats, &source)),
+        TransferJob::GetDir {
+            path,
+            target,
+            options,
+        } => DirTransfer::new(&sessions, device, window, options).and_then(|transfer| {
+            return transfer
+                .progress(&progress)
+                .cancel_on(cancelled)
+                .download(&path, &target)
+                .map(TransferOutcome::Dir);
+        }),
+        TransferJob::PutDir {
+            source,
+            path,
+            options,
+        } => DirTransfer::new(&sessions, device, window, options).and_then(|transfer| {
+            return transfer
+                .progress(&progress)
+                .cancel_on(cancelled)
+                .upload(&source, &path)
+                .map(TransferOutcome::Dir);
+        }),
+        TransferJob::Sync {
+            source,
+            path,
+            options,
+        } => {
+            DirTransfer::new(&sessions, device, window, options.tree.clone()).and_then(|transfer| {
+                return transfer
+                    .progress(&progress)
+                    .cancel_on(cancelled)
+                    .sync(&source, &path, &options)
+                    .map(TransferOutcome::Sync);
+            })
+        }
+    };
+    match result {
+        Ok(outcome) => channel.closed(outcome),
+        Err(e) => channel.closed(e),
+    }
+}
+
+#[derive(Serialize, Clone)]
+struct TransferProgress {
+    transferred: u64,
+    total: u64,
+    /// Average bytes per second since the transfer started
+    rate: u64,
+}
+
+#[derive(Serialize, Clone)]
+struct TransferResult {
+    #[serde(flatten)]
+    stats: TransferStats,
+    /// Path of the local file read or written
+    local: String,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(untagged)]
+enum TransferOutcome {
+    File(TransferResult),
+    Dir(DirTransferResult),
+    Sync(SyncResult),
+}
+
+impl TransferOutcome {
+    fn file(stats: TransferStats, local: &Path) -> Self {
+        return TransferOutcome::File(TransferResult {
+            stats,
+            local: local.to_string_lossy().to_string(),
+        });
+    }
+}
+
+struct TransferChannelHandler {
+    started_lock: Arc<(Mutex<bool>, Condvar)>,
+    cancelled: AtomicBool,
+}
+
+impl EventHandler for TransferChannelHandler {
+    fn tx(&self, _payload: Option<&str>) {
+        self.start();
+    }
+
+    fn close(&self, _payload: Option<&str>) {
+        log::debug!("Transfer requested to stop");
+        self.cancelled.store(true, Ordering::SeqCst);
+        // Doesn't wait for a start that won't come
+        self.start();
+    }
+}
+
+impl TransferChannelHandler {
+    fn wait(&self) {
+        let (lock, cvar) = &*self.started_lock;
+        let mut started = lock.lock().unwrap();
+        while !*started {
+            started = cvar.wait(started).unwrap();
+        }
+    }
+
+    fn start(&self) {
+        let (lock, cvar) = &*self.started_lock;
+        *lock.lock().unwrap() = true;
+        cvar.notify_one();
+    }
+}
@@ -1,35 +1,87 @@
 use std::path::PathBuf;
 use std::sync::{Arc, Condvar, Mutex};
+use std::thread::sleep;
+use std::time::Duration;
 
-use crate::conn_pool::{DeviceConnectionPool, ManagedDeviceConnection};
+use crate::conn_pool::{
+    ConnectionStateCallback, DeviceConnectionPool, InteractiveAuth, ManagedDeviceConnection,
+    PoolInfo,
+};
 use crate::device_manager::Device;
 use crate::error::Error;
-use crate::session_manager::{Proc, SessionManager};
-use crate::app_dirs::{GetSshDir, SetSshDir};
+use crate::session_manager::{Proc, RetryCallback, RetryPolicy, SessionManager};
+use crate::vault::SecretVault;
+use crate::app_dirs::{GetConfDir, GetSshDir, SetConfDir, SetSshDir};
+
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
 
 impl SessionManager {
+    pub fn new(vault: Arc<SecretVault>, interactive: Arc<InteractiveAuth>) -> SessionManager {
+        let manager = SessionManager {
+            vault,
+            interactive,
+            ..Default::default()
+        };
+        manager.keepalive();
+        return manager;
+    }
+
+    pub fn set_callback(&self, callback: Box<dyn ConnectionStateCallback + Send + Sync>) {
+        *self.listener.lock().unwrap() = Some(callback);
+    }
+
     pub fn session(&self, device: Device) -> Result<ManagedDeviceConnection, Error> {
         return self.pool(device).get();
     }
 
+    /// Like [SessionManager::session], but gives up after `timeout` if the pool is busy.
+    pub fn session_timeout(
+        &self,
+        device: Device,
+        timeout: Duration,
+    ) -> Result<ManagedDeviceConnection, Error> {
+        return self.pool(device).get_timeout(timeout);
+    }
+
+    /// Connections the pool of the device keeps open at most.
+    pub fn pool_size(&self, device: Device) -> usize {
+        return self.pool(device).max_size() as usize;
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        return self.retry.lock().unwrap().clone();
+    }
+
+    pub fn set_retry_policy(&self, policy: RetryPolicy) {
+        *self.retry.lock().unwrap() = policy;
+    }
+
+    pub fn set_retry_callback(&self, callback: Box<dyn RetryCallback + Send + Sync>) {
+        *self.retry_callback.lock().unwrap() = Some(Arc::from(callback));
+    }
+
     pub fn with_session<T, F>(&self, device: Device, action: F) -> Result<T, Error>
     where
         F: Fn(&ManagedDeviceConnection) -> Result<T, Error>,
     {
+        let name = device.name.clone();
         let pool = self.pool(device);
-        loop {
-            let session = pool.get()?;
-            return match action(&session) {
-                Ok(ret) => {
-                    session.mark_last_ok();
-                    Ok(ret)
-                }
-                Err(Error::Disconnected) => {
-                    continue;
-                }
-                Err(e) => Err(e),
-            };
-        }
+        return self.retry(&name, |timeout| {
+            let session = pool.get_timeout(timeout)?;
+            let ret = action(&session)?;
+            session.mark_last_ok();
+            return Ok(ret);
+        });
+    }
+
+    /// Runs `action` again with the [RetryPolicy] while the connection to the device drops.
+    pub(crate) fn retry<T, F>(&self, device: &str, action: F) -> Result<T, Error>
+    where
+        F: FnMut(Duration) -> Result<T, Error>,
+    {
+        let policy = self.retry.lock().unwrap().clone();
+        let callback = self.retry_callback.lock().unwrap().clone();
+        return policy.run(device, callback.as_deref(), action);
     }
 
     pub fn spawn(&self, device: Device, command: &str) -> Proc {
@@ -43,26 +95,148 @@ impl SessionManager {
         };
     }
 
-    fn pool(&self, device: Device) -> DeviceConnectionPool {
-        if device.new {
-            return DeviceConnectionPool::new(device, self.get_ssh_dir());
-        }
-        if let Some(p) = self
+    /// Drops the cached connection pools of a device, and of devices that jump through it at any
+    /// hop, closing their idle connections.
+    pub fn evict(&self, name: &str) {
+        self.pools
+            .lock()
+            .expect("Failed to lock SessionManager::pools")
+            .retain(|_, pool| {
+                if pool.device.name != name && !pool.jumps.iter().any(|jump| jump == name) {
+                    return true;
+                }
+                log::info!("Evicted connection pool of {}", pool.device.name);
+                return false;
+            });
+    }
+
+    pub fn pools(&self) -> Vec<PoolInfo> {
+        let pools: Vec<DeviceConnectionPool> = self
             .pools
             .lock()
             .expect("Failed to lock SessionManager::pools")
-            .get(&device.name)
-        {
-            return p.clone();
+            .values()
+            .cloned()
+            .collect();
+        return pools.iter().map(|p| p.info()).collect();
+    }
+
+    /// Closes a connection of the device, see [DeviceConnectionPool::close].
+    pub fn close_connection(&self, name: &str, id: &str) -> Result<(), Error> {
+        let pools: Vec<DeviceConnectionPool> = self
+            .pools
+            .lock()
+            .expect("Failed to lock SessionManager::pools")
+            .values()
+            .filter(|p| p.device.name == name)
+            .cloned()
+            .collect();
+        if pools.iter().any(|p| p.close(id)) {
+            return Ok(());
         }
-        let key = device.name.clone();
-        let pool = DeviceConnectionPool::new(device, self.get_ssh_dir());
+        return Err(Error::NotFound);
+    }
+
+    /// Drops the connection pool of the device. Connections in use are closed once released.
+    pub fn close_pool(&self, name: &str) -> Result<(), Error> {
+        let mut pools = self
+            .pools
+            .lock()
+            .expect("Failed to lock SessionManager::pools");
+        let count = pools.len();
+        pools.retain(|_, pool| pool.device.name != name);
+        if pools.len() == count {
+            return Err(Error::NotFound);
+        }
+        log::info!("Closed connection pool of {}", name);
+        return Ok(());
+    }
+
+    /// Drops the cached connection pools of devices that are no longer in `devices`.
+    pub fn retain_devices(&self, devices: &[Device]) {
         self.pools
             .lock()
             .expect("Failed to lock SessionManager::pools")
-            .insert(key, pool.clone());
+            .retain(|_, pool| {
+                if devices.iter().any(|d| d.name == pool.device.name) {
+                    return true;
+                }
+                log::info!("Evicted connection pool of removed {}", pool.device.name);
+                return false;
+            });
+    }
+
+    /// Pools are keyed by the settings of the device alone, so the device list is only read for
+    /// its jump hosts when a pool is created. Pools of devices whose jump hosts changed are
+    /// evicted along with the others that need to reconnect, see
+    /// [crate::device_manager::DeviceListDiff::reconnect].
+    fn pool(&self, device: Device) -> DeviceConnectionPool {
+        if device.new {
+            return DeviceConnectionPool::new(
+                device.clone(),
+                self.chain(&device),
+                self.get_ssh_dir(),
+                self.get_conf_dir(),
+                self.vault.clone(),
+                self.interactive.clone(),
+                self.listener.clone(),
+            );
+        }
+        let key = device.connection_fingerprint(&[]);
+        let mut pools = self
+            .pools
+            .lock()
+            .expect("Failed to lock SessionManager::pools");
+        if let Some(p) = pools.get(&key) {
+            return p.clone();
+        }
+        pools.retain(|_, pool| {
+            if pool.device.name != device.name {
+                return true;
+            }
+            log::info!("Settings of {} changed, evicting stale pool", device.name);
+            return false;
+        });
+        let pool = DeviceConnectionPool::new(
+            device.clone(),
+            self.chain(&device),
+            self.get_ssh_dir(),
+            self.get_conf_dir(),
+            self.vault.clone(),
+            self.interactive.clone(),
+            self.listener.clone(),
+        );
+        pools.insert(key, pool.clone());
         return pool;
     }
+
+    fn chain(&self, device: &Device) -> Vec<Device> {
+        // Jump hosts that can't be resolved fail the connection later, with a proper error
+        return device
+            .jump_chain(self.get_conf_dir().as_deref())
+            .unwrap_or_default();
+    }
+
+    /// Probes idle pooled connections in the background, until the manager is dropped.
+    fn keepalive(&self) {
+        let pools = Arc::downgrade(&self.pools);
+        std::thread::spawn(move || loop {
+            sleep(KEEPALIVE_INTERVAL);
+            let Some(pools) = pools.upgrade() else {
+                break;
+            };
+            let snapshot: Vec<DeviceConnectionPool> = pools
+                .lock()
+                .expect("Failed to lock SessionManager::pools")
+                .values()
+                .cloned()
+                .collect();
+            drop(pools);
+            for pool in snapshot {
+                pool.keepalive();
+            }
+        });
+    }
 }
 
 impl GetSshDir for SessionManager {
@@ -76,3 +250,15 @@ impl SetSshDir for SessionManager {
         *self.ssh_dir.lock().unwrap() = Some(dir);
     }
 }
+
+impl GetConfDir for SessionManager {
+    fn get_conf_dir(&self) -> Option<PathBuf> {
+        return self.conf_dir.lock().unwrap().clone();
+    }
+}
+
+impl SetConfDir for SessionManager {
+    fn set_conf_dir(&self, dir: PathBuf) {
+        *self.conf_dir.lock().unwrap() = Some(dir);
+    }
+}
@@ -2,19 +2,69 @@ use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::mpsc::Sender;
 use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-use crate::conn_pool::DeviceConnectionPool;
+use crate::conn_pool::{ConnectionStateListener, DeviceConnectionPool, InteractiveAuth};
 use crate::device_manager::Device;
+use crate::error::Error;
+use crate::vault::SecretVault;
 
 mod manager;
 mod proc;
+mod retry;
 
 #[derive(Default)]
 pub struct SessionManager {
     ssh_dir: Mutex<Option<PathBuf>>,
-    pools: Mutex<HashMap<String, DeviceConnectionPool>>,
+    conf_dir: Mutex<Option<PathBuf>>,
+    /// Connection pools keyed by [Device::connection_fingerprint].
+    pools: Arc<Mutex<HashMap<String, DeviceConnectionPool>>>,
+    vault: Arc<SecretVault>,
+    interactive: Arc<InteractiveAuth>,
+    listener: ConnectionStateListener,
+    retry: Mutex<RetryPolicy>,
+    retry_callback: Mutex<Option<Arc<dyn RetryCallback + Send + Sync>>>,
+}
+
+/// How operations are retried when their connection drops.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// No attempt is started after this much time has passed since the first one, and attempts
+    /// only wait for a connection until then
+    pub deadline: Duration,
+}
+
+/// [RetryPolicy] as set by the frontend, with durations in milliseconds.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RetryOptions {
+    #[serde(rename = "maxAttempts")]
+    pub max_attempts: u32,
+    #[serde(rename = "initialBackoffMs")]
+    pub initial_backoff_ms: u64,
+    #[serde(rename = "maxBackoffMs")]
+    pub max_backoff_ms: u64,
+    #[serde(rename = "deadlineMs")]
+    pub deadline_ms: u64,
+}
+
+pub trait RetryCallback {
+    fn retrying(&self, attempt: &RetryAttempt);
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct RetryAttempt {
+    pub device: String,
+    pub attempt: u32,
+    #[serde(rename = "maxAttempts")]
+    pub max_attempts: u32,
+    #[serde(rename = "delayMs")]
+    pub delay_ms: u64,
+    pub error: Error,
 }
 
 pub struct Proc {
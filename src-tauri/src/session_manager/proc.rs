@@ -3,9 +3,6 @@ use std::io::Write;
 use std::sync::mpsc::channel;
 use std::time::Duration;
 
-use libssh_rs::Channel;
-
-use crate::conn_pool::ManagedDeviceConnection;
 use crate::error::Error;
 use crate::session_manager::{Proc, SessionManager};
 
@@ -54,27 +51,14 @@ impl Proc {
     }
 
     pub fn wait_close(&self, sessions: &SessionManager) -> Result<i32, Error> {
-        let session: ManagedDeviceConnection;
         let (sender, receiver) = channel::<Vec<u8>>();
         *self.sender.lock().unwrap() = Some(sender);
-        let channel: Channel;
-        loop {
-            let conn = sessions.session(self.device.clone())?;
-            let open = || {
-                let ch = conn.new_channel()?;
-                ch.open_session()?;
-                Ok(ch)
-            };
-            match open() {
-                Ok(ch) => {
-                    session = conn;
-                    channel = ch;
-                    break;
-                }
-                Err(Error::Disconnected) => continue,
-                Err(e) => return Err(e),
-            };
-        }
+        let (session, channel) = sessions.retry(&self.device.name, |timeout| {
+            let conn = sessions.session_timeout(self.device.clone(), timeout)?;
+            let ch = conn.new_channel()?;
+            ch.open_session()?;
+            return Ok((conn, ch));
+        })?;
         channel.request_exec(&self.command)?;
         let mut buf = [0; 8192];
         let mut interrupted = false;
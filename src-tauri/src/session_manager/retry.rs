@@ -0,0 +1,112 @@
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+use crate::error::Error;
+use crate::session_manager::{RetryAttempt, RetryCallback, RetryOptions, RetryPolicy};
+
+/// Least time given to an attempt, so one started just before the deadline can still connect.
+const MIN_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(1);
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        return RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(5),
+            deadline: Duration::from_secs(60),
+        };
+    }
+}
+
+impl From<RetryOptions> for RetryPolicy {
+    /// Makes at least one attempt, and never backs off less than it did before.
+    fn from(options: RetryOptions) -> Self {
+        let initial_backoff = Duration::from_millis(options.initial_backoff_ms);
+        return RetryPolicy {
+            max_attempts: options.max_attempts.max(1),
+            initial_backoff,
+            max_backoff: Duration::from_millis(options.max_backoff_ms).max(initial_backoff),
+            deadline: Duration::from_millis(options.deadline_ms),
+        };
+    }
+}
+
+impl From<&RetryPolicy> for RetryOptions {
+    fn from(policy: &RetryPolicy) -> Self {
+        return RetryOptions {
+            max_attempts: policy.max_attempts,
+            initial_backoff_ms: policy.initial_backoff.as_millis() as u64,
+            max_backoff_ms: policy.max_backoff.as_millis() as u64,
+            deadline_ms: policy.deadline.as_millis() as u64,
+        };
+    }
+}
+
+impl RetryPolicy {
+    /// Runs `action` until it stops failing with [Error::Disconnected], or the policy gives up.
+    ///
+    /// `action` is given the time left before the deadline, to wait for a connection at most.
+    pub(crate) fn run<T, F>(
+        &self,
+        device: &str,
+        callback: Option<&(dyn RetryCallback + Send + Sync)>,
+        mut action: F,
+    ) -> Result<T, Error>
+    where
+        F: FnMut(Duration) -> Result<T, Error>,
+    {
+        let started = Instant::now();
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let left = self.deadline.saturating_sub(started.elapsed());
+            let error = match action(left.max(MIN_ATTEMPT_TIMEOUT)) {
+                Err(e @ Error::Disconnected) => e,
+                result => return result,
+            };
+            let delay = self.backoff(attempt);
+            if attempt >= self.max_attempts || started.elapsed() + delay > self.deadline {
+                log::warn!(
+                    "Connection to {} dropped, giving up after {} attempts in {:?}",
+                    device,
+                    attempt,
+                    started.elapsed()
+                );
+                return Err(Error::RetriesExhausted {
+                    message: format!("Connection dropped, gave up after {attempt} attempts"),
+                    attempts: attempt,
+                    last_error: Box::new(error),
+                });
+            }
+            log::info!(
+                "Connection to {} dropped (attempt {}/{}), retrying in {:?}",
+                device,
+                attempt,
+                self.max_attempts,
+                delay
+            );
+            if let Some(callback) = callback {
+                callback.retrying(&RetryAttempt {
+                    device: String::from(device),
+                    attempt,
+                    max_attempts: self.max_attempts,
+                    delay_ms: delay.as_millis() as u64,
+                    error,
+                });
+            }
+            sleep(delay);
+        }
+    }
+
+    /// Exponential backoff, with a random jitter of up to half of it so that callers failing
+    /// together don't retry together.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(1 << (attempt - 1).min(16))
+            .min(self.max_backoff);
+        return backoff.mul_f64(rand::thread_rng().gen_range(0.5..=1.0));
+    }
+}
@@ -1,16 +1,29 @@
 use std::path::PathBuf;
 use std::sync::Arc;
 
-use crate::app_dirs::{GetSshDir, SetSshDir};
+use crate::app_dirs::{GetConfDir, GetSshDir, SetConfDir, SetSshDir};
+use crate::conn_pool::InteractiveAuth;
 use crate::device_manager::Device;
 use crate::error::Error;
 use crate::shell_manager::{Shell, ShellInfo, ShellManager, ShellToken};
+use crate::vault::SecretVault;
 
 impl ShellManager {
+    pub fn new(vault: Arc<SecretVault>, interactive: Arc<InteractiveAuth>) -> ShellManager {
+        return ShellManager {
+            vault,
+            interactive,
+            ..Default::default()
+        };
+    }
+
     pub fn open(&self, device: Device, rows: u16, cols: u16, dumb: bool) -> Arc<Shell> {
         let shell = Arc::new(Shell::new(
             device,
             self.get_ssh_dir().as_deref(),
+            self.get_conf_dir().as_deref(),
+            self.vault.clone(),
+            self.interactive.clone(),
             !dumb,
             rows,
             cols,
@@ -60,3 +73,15 @@ impl SetSshDir for ShellManager {
         *self.ssh_dir.lock().unwrap() = Some(dir);
     }
 }
+
+impl GetConfDir for ShellManager {
+    fn get_conf_dir(&self) -> Option<PathBuf> {
+        return self.conf_dir.lock().unwrap().clone();
+    }
+}
+
+impl SetConfDir for ShellManager {
+    fn set_conf_dir(&self, dir: PathBuf) {
+        *self.conf_dir.lock().unwrap() = Some(dir);
+    }
+}
@@ -7,9 +7,11 @@ use serde::Serialize;
 use uuid::Uuid;
 use vt100::Parser;
 
+use crate::conn_pool::InteractiveAuth;
 use crate::device_manager::Device;
 use crate::error::Error;
 use crate::shell_manager::shell::ShellsMap;
+use crate::vault::SecretVault;
 
 pub(crate) mod manager;
 pub(crate) mod shell;
@@ -19,6 +21,9 @@ pub(crate) mod token;
 pub struct ShellManager {
     pub(crate) shells: Arc<Mutex<ShellsMap>>,
     ssh_dir: Mutex<Option<PathBuf>>,
+    conf_dir: Mutex<Option<PathBuf>>,
+    vault: Arc<SecretVault>,
+    interactive: Arc<InteractiveAuth>,
 }
 
 pub struct Shell {
@@ -26,6 +31,9 @@ pub struct Shell {
     created_at: Instant,
     device: Device,
     ssh_dir: Option<PathBuf>,
+    conf_dir: Option<PathBuf>,
+    vault: Arc<SecretVault>,
+    interactive: Arc<InteractiveAuth>,
     pub(crate) has_pty: Mutex<Option<bool>>,
     pub(crate) closed: Mutex<Option<ShellState>>,
     pub(crate) sender: Mutex<Option<Sender<ShellMessage>>>,
@@ -10,10 +10,11 @@ use std::time::{Duration, Instant};
 use libssh_rs::Error::RequestDenied;
 use vt100::Parser;
 
-use crate::conn_pool::DeviceConnection;
+use crate::conn_pool::{DeviceConnection, InteractiveAuth};
 use crate::device_manager::Device;
 use crate::error::Error;
 use crate::shell_manager::{Shell, ShellInfo, ShellMessage, ShellScreen, ShellState, ShellToken};
+use crate::vault::SecretVault;
 
 pub(crate) type ShellsMap = HashMap<ShellToken, Arc<Shell>>;
 
@@ -86,6 +87,9 @@ impl Shell {
     pub(crate) fn new(
         device: Device,
         ssh_dir: Option<&Path>,
+        conf_dir: Option<&Path>,
+        vault: Arc<SecretVault>,
+        interactive: Arc<InteractiveAuth>,
         wants_pty: bool,
         rows: u16,
         cols: u16,
@@ -96,6 +100,9 @@ impl Shell {
             created_at: Instant::now(),
             device,
             ssh_dir: ssh_dir.map(|p| p.to_path_buf()),
+            conf_dir: conf_dir.map(|p| p.to_path_buf()),
+            vault,
+            interactive,
             has_pty: Mutex::new(if !wants_pty { Some(false) } else { None }),
             closed: Mutex::default(),
             sender: Mutex::default(),
@@ -137,7 +144,15 @@ impl Shell {
 
     fn worker(&self) -> Result<i32, Error> {
         let (sender, receiver) = channel::<ShellMessage>();
-        let connection = DeviceConnection::new(self.device.clone(), self.ssh_dir.as_deref())?;
+        let chain = self.device.jump_chain(self.conf_dir.as_deref())?;
+        let connection = DeviceConnection::new(
+            self.device.clone(),
+            &chain,
+            self.ssh_dir.as_deref(),
+            self.conf_dir.as_deref(),
+            &self.vault,
+            &self.interactive,
+        )?;
         let channel = connection.new_channel()?;
         channel.open_session()?;
         let (rows, cols) = self.parser.lock().unwrap().screen().size();
@@ -0,0 +1,55 @@
+use openssl::hash::MessageDigest;
+use openssl::pkcs5::pbkdf2_hmac;
+use openssl::rand::rand_bytes;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+
+use crate::error::Error;
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+pub(super) fn random(len: usize) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![0u8; len];
+    rand_bytes(&mut buf).map_err(|e| Error::new(format!("Crypto Error: {e}")))?;
+    return Ok(buf);
+}
+
+pub(super) fn derive(password: &str, salt: &[u8], iterations: u32) -> Result<Vec<u8>, Error> {
+    let mut key = vec![0u8; 32];
+    pbkdf2_hmac(
+        password.as_bytes(),
+        salt,
+        iterations as usize,
+        MessageDigest::sha256(),
+        &mut key,
+    )
+    .map_err(|e| Error::new(format!("Crypto Error: {e}")))?;
+    return Ok(key);
+}
+
+/// Encrypts `data` with AES-256-GCM, returning hex of nonce, tag and ciphertext.
+pub(super) fn seal(key: &[u8], data: &[u8]) -> Result<String, Error> {
+    let nonce = random(NONCE_LEN)?;
+    let mut tag = [0u8; TAG_LEN];
+    let encrypted = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(&nonce),
+        &[],
+        data,
+        &mut tag,
+    )
+    .map_err(|e| Error::new(format!("Crypto Error: {e}")))?;
+    return Ok(hex::encode([nonce.as_slice(), &tag, &encrypted].concat()));
+}
+
+pub(super) fn open(key: &[u8], sealed: &str) -> Result<Vec<u8>, Error> {
+    let sealed = hex::decode(sealed).map_err(|_| Error::bad_config())?;
+    if sealed.len() < NONCE_LEN + TAG_LEN {
+        return Err(Error::bad_config());
+    }
+    let (nonce, rest) = sealed.split_at(NONCE_LEN);
+    let (tag, encrypted) = rest.split_at(TAG_LEN);
+    return decrypt_aead(Cipher::aes_256_gcm(), key, Some(nonce), &[], encrypted, tag)
+        .map_err(|_| Error::BadPassphrase);
+}
@@ -0,0 +1,40 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+mod crypto;
+mod store;
+
+/// Encrypted store for device passwords and key passphrases.
+#[derive(Default)]
+pub struct SecretVault {
+    conf_dir: Mutex<Option<PathBuf>>,
+    key: Mutex<Option<Vec<u8>>>,
+    file_lock: Mutex<()>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct VaultStatus {
+    pub initialized: bool,
+    pub unlocked: bool,
+    #[serde(rename = "keyFile")]
+    pub key_file: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct VaultFile {
+    kdf: VaultKdf,
+    check: String,
+    entries: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type")]
+enum VaultKdf {
+    #[serde(rename = "pbkdf2-sha256")]
+    Pbkdf2 { salt: String, iterations: u32 },
+    #[serde(rename = "keyFile")]
+    KeyFile,
+}
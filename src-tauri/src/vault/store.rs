@@ -0,0 +1,243 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use uuid::Uuid;
+
+use crate::app_dirs::{GetConfDir, SetConfDir};
+use crate::device_manager::Device;
+use crate::error::Error;
+use crate::vault::{crypto, SecretVault, VaultFile, VaultKdf, VaultStatus};
+
+const CHECK_PLAINTEXT: &[u8] = b"devman-vault";
+const PBKDF2_ITERATIONS: u32 = 600_000;
+
+impl SecretVault {
+    pub fn status(&self) -> Result<VaultStatus, Error> {
+        let file = self.read()?;
+        return Ok(VaultStatus {
+            initialized: file.is_some(),
+            unlocked: self.key.lock().unwrap().is_some(),
+            key_file: matches!(file.map(|f| f.kdf), Some(VaultKdf::KeyFile)),
+        });
+    }
+
+    /// Creates the vault, protected by `password`, or by a generated key file if there is none.
+    pub fn create(&self, password: Option<&str>) -> Result<(), Error> {
+        let _guard = self.file_lock.lock().unwrap();
+        if self.read()?.is_some() {
+            return Err(Error::io(ErrorKind::AlreadyExists));
+        }
+        let (kdf, key) = match password {
+            Some(password) => {
+                let salt = crypto::random(16)?;
+                let key = crypto::derive(password, &salt, PBKDF2_ITERATIONS)?;
+                let kdf = VaultKdf::Pbkdf2 {
+                    salt: hex::encode(salt),
+                    iterations: PBKDF2_ITERATIONS,
+                };
+                (kdf, key)
+            }
+            None => {
+                let key = crypto::random(32)?;
+                self.write_key_file(&key)?;
+                (VaultKdf::KeyFile, key)
+            }
+        };
+        self.write(&VaultFile {
+            kdf,
+            check: crypto::seal(&key, CHECK_PLAINTEXT)?,
+            entries: BTreeMap::new(),
+        })?;
+        *self.key.lock().unwrap() = Some(key);
+        log::info!("Secret vault created");
+        return Ok(());
+    }
+
+    pub fn unlock(&self, password: Option<&str>) -> Result<(), Error> {
+        let file = self.read()?.ok_or(Error::NotFound)?;
+        let key = match &file.kdf {
+            VaultKdf::Pbkdf2 { salt, iterations } => crypto::derive(
+                password.ok_or(Error::PassphraseRequired)?,
+                &hex::decode(salt).map_err(|_| Error::bad_config())?,
+                *iterations,
+            )?,
+            VaultKdf::KeyFile => self.read_key_file()?,
+        };
+        if crypto::open(&key, &file.check)? != CHECK_PLAINTEXT {
+            return Err(Error::BadPassphrase);
+        }
+        *self.key.lock().unwrap() = Some(key);
+        log::info!("Secret vault unlocked");
+        return Ok(());
+    }
+
+    pub fn lock(&self) {
+        *self.key.lock().unwrap() = None;
+        log::info!("Secret vault locked");
+    }
+
+    pub fn put(&self, secret: &str) -> Result<String, Error> {
+        let key = self.unlocked_key()?;
+        let _guard = self.file_lock.lock().unwrap();
+        let mut file = self.read()?.ok_or(Error::NotFound)?;
+        let id = Uuid::new_v4().to_string();
+        file.entries
+            .insert(id.clone(), crypto::seal(&key, secret.as_bytes())?);
+        self.write(&file)?;
+        return Ok(id);
+    }
+
+    pub fn get(&self, id: &str) -> Result<String, Error> {
+        let key = self.unlocked_key()?;
+        let file = self.read()?.ok_or(Error::NotFound)?;
+        let sealed = file.entries.get(id).ok_or(Error::NotFound)?;
+        return String::from_utf8(crypto::open(&key, sealed)?).map_err(|_| Error::bad_config());
+    }
+
+    /// Whether the vault has an entry `id`, which doesn't need it to be unlocked.
+    pub fn contains(&self, id: &str) -> Result<bool, Error> {
+        return Ok(self
+            .read()?
+            .is_some_and(|file| file.entries.contains_key(id)));
+    }
+
+    pub fn remove(&self, id: &str) -> Result<(), Error> {
+        let _guard = self.file_lock.lock().unwrap();
+        let Some(mut file) = self.read()? else {
+            return Ok(());
+        };
+        if file.entries.remove(id).is_some() {
+            self.write(&file)?;
+        }
+        return Ok(());
+    }
+
+    /// Returns a copy of the device with its password and passphrase read from the vault.
+    pub fn resolve(&self, device: &Device) -> Result<Device, Error> {
+        let mut device = device.clone();
+        if let Some(id) = &device.password_ref {
+            device.password = Some(self.get(id)?);
+        }
+        if let Some(id) = &device.passphrase_ref {
+            device.passphrase = Some(self.get(id)?);
+        }
+        return Ok(device);
+    }
+
+    /// Moves the plaintext password and passphrase of the device into the vault.
+    ///
+    /// Does nothing if the vault hasn't been created. Returns true if the device was changed.
+    pub fn seal(&self, device: &mut Device) -> Result<bool, Error> {
+        if self.read()?.is_none() {
+            return Ok(false);
+        }
+        let mut changed = false;
+        if let Some(password) = device.password.take() {
+            device.password_ref = Some(self.put(&password)?);
+            changed = true;
+        }
+        if let Some(passphrase) = device.passphrase.take() {
+            device.passphrase_ref = Some(self.put(&passphrase)?);
+            changed = true;
+        }
+        return Ok(changed);
+    }
+
+    /// Key of the vault, read from the key file if the vault doesn't use a master password.
+    fn unlocked_key(&self) -> Result<Vec<u8>, Error> {
+        if let Some(key) = self.key.lock().unwrap().as_ref() {
+            return Ok(key.clone());
+        }
+        let file = self.read()?.ok_or(Error::NotFound)?;
+        if let VaultKdf::Pbkdf2 { .. } = file.kdf {
+            return Err(Error::VaultLocked);
+        }
+        self.unlock(None)?;
+        return self.key.lock().unwrap().clone().ok_or(Error::VaultLocked);
+    }
+
+    fn read(&self) -> Result<Option<VaultFile>, Error> {
+        let file = match File::open(self.vault_path()?) {
+            Ok(file) => file,
+            Err(e) => {
+                return match e.kind() {
+                    ErrorKind::NotFound => Ok(None),
+                    _ => Err(e.into()),
+                };
+            }
+        };
+        return Ok(Some(serde_json::from_reader(BufReader::new(file))?));
+    }
+
+    fn write(&self, file: &VaultFile) -> Result<(), Error> {
+        let path = self.vault_path()?;
+        let temp_path = path.with_file_name(format!(".devman-vault.json.{}.tmp", Uuid::new_v4()));
+        let data = serde_json::to_vec_pretty(file)?;
+        let result = write_temp(&temp_path, &data).and_then(|_| Ok(fs::rename(&temp_path, &path)?));
+        if result.is_err() {
+            fs::remove_file(&temp_path).unwrap_or(());
+        }
+        return result;
+    }
+
+    fn read_key_file(&self) -> Result<Vec<u8>, Error> {
+        let content = fs::read_to_string(self.key_file_path()?)?;
+        return hex::decode(content.trim()).map_err(|_| Error::bad_config());
+    }
+
+    fn write_key_file(&self, key: &[u8]) -> Result<(), Error> {
+        let path = self.key_file_path()?;
+        create_private(&path)?.write_all(hex::encode(key).as_bytes())?;
+        return Ok(());
+    }
+
+    fn vault_path(&self) -> Result<PathBuf, Error> {
+        return Ok(self.ensure_conf_dir()?.join("devman-vault.json"));
+    }
+
+    fn key_file_path(&self) -> Result<PathBuf, Error> {
+        return Ok(self.ensure_conf_dir()?.join("devman-vault.key"));
+    }
+}
+
+impl GetConfDir for SecretVault {
+    fn get_conf_dir(&self) -> Option<PathBuf> {
+        return self.conf_dir.lock().unwrap().clone();
+    }
+}
+
+impl SetConfDir for SecretVault {
+    fn set_conf_dir(&self, dir: PathBuf) {
+        *self.conf_dir.lock().unwrap() = Some(dir);
+    }
+}
+
+fn write_temp(path: &Path, data: &[u8]) -> Result<(), Error> {
+    let mut file = create_private(path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    return Ok(());
+}
+
+/// Creates or truncates a file only the user can read, before anything is written to it.
+#[cfg(unix)]
+fn create_private(path: &Path) -> Result<File, Error> {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    // The mode only applies to new files, an existing one may be readable by others
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    return Ok(file);
+}
+
+#[cfg(not(unix))]
+fn create_private(path: &Path) -> Result<File, Error> {
+    return Ok(File::create(path)?);
+}
@@ -1,11 +1,11 @@
-import {Component, Input, OnInit} from '@angular/core';
+import {Component, Input, OnDestroy, OnInit} from '@angular/core';
 import {FormControl, FormGroup, ValidationErrors, Validators} from '@angular/forms';
 import {NgbModal} from '@ng-bootstrap/ng-bootstrap';
 import {DeviceManagerService} from '../../core/services';
 import {MessageDialogComponent} from '../../shared/components/message-dialog/message-dialog.component';
 import {KeyserverHintComponent} from '../keyserver-hint/keyserver-hint.component';
-import {NewDevice, NewDeviceAuthentication, NewDeviceBase} from "../../types";
-import {noop, Observable, of} from "rxjs";
+import {DiscoveredDevice, NewDevice, NewDeviceAuthentication, NewDeviceBase} from "../../types";
+import {finalize, noop, Observable, of, Subscription} from "rxjs";
 import {fromPromise} from "rxjs/internal/observable/innerFrom";
 import {open as showOpenDialog} from '@tauri-apps/api/dialog';
 import {homeDir} from '@tauri-apps/api/path';
@@ -22,7 +22,7 @@ import {RetryFailedComponent} from "../retry-failed/retry-failed.component";
     templateUrl: './device-editor.component.html',
     styleUrls: ['./device-editor.component.scss']
 })
-export class DeviceEditorComponent implements OnInit {
+export class DeviceEditorComponent implements OnInit, OnDestroy {
 
     formGroup!: FormGroup<SetupInfoFormControls>;
 
@@ -37,6 +37,9 @@ export class DeviceEditorComponent implements OnInit {
     @Input()
     hideDevModeAuth?: boolean;
 
+    discovered: DiscoveredDevice[] = [];
+    discovering?: Subscription;
+
     constructor(private modalService: NgbModal, private deviceManager: DeviceManagerService) {
     }
 
@@ -99,6 +102,27 @@ export class DeviceEditorComponent implements OnInit {
         });
     }
 
+    ngOnDestroy(): void {
+        this.discovering?.unsubscribe();
+    }
+
+    discover(): void {
+        this.discovering?.unsubscribe();
+        this.discovered = [];
+        this.discovering = this.deviceManager.discover()
+            .pipe(finalize(() => this.discovering = undefined))
+            .subscribe({
+                next: (device) => this.discovered.push(device),
+                error: (e) => console.warn('Failed to discover devices:', e),
+            });
+    }
+
+    useDiscovered(device: DiscoveredDevice): void {
+        const address = this.formGroup.controls.address;
+        address.setValue(device.address);
+        address.markAsDirty();
+    }
+
     async submit(): Promise<NewDevice> {
         const newDevice = await this.getNewDevice();
         try {
@@ -7,6 +7,7 @@ import {MessageDialogComponent} from './shared/components/message-dialog/message
 import {UpdateDetailsComponent} from './update-details/update-details.component';
 import {open} from "@tauri-apps/api/shell";
 import {noop} from "rxjs";
+import {AuthPromptComponent} from "./shared/components/auth-prompt/auth-prompt.component";
 
 @Component({
   selector: 'app-root',
@@ -32,6 +33,10 @@ export class AppComponent {
       }
     }).catch(noop);
     deviceManager.load();
+    deviceManager.authPrompts$.subscribe(async request => {
+      const answers = await AuthPromptComponent.prompt(this.modalService, request);
+      await deviceManager.answerAuthPrompt(request, answers);
+    });
   }
 
   private async notifyUpdate(info: Release, version: SemVer): Promise<void> {
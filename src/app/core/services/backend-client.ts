@@ -30,7 +30,7 @@ export abstract class BackendClient {
             this.zone.run(() => handler(event.payload))).then(noop);
     }
 
-    private static toBackendError(e: unknown): Error {
+    protected static toBackendError(e: unknown): Error {
         if (BackendError.isCompatibleBody(e)) {
             if (e.reason === 'ExitStatus') {
                 return ExecutionError.fromBackendError(e);
@@ -78,17 +78,22 @@ export class BackendError extends Error {
 export type ErrorReason =
     'Authorization' |
     'BadPassphrase' |
+    'Cancelled' |
+    'DirectoryNotEmpty' |
     'Disconnected' |
     'ExitStatus' |
+    'HostKeyChanged' |
     'IO' |
     'Message' |
     'NeedsReconnect' |
     'NegativeReply' |
     'NotFound' |
     'PassphraseRequired' |
+    'RetriesExhausted' |
     'Timeout' |
     'Unsupported' |
-    'UnsupportedKey';
+    'UnsupportedKey' |
+    'VaultLocked';
 
 export class IOError extends BackendError {
     declare code: 'PermissionDenied' | 'NotFound' | string;
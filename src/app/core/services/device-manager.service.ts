@@ -1,13 +1,33 @@
 import {Injectable, NgZone} from "@angular/core";
-import {BehaviorSubject, from, Observable, Subject} from "rxjs";
-import {CrashReportEntry, Device, DeviceLike, FileItem, FileSession, NewDevice, StorageInfo} from '../../types';
-import {BackendClient, IOError} from "./backend-client";
+import {BehaviorSubject, from, noop, Observable, Subject, Subscriber} from "rxjs";
+import {listen} from "@tauri-apps/api/event";
+import {
+    AuthPromptRequest,
+    ConnectionRetry,
+    ConnectionState,
+    ConnectionStateEvent,
+    CrashReportEntry,
+    Device,
+    DeviceLike,
+    DeviceList,
+    DiagnosticReport,
+    DiscoveredDevice,
+    FileItem,
+    FileSession,
+    HostKey,
+    NewDevice,
+    PoolInfo,
+    RetryPolicy,
+    StorageInfo
+} from '../../types';
+import {BackendClient, BackendError, IOError} from "./backend-client";
 import {FileSessionImpl} from "./file.session";
 import {HomebrewChannelConfiguration, OsInfo, SystemInfo} from "../../types/luna-apis";
 import {LunaResponseError, RemoteLunaService} from "./remote-luna.service";
 import {RemoteCommandService} from "./remote-command.service";
 import {RemoteFileService} from "./remote-file.service";
 import {DevModeService} from "./dev-mode.service";
+import {EventChannel} from "../event-channel";
 
 export type ScreenshotMethod = 'DISPLAY' | 'VIDEO' | 'GRAPHIC';
 
@@ -18,6 +38,9 @@ export class DeviceManagerService extends BackendClient {
 
     private devicesSubject: Subject<Device[] | null>;
     private selectedSubject: Subject<Device | null>;
+    private authPromptsSubject: Subject<AuthPromptRequest>;
+    private connectionStatesSubject: BehaviorSubject<Record<string, ConnectionState>>;
+    private connectionRetriesSubject: Subject<ConnectionRetry>;
 
     constructor(zone: NgZone, private cmd: RemoteCommandService, private file: RemoteFileService,
                 private luna: RemoteLunaService, private devMode: DevModeService) {
@@ -25,6 +48,18 @@ export class DeviceManagerService extends BackendClient {
         this.devicesSubject = new BehaviorSubject<Device[] | null>(null);
         this.selectedSubject = new BehaviorSubject<Device | null>(null);
         this.on('devicesUpdated', (devices: Device[]) => this.onDevicesUpdated(devices));
+        this.authPromptsSubject = new Subject<AuthPromptRequest>();
+        listen('devices-changed', () => this.zone.run(() => this.load())).then(noop);
+        listen<AuthPromptRequest>('auth-prompt', (e) =>
+            this.zone.run(() => this.authPromptsSubject.next(e.payload))).then(noop);
+        this.connectionStatesSubject = new BehaviorSubject<Record<string, ConnectionState>>({});
+        listen<ConnectionStateEvent>('connection-state', (e) => this.zone.run(() => {
+            const states = this.connectionStatesSubject.value;
+            this.connectionStatesSubject.next({...states, [e.payload.device]: e.payload.state});
+        })).then(noop);
+        this.connectionRetriesSubject = new Subject<ConnectionRetry>();
+        listen<ConnectionRetry>('connection-retry', (e) =>
+            this.zone.run(() => this.connectionRetriesSubject.next(e.payload))).then(noop);
     }
 
     get devices$(): Observable<Device[] | null> {
@@ -35,12 +70,52 @@ export class DeviceManagerService extends BackendClient {
         return this.selectedSubject.asObservable();
     }
 
+    /**
+     * Keyboard-interactive prompts of hosts being connected to. Each of them needs to be answered
+     * with {@link answerAuthPrompt}, or the connection will fail after a while.
+     */
+    get authPrompts$(): Observable<AuthPromptRequest> {
+        return this.authPromptsSubject.asObservable();
+    }
+
+    /**
+     * Last known state of the pooled connections, keyed by device name. Devices that haven't been
+     * connected to yet are absent.
+     */
+    get connectionStates$(): Observable<Record<string, ConnectionState>> {
+        return this.connectionStatesSubject.asObservable();
+    }
+
+    /**
+     * Reported before waiting to retry an operation whose connection dropped.
+     */
+    get connectionRetries$(): Observable<ConnectionRetry> {
+        return this.connectionRetriesSubject.asObservable();
+    }
+
+    async answerAuthPrompt(request: AuthPromptRequest, answers?: string[]): Promise<void> {
+        const channel = new AuthPromptChannel(request.token);
+        try {
+            if (answers) {
+                await channel.send(answers);
+            } else {
+                await channel.close();
+            }
+        } finally {
+            await channel.unlisten();
+        }
+    }
+
 
     load(): void {
         this.list().then(devices => this.onDevicesUpdated(devices));
     }
 
     async list(): Promise<Device[]> {
+        return await this.listAll().then(list => list.devices);
+    }
+
+    async listAll(): Promise<DeviceList> {
         return await this.invoke('list');
     }
 
@@ -58,6 +133,13 @@ export class DeviceManagerService extends BackendClient {
         return await this.invoke('add', {device});
     }
 
+    async updateDevice(name: string, device: DeviceLike): Promise<Device> {
+        return await this.invoke<Device>('update', {name, device}).then(device => {
+            this.load();
+            return device;
+        });
+    }
+
     async readPrivKey(device: Device): Promise<string> {
         return await this.invoke('privkey_read', {device});
     }
@@ -66,10 +148,71 @@ export class DeviceManagerService extends BackendClient {
         return await this.invoke('novacom_getkey', {address, passphrase});
     }
 
+    /**
+     * Searches the LAN for TVs, emitting each one found, until `timeout` seconds have passed or
+     * the subscription is dropped.
+     */
+    discover(timeout?: number): Observable<DiscoveredDevice> {
+        return new Observable<DiscoveredDevice>(subscriber => {
+            let channel: DiscoveryChannel | undefined;
+            let stopped = false;
+            this.invoke<string>('discover', {timeout}).then(token => {
+                channel = new DiscoveryChannel(token, this.zone, subscriber);
+                if (stopped) {
+                    channel.close().then();
+                    return;
+                }
+                channel.send().then();
+            }).catch(e => subscriber.error(e));
+            return () => {
+                stopped = true;
+                channel?.close().then();
+            };
+        });
+    }
+
+    /**
+     * Pins the new host key of the device, if it still presents the one with `fingerprint`, the
+     * `newFingerprint` of the `HostKeyChanged` error the user accepted.
+     */
+    async acceptHostKey(device: Device, fingerprint: string): Promise<HostKey> {
+        return await this.invoke('host_key_accept', {device, fingerprint});
+    }
+
     async verifyLocalPrivateKey(name: string, passphrase?: string): Promise<void> {
         await this.invoke('localkey_verify', {name, passphrase});
     }
 
+    async diagnose(device: DeviceLike): Promise<DiagnosticReport> {
+        return await this.invoke('diagnose', {device});
+    }
+
+    async pools(): Promise<PoolInfo[]> {
+        return await this.invoke('pools');
+    }
+
+    async closePool(name: string): Promise<void> {
+        return await this.invoke('pool_close', {name});
+    }
+
+    /**
+     * Closes the connection right away if it's idle, otherwise once it's no longer in use.
+     */
+    async closeConnection(name: string, id: string): Promise<void> {
+        return await this.invoke('connection_close', {name, id});
+    }
+
+    async getRetryPolicy(): Promise<RetryPolicy> {
+        return await this.invoke('retry_policy');
+    }
+
+    /**
+     * Changes how operations are retried when their connection drops, until the app restarts.
+     */
+    async setRetryPolicy(policy: RetryPolicy): Promise<void> {
+        return await this.invoke('retry_policy_set', {policy});
+    }
+
     async devModeToken(device: Device): Promise<string> {
         return await this.devMode.token(device);
     }
@@ -224,6 +367,39 @@ export class CrashReport implements CrashReportEntry {
 
 }
 
+class AuthPromptChannel extends EventChannel<never, unknown> {
+    constructor(token: string) {
+        super(token);
+    }
+
+    onReceive(): void {
+    }
+
+    onClose(): void {
+    }
+}
+
+class DiscoveryChannel extends EventChannel<DiscoveredDevice, unknown> {
+    constructor(token: string, private zone: NgZone, private subscriber: Subscriber<DiscoveredDevice>) {
+        super(token);
+    }
+
+    onReceive(payload: DiscoveredDevice): void {
+        this.zone.run(() => this.subscriber.next(payload));
+    }
+
+    onClose(payload: unknown): void {
+        this.unlisten().then();
+        this.zone.run(() => {
+            if (BackendError.isCompatibleBody(payload)) {
+                this.subscriber.error(new BackendError(payload));
+            } else {
+                this.subscriber.complete();
+            }
+        });
+    }
+}
+
 export interface DeviceInfo {
     modelName: string;
     osVersion?: string;
@@ -1,4 +1,13 @@
-import {Device, FileItem, FileSession} from '../../types';
+import {
+  Device,
+  DirTransferResult,
+  FileItem,
+  FileSession,
+  SyncOptions,
+  SyncResult,
+  TransferOptions,
+  TreeOptions
+} from '../../types';
 import {RemoteCommandService} from './remote-command.service';
 import * as path from 'path';
 import {basename} from '@tauri-apps/api/path'
@@ -23,21 +32,42 @@ export class FileSessionImpl implements FileSession {
     });
   }
 
+  async stat(path: string): Promise<FileItem> {
+    return this.file.stat(this.device, path).catch(e => {
+      if (IOError.isCompatible(e) && e.code === 'NotFound') {
+        throw new FileError.NotFound(path, e.message);
+      }
+      throw e;
+    });
+  }
+
   async rm(path: string, recursive: boolean): Promise<void> {
     await this.file.rm(this.device, path, recursive);
   }
 
-  getTemp(remotePath: string): Promise<string> {
-    return this.file.getTemp(this.device, remotePath);
+  getTemp(remotePath: string, options?: TransferOptions): Promise<string> {
+    return this.file.getTemp(this.device, remotePath, options);
   }
 
-  get(remotePath: string, localPath: string): Promise<void> {
-    return this.file.get(this.device, remotePath, localPath);
+  get(remotePath: string, localPath: string, options?: TransferOptions): Promise<void> {
+    return this.file.get(this.device, remotePath, localPath, options);
   }
 
-  put(localPath: string, remotePath: string): Promise<void> {
+  put(localPath: string, remotePath: string, options?: TransferOptions): Promise<void> {
     console.log('put', localPath, '=>', remotePath);
-    return this.file.put(this.device, remotePath, localPath);
+    return this.file.put(this.device, remotePath, localPath, options);
+  }
+
+  getDir(remotePath: string, localPath: string, options?: TransferOptions & TreeOptions): Promise<DirTransferResult> {
+    return this.file.getDir(this.device, remotePath, localPath, options);
+  }
+
+  putDir(localPath: string, remotePath: string, options?: TransferOptions & TreeOptions): Promise<DirTransferResult> {
+    return this.file.putDir(this.device, remotePath, localPath, options);
+  }
+
+  sync(localPath: string, remotePath: string, options?: TransferOptions & SyncOptions): Promise<SyncResult> {
+    return this.file.sync(this.device, remotePath, localPath, options);
   }
 
   mkdir(path: string): Promise<void> {
@@ -45,6 +75,18 @@ export class FileSessionImpl implements FileSession {
     return this.file.mkdir(this.device, path);
   }
 
+  rename(from: string, to: string): Promise<void> {
+    return this.file.rename(this.device, from, to);
+  }
+
+  chmod(path: string, mode: number): Promise<void> {
+    return this.file.chmod(this.device, path, mode);
+  }
+
+  symlink(target: string, path: string): Promise<void> {
+    return this.file.symlink(this.device, target, path);
+  }
+
   async uploadBatch(sources: string[], pwd: string, failCb: (name: string, e: Error) => Promise<boolean>): Promise<void> {
     for (const source of sources) {
       const name = await basename(source);
@@ -1,8 +1,19 @@
 import {Injectable, NgZone} from "@angular/core";
 import {BackendClient, BackendError} from "./backend-client";
-import {Device, FileItem} from "../../types";
+import {
+    Device,
+    DirTransferResult,
+    FileItem,
+    SyncOptions,
+    SyncResult,
+    TransferOptions,
+    TransferProgress,
+    TransferResult,
+    TransferStats,
+    TreeOptions
+} from "../../types";
 import {Buffer} from "buffer";
-import {ExecutionError, RemoteCommandService} from "./remote-command.service";
+import {ExecutionError} from "./remote-command.service";
 import {finalize, firstValueFrom, lastValueFrom, Observable, Subject} from "rxjs";
 import {EventChannel} from "../event-channel";
 import {map} from "rxjs/operators";
@@ -12,7 +23,7 @@ import {map} from "rxjs/operators";
 })
 export class RemoteFileService extends BackendClient {
 
-    constructor(zone: NgZone, private cmd: RemoteCommandService) {
+    constructor(zone: NgZone) {
         super(zone, 'remote-file');
     }
 
@@ -20,8 +31,45 @@ export class RemoteFileService extends BackendClient {
         return this.invoke<FileItem[]>('ls', {device, path});
     }
 
+    /**
+     * Stats a single file, not following it if it's a symbolic link.
+     */
+    public async stat(device: Device, path: string): Promise<FileItem> {
+        return this.invoke<FileItem>('stat', {device, path});
+    }
+
+    /**
+     * @param recursive Remove directories with everything in them, otherwise only empty ones are removed
+     */
     public async rm(device: Device, path: string, recursive: boolean): Promise<void> {
-        await this.cmd.exec(device, `xargs -0 rm ${recursive ? '-r' : ''}`, 'buffer', path);
+        await this.invoke('rm', {device, path, recursive});
+    }
+
+    /**
+     * @param mode Permissions of the new directory, 0o755 by default
+     * @param parents Create missing parent directories too, and don't fail if it exists, like `mkdir -p`
+     */
+    public async mkdir(device: Device, path: string, mode?: number, parents?: boolean): Promise<void> {
+        await this.invoke('mkdir', {device, path, mode, parents});
+    }
+
+    public async rename(device: Device, from: string, to: string): Promise<void> {
+        await this.invoke('rename', {device, from, to});
+    }
+
+    public async chmod(device: Device, path: string, mode: number): Promise<void> {
+        await this.invoke('chmod', {device, path, mode});
+    }
+
+    public async chown(device: Device, path: string, uid: number, gid: number): Promise<void> {
+        await this.invoke('chown', {device, path, uid, gid});
+    }
+
+    /**
+     * Creates a symbolic link at `path` pointing to `target`.
+     */
+    public async symlink(device: Device, target: string, path: string): Promise<void> {
+        await this.invoke('symlink', {device, target, path});
     }
 
     public async read(device: Device, path: string, encoding?: 'gzip', output?: 'buffer'): Promise<Buffer>;
@@ -41,20 +89,50 @@ export class RemoteFileService extends BackendClient {
         await this.invoke('write', {device, path, content});
     }
 
-    public async get(device: Device, path: string, target: string): Promise<void> {
-        await this.invoke('get', {device, path, target});
+    public async get(device: Device, path: string, target: string, options?: TransferOptions): Promise<void> {
+        await this.transfer('get', {device, path, target, window: options?.window}, options);
+    }
+
+    public async put(device: Device, path: string, source: string, options?: TransferOptions): Promise<void> {
+        await this.transfer('put', {device, path, source, window: options?.window}, options);
+    }
+
+    public async getDir(device: Device, path: string, target: string,
+                        options?: TransferOptions & TreeOptions): Promise<DirTransferResult> {
+        const args = {device, path, target, window: options?.window, options: treeOptions(options)};
+        return this.transfer<DirTransferResult>('get_dir', args, options);
     }
 
-    public async put(device: Device, path: string, source: string): Promise<void> {
-        await this.invoke('put', {device, path, source});
+    public async putDir(device: Device, path: string, source: string,
+                        options?: TransferOptions & TreeOptions): Promise<DirTransferResult> {
+        const args = {device, path, source, window: options?.window, options: treeOptions(options)};
+        return this.transfer<DirTransferResult>('put_dir', args, options);
     }
 
-    public async mkdir(device: Device, path: string): Promise<void> {
-        await this.cmd.exec(device, `xargs -0 mkdir`, 'buffer', path);
+    /**
+     * Uploads the local files that differ from the remote ones, or only lists them for a dry run.
+     */
+    public async sync(device: Device, path: string, source: string,
+                      options?: TransferOptions & SyncOptions): Promise<SyncResult> {
+        const sync = options && {
+            ...treeOptions(options),
+            checksum: options.checksum,
+            delete: options.delete,
+            dryRun: options.dryRun
+        };
+        const args = {device, path, source, window: options?.window, options: sync};
+        return this.transfer<SyncResult>('sync', args, options);
     }
 
-    public async getTemp(device: Device, path: string): Promise<string> {
-        return await this.invoke<string>('get_temp', {device, path});
+    /**
+     * Downloads the file without, then with parallel segments, to compare their throughput.
+     */
+    public async benchmark(device: Device, path: string, window?: number): Promise<TransferStats[]> {
+        return await this.invoke<TransferStats[]>('benchmark', {device, path, window});
+    }
+
+    public async getTemp(device: Device, path: string, options?: TransferOptions): Promise<string> {
+        return (await this.transfer<TransferResult>('get_temp', {device, path}, options)).local;
     }
 
     public async serveLocal(device: Device, localPath: string): Promise<ServeInstance> {
@@ -108,6 +186,49 @@ export class RemoteFileService extends BackendClient {
         });
     }
 
+    private async transfer<R = TransferResult>(method: string, args: Record<string, unknown>,
+                                               options?: TransferOptions): Promise<R> {
+        const token = await this.invoke<string>(method, args);
+        const zone = this.zone;
+        return new Promise<R>((resolve, reject) => {
+            const channel = new class extends EventChannel<TransferProgress, R | unknown> {
+                constructor(token: string) {
+                    super(token);
+                }
+
+                onReceive(payload: TransferProgress): void {
+                    zone.run(() => options?.progress?.(payload));
+                }
+
+                onClose(payload: R | unknown): void {
+                    this.unlisten().then();
+                    zone.run(() => {
+                        if (BackendError.isCompatibleBody(payload)) {
+                            reject(RemoteFileService.toBackendError(payload));
+                        } else {
+                            resolve(payload as R);
+                        }
+                    });
+                }
+            }(token);
+            const signal = options?.signal;
+            if (signal?.aborted) {
+                channel.close().then();
+                return;
+            }
+            signal?.addEventListener('abort', () => channel.close().then(), {once: true});
+            channel.send().then();
+        });
+    }
+
+}
+
+function treeOptions(options?: TreeOptions): TreeOptions | undefined {
+    if (!options) {
+        return undefined;
+    }
+    const {followLinks, include, exclude} = options;
+    return {followLinks, include, exclude};
 }
 
 export declare interface ServeInstance {
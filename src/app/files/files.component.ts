@@ -10,7 +10,7 @@ import {open as showOpenDialog, save as showSaveDialog} from '@tauri-apps/api/di
 import * as path from "path";
 import {RemoteCommandService} from "../core/services/remote-command.service";
 import {trimEnd} from "lodash-es";
-import {downloadDir} from "@tauri-apps/api/path";
+import {downloadDir, join as localJoin} from "@tauri-apps/api/path";
 import {CreateDirectoryMessageComponent} from "./create-directory-message/create-directory-message.component";
 
 class FilesState {
@@ -150,7 +150,8 @@ export class FilesComponent implements OnInit, OnDestroy {
     async openItem(file: FileItem): Promise<void> {
         const cwd = this.history?.current;
         if (!cwd) return;
-        switch (file.type) {
+        // Symbolic links are opened as what they point to
+        switch (file.type === 'l' ? file.link?.type : file.type) {
             case 'd': {
                 await this.cd(path.join(cwd, file.filename), true);
                 break;
@@ -169,12 +170,15 @@ export class FilesComponent implements OnInit, OnDestroy {
         const cwd = this.history?.current;
         if (!cwd || !this.device) return;
         const progress = ProgressDialogComponent.open(this.modalService);
+        const component = progress.componentInstance as ProgressDialogComponent;
         let result = false;
         let tempPath: string | null = null;
         do {
             try {
-                tempPath = await this.session!.getTemp(path.join(cwd, file.filename));
+                tempPath = await this.session!.getTemp(path.join(cwd, file.filename),
+                    component.transfer(`Downloading ${file.filename}`));
             } catch (e) {
+                if (component.cancelled) break;
                 result = await MessageDialogComponent.open(this.modalService, {
                     title: `Failed to download file ${file.filename}`,
                     message: (e as Error).message ?? String(e),
@@ -205,13 +209,16 @@ export class FilesComponent implements OnInit, OnDestroy {
         });
         if (!returnValue) return;
         const progress = ProgressDialogComponent.open(this.modalService);
+        const component = progress.componentInstance as ProgressDialogComponent;
         const target = returnValue as string;
         for (const file of files) {
             let result = false;
             do {
                 try {
-                    await this.session!.get(path.join(cwd, file.filename), target);
+                    await this.session!.get(path.join(cwd, file.filename), await localJoin(target, file.filename),
+                        component.transfer(`Downloading ${file.filename}`));
                 } catch (e) {
+                    if (component.cancelled) break;
                     result = await MessageDialogComponent.open(this.modalService, {
                         title: `Failed to download file ${file.filename}`,
                         message: (e as Error).message ?? String(e),
@@ -223,7 +230,7 @@ export class FilesComponent implements OnInit, OnDestroy {
                     }).result;
                 }
             } while (result);
-            if (result === null) {
+            if (result === null || component.cancelled) {
                 break;
             }
         }
@@ -274,11 +281,14 @@ export class FilesComponent implements OnInit, OnDestroy {
         const returnValue = await showSaveDialog({defaultPath: file.filename});
         if (!returnValue) return;
         const progress = ProgressDialogComponent.open(this.modalService);
+        const component = progress.componentInstance as ProgressDialogComponent;
         let result = false;
         do {
             try {
-                await this.session!.get(path.join(cwd, file.filename), returnValue);
+                await this.session!.get(path.join(cwd, file.filename), returnValue,
+                    component.transfer(`Downloading ${file.filename}`));
             } catch (e) {
+                if (component.cancelled) break;
                 result = await MessageDialogComponent.open(this.modalService, {
                     title: `Failed to download file ${file.filename}`,
                     message: (e as Error).message ?? String(e),
@@ -15,7 +15,8 @@ export class RemoveDeviceComponent {
   }
 
   get canDeleteSshKey(): boolean {
-    return this.device.privateKey?.openSsh?.startsWith("webos_") === true;
+    const privateKey = this.device.privateKey;
+    return !!privateKey && 'openSsh' in privateKey && privateKey.openSsh.startsWith("webos_");
   }
 
   confirmDeletion() {
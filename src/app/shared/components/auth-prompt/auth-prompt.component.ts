@@ -0,0 +1,32 @@
+import {Component, Inject, Injector} from '@angular/core';
+import {NgbActiveModal, NgbModal} from "@ng-bootstrap/ng-bootstrap";
+import {AuthPromptRequest} from "../../../types";
+
+@Component({
+  selector: 'app-auth-prompt',
+  templateUrl: './auth-prompt.component.html',
+  styleUrls: ['./auth-prompt.component.scss']
+})
+export class AuthPromptComponent {
+  answers: string[];
+
+  constructor(
+    public modal: NgbActiveModal,
+    @Inject('request') public request: AuthPromptRequest
+  ) {
+    this.answers = request.prompts.map(() => '');
+  }
+
+  static prompt(modals: NgbModal, request: AuthPromptRequest): Promise<string[] | undefined> {
+    const ref = modals.open(AuthPromptComponent, {
+      size: 'sm',
+      centered: true,
+      injector: Injector.create({
+        providers: [
+          {provide: 'request', useValue: request}
+        ]
+      })
+    });
+    return ref.result.catch(() => undefined);
+  }
+}
@@ -1,4 +1,8 @@
 import {Component, EventEmitter, Input, Output} from '@angular/core';
+import {NgbModal} from '@ng-bootstrap/ng-bootstrap';
+import {DeviceManagerService} from '../../../core/services';
+import {BackendError} from '../../../core/services/backend-client';
+import {MessageDialogComponent} from '../message-dialog/message-dialog.component';
 
 @Component({
   selector: 'app-error-card',
@@ -15,11 +19,54 @@ export class ErrorCardComponent {
   @Output()
   retry: EventEmitter<void> = new EventEmitter<void>();
 
-  constructor() {
+  constructor(private modalService: NgbModal, private deviceManager: DeviceManagerService) {
   }
 
   get details(): string | undefined {
     return (this.error as any)?.details;
   }
 
+  get hostKeyChanged(): HostKeyChanged | undefined {
+    if (BackendError.isCompatible(this.error) && this.error.reason === 'HostKeyChanged') {
+      return this.error as unknown as HostKeyChanged;
+    }
+    return undefined;
+  }
+
+  async trustHostKey(changed: HostKeyChanged): Promise<void> {
+    const confirmed = await MessageDialogComponent.open(this.modalService, {
+      title: 'Trust the new host key?',
+      message: `Only continue if you know why the key of ${changed.name} changed, for example after a factory reset. ` +
+        `Otherwise someone may be intercepting the connection.`,
+      positive: 'Trust',
+      positiveStyle: 'danger',
+      negative: 'Cancel',
+      autofocus: 'negative',
+    }).result.catch(() => false);
+    if (!confirmed) {
+      return;
+    }
+    try {
+      const device = (await this.deviceManager.list()).find(d => d.name === changed.name);
+      if (!device) {
+        throw new Error(`Device ${changed.name} not found`);
+      }
+      await this.deviceManager.acceptHostKey(device, changed.newFingerprint);
+    } catch (e) {
+      MessageDialogComponent.open(this.modalService, {
+        message: 'Failed to trust the new host key',
+        error: e as Error,
+        positive: 'OK',
+      });
+      return;
+    }
+    this.retry.emit();
+  }
+
+}
+
+interface HostKeyChanged {
+  name: string;
+  oldFingerprint: string;
+  newFingerprint: string;
 }
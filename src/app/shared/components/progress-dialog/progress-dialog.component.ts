@@ -1,5 +1,7 @@
 import {Component} from '@angular/core';
 import {NgbModal, NgbModalRef} from '@ng-bootstrap/ng-bootstrap';
+import {filesize} from 'filesize';
+import {TransferOptions} from '../../../types';
 
 @Component({
   selector: 'app-progress-dialog',
@@ -10,10 +12,38 @@ export class ProgressDialogComponent {
 
   message?: string;
   progress?: number;
+  detail?: string;
+  /** Shows a Cancel button when set */
+  cancel?: () => void;
+
+  private controller?: AbortController;
 
   constructor() {
   }
 
+  get cancelled(): boolean {
+    return this.controller?.signal.aborted ?? false;
+  }
+
+  /**
+   * Options showing the transfer progress in this dialog. Cancel aborts every transfer started with them.
+   */
+  transfer(message?: string): TransferOptions {
+    const controller = this.controller ??= new AbortController();
+    this.message = message;
+    this.progress = undefined;
+    this.detail = undefined;
+    this.cancel = () => controller.abort();
+    return {
+      signal: controller.signal,
+      progress: ({transferred, total, rate}) => {
+        this.progress = total > 0 ? transferred * 100 / total : undefined;
+        this.detail = `${filesize(transferred, {output: 'string'})} of ${filesize(total, {output: 'string'})}, `
+          + `${filesize(rate, {output: 'string'})}/s`;
+      },
+    };
+  }
+
   static open(service: NgbModal): NgbModalRef {
     return service.open(ProgressDialogComponent, {
       centered: true,
@@ -13,6 +13,7 @@ import {SizeCalculatorComponent} from "./components/term-size-calculator/size-ca
 import {LoadingCardComponent} from './components/loading-card/loading-card.component';
 import {StatStorageInfoComponent} from './components/stat-storage-info/stat-storage-info.component';
 import {FilesizePipe} from "./pipes/filesize.pipe";
+import {AuthPromptComponent} from './components/auth-prompt/auth-prompt.component';
 
 @NgModule({
     declarations: [
@@ -27,6 +28,7 @@ import {FilesizePipe} from "./pipes/filesize.pipe";
         SizeCalculatorComponent,
         LoadingCardComponent,
         StatStorageInfoComponent,
+        AuthPromptComponent,
     ],
     imports: [CommonModule, FormsModule, NgbModule],
     exports: [
@@ -40,7 +42,8 @@ import {FilesizePipe} from "./pipes/filesize.pipe";
         ExternalLinkDirective,
         SizeCalculatorComponent,
         LoadingCardComponent,
-        StatStorageInfoComponent
+        StatStorageInfoComponent,
+        AuthPromptComponent
     ]
 })
 export class SharedModule {
@@ -4,13 +4,148 @@ export declare interface Device {
   port: number;
   username: 'prisoner' | 'root' | string;
   profile: 'ose';
-  privateKey?: { openSsh: string };
+  privateKey?: PrivateKey;
   passphrase?: string;
   password?: string;
+  passphraseRef?: string;
+  passwordRef?: string;
+  authMethods?: AuthMethod[];
+  jumpHost?: JumpHost;
+  sshOptions?: SshOptions;
+  pool?: PoolOptions;
   description?: string;
   default?: boolean;
   indelible?: boolean;
-  files?: string;
+  /**
+   * How files are transferred: over SFTP, or through commands like `cat` for devices without it.
+   * Falls back to stream if the device has no SFTP, when not set.
+   */
+  files?: 'stream' | 'sftp';
+}
+
+/**
+ * Key file in ~/.ssh, key kept in the vault, or the keys held by ssh-agent.
+ */
+export type PrivateKey = { openSsh: string } | { openSshData: string } | { sshAgent: true };
+
+export type AuthMethod = 'publickey' | 'password' | 'keyboard-interactive' | 'none';
+
+export type JumpHost = { device: string } | {
+  host: string;
+  port?: number;
+  username: string;
+  privateKey?: PrivateKey;
+};
+
+export declare interface SshOptions {
+  ciphers?: string[];
+  kex?: string[];
+  macs?: string[];
+  hostKeys?: string[];
+  connectTimeout?: number;
+  compression?: boolean;
+}
+
+export declare interface PoolOptions {
+  maxSize?: number;
+  /** Seconds, 0 to keep unused connections open */
+  idleTimeout?: number;
+}
+
+export declare interface PoolInfo {
+  device: string;
+  maxSize: number;
+  idleTimeout: number | null;
+  connections: ConnectionInfo[];
+}
+
+export declare interface ConnectionInfo {
+  id: string;
+  /** Seconds since the connection was opened */
+  age: number;
+  /** Seconds since the connection was last used */
+  idle: number;
+  inUse: boolean;
+  lastOk: boolean;
+  user: {
+    uid: { id: number, name?: string },
+    gid: { id: number, name?: string },
+    groups: { id: number, name?: string }[]
+  } | null;
+  /** How often the SFTP session of this connection was opened, reused and dropped after an error */
+  sftp: { opened: number, reused: number, invalidated: number };
+}
+
+export declare interface AuthPromptRequest {
+  token: string;
+  device: string;
+  name: string;
+  instruction: string;
+  prompts: { prompt: string, echo: boolean }[];
+}
+
+export type ConnectionState = 'connected' | 'idle' | 'lost';
+
+export declare interface ConnectionStateEvent {
+  device: string;
+  state: ConnectionState;
+}
+
+export declare interface ConnectionRetry {
+  device: string;
+  attempt: number;
+  maxAttempts: number;
+  delayMs: number;
+  error: { reason: string, message?: string };
+}
+
+/**
+ * How operations are retried when their connection drops.
+ */
+export declare interface RetryPolicy {
+  maxAttempts: number;
+  initialBackoffMs: number;
+  maxBackoffMs: number;
+  /**
+   * No attempt is started after this much time has passed since the first one
+   */
+  deadlineMs: number;
+}
+
+export declare interface DiagnosticStage {
+  name: 'dns' | 'tcp' | 'banner' | 'jump' | 'kex' | 'auth' | 'user';
+  status: 'ok' | 'failed' | 'skipped';
+  elapsedMs: number;
+  detail?: Record<string, unknown>;
+  error?: { reason: string, message?: string, [key: string]: unknown };
+}
+
+export declare interface DiagnosticReport {
+  device: string;
+  ok: boolean;
+  stages: DiagnosticStage[];
+}
+
+export declare interface DiscoveredDevice {
+  address: string;
+  name?: string;
+  model?: string;
+  server?: string;
+  usn?: string;
+  /** Port 9991 is open, serving the key for the Developer Mode app */
+  keyServer: boolean;
+  /** Port 9922 is open, the SSH server of Developer Mode */
+  ssh: boolean;
+}
+
+export declare interface InvalidDevice {
+  name?: string;
+  reason: string;
+}
+
+export declare interface DeviceList {
+  devices: Device[];
+  invalid: InvalidDevice[];
 }
 
 export enum NewDeviceAuthentication {
@@ -49,3 +184,10 @@ export declare interface NewDeviceWithDevicePrivateKey extends NewDeviceBase {
 export type NewDevice = NewDeviceWithPassword | NewDeviceWithLocalPrivateKey | NewDeviceWithDevicePrivateKey;
 
 export type DeviceLike = Device | NewDevice;
+
+export declare interface HostKey {
+  host: string;
+  port: number;
+  fingerprint: string;
+  pinnedAt: number;
+}
@@ -1,3 +1,5 @@
+import {BackendErrorBody} from "../core/services/backend-client";
+
 export type FileType = '-' | 'd' | 'c' | 'b' | 's' | 'p' | 'l' | '';
 
 export declare interface FileItem {
@@ -15,6 +17,8 @@ export declare interface FileItem {
 export declare interface LinkInfo {
     target?: string;
     broken?: boolean;
+    /** Type of what the link points to, unless broken */
+    type?: FileType;
 }
 
 export declare interface PermInfo {
@@ -23,19 +27,112 @@ export declare interface PermInfo {
     execute: boolean;
 }
 
+export declare interface TransferStats {
+    bytes: number;
+    elapsedMs: number;
+    window: number;
+    /** Connections the file was transferred over, fewer than window if the pool was busy */
+    connections: number;
+}
+
+export declare interface TransferProgress {
+    transferred: number;
+    total: number;
+    /** Average bytes per second since the transfer started */
+    rate: number;
+}
+
+export declare interface TransferResult extends TransferStats {
+    /** Path of the local file read or written */
+    local: string;
+}
+
+export declare interface TransferOptions {
+    /** Segments of a big file transferred in parallel, at most and by default the pool size of the device */
+    window?: number;
+    progress?: (progress: TransferProgress) => void;
+    /** Cancels the transfer, and removes the partially written file */
+    signal?: AbortSignal;
+}
+
+export declare interface TreeOptions {
+    /** Copy what symbolic links point to, instead of the links themselves */
+    followLinks?: boolean;
+    /** Glob patterns of files, or directories to copy all of, everything if empty */
+    include?: string[];
+    /** Glob patterns of files and directories to skip */
+    exclude?: string[];
+}
+
+export declare interface DirTransferResult {
+    files: EntryResult[];
+    bytes: number;
+    elapsedMs: number;
+    /** Entries not copied, which have an `error` */
+    failed: number;
+}
+
+export declare interface EntryResult {
+    /** Relative to the directory copied, which itself is `.` */
+    path: string;
+    type: FileType;
+    bytes: number;
+    error?: BackendErrorBody;
+}
+
+export declare interface SyncOptions extends TreeOptions {
+    /** Compare files of the same size by SHA-256, instead of by mtime */
+    checksum?: boolean;
+    /** Delete remote entries that aren't in the local tree */
+    delete?: boolean;
+    /** Only tell what would be done */
+    dryRun?: boolean;
+}
+
+export declare interface SyncAction {
+    /** Relative to the directory synced */
+    path: string;
+    type: FileType;
+    action: 'upload' | 'delete';
+    reason: 'missing' | 'type' | 'size' | 'mtime' | 'checksum' | 'target' | 'unreadable' | 'extra';
+    bytes: number;
+}
+
+export declare interface SyncResult extends DirTransferResult {
+    /** What was done, or would be for a dry run */
+    actions: SyncAction[];
+    dryRun: boolean;
+    /** Files already up to date */
+    unchanged: number;
+}
+
 export declare interface FileSession {
 
     ls(path: string): Promise<FileItem[]>;
 
+    stat(path: string): Promise<FileItem>;
+
     rm(path: string, recursive: boolean): Promise<void>;
 
-    get(remotePath: string, localPath: string): Promise<void>;
+    get(remotePath: string, localPath: string, options?: TransferOptions): Promise<void>;
+
+    put(localPath: string, remotePath: string, options?: TransferOptions): Promise<void>;
+
+    getDir(remotePath: string, localPath: string, options?: TransferOptions & TreeOptions): Promise<DirTransferResult>;
+
+    putDir(localPath: string, remotePath: string, options?: TransferOptions & TreeOptions): Promise<DirTransferResult>;
 
-    put(localPath: string, remotePath: string): Promise<void>;
+    sync(localPath: string, remotePath: string, options?: TransferOptions & SyncOptions): Promise<SyncResult>;
 
     mkdir(path: string): Promise<void>;
 
-    getTemp(remotePath: string): Promise<string>;
+    rename(from: string, to: string): Promise<void>;
+
+    chmod(path: string, mode: number): Promise<void>;
+
+    symlink(target: string, path: string): Promise<void>;
+
+    getTemp(remotePath: string, options?: TransferOptions): Promise<string>;
 
     uploadBatch(strings: string[], pwd: string, failCb: (name: string, e: Error) => Promise<boolean>): Promise<void>;
 }